@@ -7,12 +7,23 @@
 //! based on the DRM MM range allocator, and a debug allocator that allocates each object as a
 //! separate GEM object.
 //!
+//! Small objects are served from a slab layer on top of the heap allocator, which carves heap
+//! chunks into fixed size classes so that most allocations do not need to take the range allocator
+//! lock or grow the heap.
+//!
 //! Allocations may optionally have debugging enabled, which adds preambles that store metadata
 //! about the allocation. This is useful for live debugging using the hypervisor or postmortem
 //! debugging with a GPU memory snapshot, since it makes it easier to identify use-after-free and
 //! caching issues.
 
-use kernel::{drm::mm, error::Result, prelude::*, str::CString};
+use kernel::{
+    c_str,
+    drm::mm,
+    error::Result,
+    prelude::*,
+    str::CString,
+    sync::{Arc, Mutex},
+};
 
 use crate::debug::*;
 use crate::driver::{AsahiDevRef, AsahiDevice};
@@ -32,11 +43,11 @@ const DEBUG_CLASS: DebugFlags = DebugFlags::Alloc;
 
 #[cfg(not(CONFIG_DRM_ASAHI_DEBUG_ALLOCATOR))]
 /// The driver-global allocator type
-pub(crate) type DefaultAllocator = HeapAllocator;
+pub(crate) type DefaultAllocator = SlabAllocator<HeapAllocator>;
 
 #[cfg(not(CONFIG_DRM_ASAHI_DEBUG_ALLOCATOR))]
 /// The driver-global allocation type
-pub(crate) type DefaultAllocation = HeapAllocation;
+pub(crate) type DefaultAllocation = SlabAllocation<HeapAllocation>;
 
 #[cfg(CONFIG_DRM_ASAHI_DEBUG_ALLOCATOR)]
 /// The driver-global allocator type
@@ -182,6 +193,133 @@ impl<T, U: RawAllocation> Drop for GenericAlloc<T, U> {
 
 static_assert!(mem::size_of::<AllocDebugData>() == 0x40);
 
+/// Usage statistics for an allocator.
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct AllocStats {
+    /// Total size of the backing objects, in bytes.
+    pub(crate) backing_bytes: usize,
+    /// Number of live allocations.
+    pub(crate) live_objects: usize,
+    /// Total requested size of live allocations, in bytes.
+    pub(crate) live_bytes: usize,
+    /// Number of freed allocations which are not yet reusable.
+    pub(crate) garbage_objects: usize,
+    /// Total size of freed allocations which are not yet reusable, in bytes.
+    pub(crate) garbage_bytes: usize,
+    /// Size of the largest free range within the backed part of the heap, in bytes.
+    pub(crate) largest_hole: usize,
+    /// Number of allocation requests that failed.
+    pub(crate) failures: usize,
+}
+
+impl fmt::Display for AllocStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "backing={:#x} live={}/{:#x} garbage={}/{:#x} largest_hole={:#x} failures={}",
+            self.backing_bytes,
+            self.live_objects,
+            self.live_bytes,
+            self.garbage_objects,
+            self.garbage_bytes,
+            self.largest_hole,
+            self.failures
+        )
+    }
+}
+
+/// A corrupted allocation found by an integrity scan.
+pub(crate) struct Corruption {
+    name: [u8; 0x20],
+    tag: u32,
+    obj_gpuva: u64,
+    size: u64,
+    state: u32,
+    /// Offset of the first bad guard byte past the end of the object, if the guard was damaged.
+    guard_offset: Option<usize>,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let len = self
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.name.len());
+        let name = core::str::from_utf8(&self.name[..len]).unwrap_or("<invalid>");
+
+        write!(
+            f,
+            "object of type {}/{:#x} at {:#x}:{:#x}: ",
+            name, self.tag, self.obj_gpuva, self.size
+        )?;
+        match self.guard_offset {
+            Some(offset) => write!(f, "guard overwritten at +{:#x}", offset),
+            None => write!(f, "bad state {:#x}", self.state),
+        }
+    }
+}
+
+/// Scan a CPU-mapped region of an allocator for debug preambles, and check the state and guard
+/// padding of every allocation found.
+///
+/// Preambles are recognized by their `obj_gpuva` pointing right past themselves, which rules out
+/// the copy of the previous preamble that precedes each one. Freed allocations are skipped.
+///
+/// # Safety
+/// `ptr` must be valid for reads of `len` bytes, and map the GPU range starting at `gpuva`.
+unsafe fn scan_region(ptr: *const u8, gpuva: u64, len: usize) -> Option<Corruption> {
+    let debug_len = mem::size_of::<AllocDebugData>();
+    let check_guard = debug_enabled(DebugFlags::DetectOverflows);
+
+    for offset in (0..len.saturating_sub(debug_len)).step_by(debug_len) {
+        let hdr_va = gpuva + offset as u64;
+        // SAFETY: The header is within the region. The firmware may write to it concurrently, so
+        // use a volatile read.
+        let hdr = unsafe { (ptr.add(offset) as *const AllocDebugData).read_volatile() };
+
+        let obj_offset = offset + debug_len;
+        if hdr.obj_gpuva != hdr_va + debug_len as u64
+            || hdr.base_gpuva > hdr_va
+            || hdr.size > (len - obj_offset) as u64
+        {
+            continue;
+        }
+
+        let corruption = |guard_offset| Corruption {
+            name: hdr.name,
+            tag: hdr.tag,
+            obj_gpuva: hdr.obj_gpuva,
+            size: hdr.size,
+            state: hdr.state,
+            guard_offset,
+        };
+
+        match hdr.state {
+            STATE_DEAD => continue,
+            STATE_LIVE => (),
+            _ => return Some(corruption(None)),
+        }
+
+        let size = hdr.size as usize;
+        if !check_guard || size > len - obj_offset - size {
+            continue;
+        }
+
+        // Same pattern as `Allocator::alloc_generic()`, which stores a missing tag as 0.
+        let pad_word = (if hdr.tag != 0 { hdr.tag } else { GUARD_MARKER }) | 0x81818181;
+        for i in 0..size {
+            // SAFETY: The guard padding is within the region, as checked above.
+            let p = unsafe { ptr.add(obj_offset + size + i).read_volatile() };
+            if p != (pad_word >> (8 * (i & 3))) as u8 {
+                return Some(corruption(Some(i)));
+            }
+        }
+    }
+
+    None
+}
+
 /// A trait representing an allocator.
 pub(crate) trait Allocator {
     /// The raw allocation type used by this allocator.
@@ -206,6 +344,23 @@ pub(crate) trait Allocator {
     /// Collect garbage for this allocator, up to the given object count. Optional.
     fn collect_garbage(&mut self, _count: usize) {}
 
+    /// Check all live allocations with debug preambles for corruption, returning the first
+    /// corrupted one. Optional.
+    fn check_integrity(&self) -> Option<Corruption> {
+        None
+    }
+
+    /// Returns usage statistics for this allocator. Allocators that do not track usage only
+    /// report their garbage.
+    fn stats(&self) -> AllocStats {
+        let (garbage_objects, garbage_bytes) = self.garbage();
+        AllocStats {
+            garbage_objects,
+            garbage_bytes,
+            ..Default::default()
+        }
+    }
+
     /// Allocate a new GpuStruct object. See [`GpuObject::new`].
     #[inline(never)]
     fn new_object<T: GpuStruct>(
@@ -624,9 +779,12 @@ impl Drop for HeapAllocation {
     fn drop(&mut self) {
         let node = self.0.take().unwrap();
         let size = node.size();
+        let real_size = node.real_size;
         let alloc = node.alloc_ref();
 
         alloc.with(|a| {
+            a.live_objects -= 1;
+            a.live_bytes -= real_size;
             if let Some(garbage) = a.garbage.as_mut() {
                 garbage.push(node);
                 a.total_garbage += size as usize;
@@ -693,6 +851,9 @@ struct HeapAllocatorInner {
     backing_objects: Vec<(crate::gem::ObjectRef, u64)>,
     garbage: Option<Vec<mm::Node<HeapAllocatorInner, HeapAllocationInner>>>,
     total_garbage: usize,
+    live_objects: usize,
+    live_bytes: usize,
+    failures: usize,
     name: CString,
     vm_id: u64,
 }
@@ -750,6 +911,9 @@ impl HeapAllocator {
             vm_id: vm.id(),
             garbage: if keep_garbage { Some(Vec::new()) } else { None },
             total_garbage: 0,
+            live_objects: 0,
+            live_bytes: 0,
+            failures: 0,
         };
 
         let mm = mm::Allocator::new(start, end - start + 1, inner)?;
@@ -893,6 +1057,29 @@ impl HeapAllocator {
                 .or(Err(ENOENT))
         })
     }
+
+    /// Count a failed allocation request.
+    fn note_failure(&self) {
+        self.mm.with_inner(|inner| inner.failures += 1);
+    }
+
+    /// Returns the size of the largest free range between `start` and the current top of the
+    /// heap, walking the range allocator's node list.
+    fn largest_hole(&self) -> u64 {
+        let mut largest = 0;
+        let mut prev_end = self.start;
+
+        self.mm.for_each_node(|node| {
+            if node.start() >= self.top {
+                return false;
+            }
+            largest = largest.max(node.start().saturating_sub(prev_end));
+            prev_end = node.start() + node.size();
+            true
+        });
+
+        largest.max(self.top.saturating_sub(prev_end))
+    }
 }
 
 impl Allocator for HeapAllocator {
@@ -945,6 +1132,7 @@ impl Allocator for HeapAllocator {
                     "HeapAllocator[{}]::new: Failed to insert node of size {:#x} / align {:#x}: {:?}\n",
                     &*self.name, size_aligned, align, a
                 );
+                self.note_failure();
                 return Err(a);
             }
         };
@@ -965,7 +1153,10 @@ impl Allocator for HeapAllocator {
                 );
             }
             let block_size = self.block_size.max((end - self.top) as usize);
-            self.add_block(block_size)?;
+            if let Err(e) = self.add_block(block_size) {
+                self.note_failure();
+                return Err(e);
+            }
             new_object = true;
         }
         assert!(end <= self.top);
@@ -989,6 +1180,7 @@ impl Allocator for HeapAllocator {
                             &*self.name,
                             start
                         );
+                        self.note_failure();
                         return Err(EIO);
                     }
                 })
@@ -1019,6 +1211,11 @@ impl Allocator for HeapAllocator {
             start
         );
 
+        self.mm.with_inner(|inner| {
+            inner.live_objects += 1;
+            inner.live_bytes += size;
+        });
+
         Ok(HeapAllocation(Some(node)))
     }
 
@@ -1032,6 +1229,42 @@ impl Allocator for HeapAllocator {
         })
     }
 
+    fn check_integrity(&self) -> Option<Corruption> {
+        if !self.cpu_maps {
+            return None;
+        }
+
+        self.mm.with_inner(|inner| {
+            for (obj, gpuva) in inner.backing_objects.iter_mut() {
+                let size = obj.size();
+                let ptr = match obj.vmap() {
+                    Ok(vmap) => vmap.as_ptr() as *const u8,
+                    Err(_) => continue,
+                };
+                // SAFETY: The vmap covers the whole backing object, which is mapped at `gpuva`.
+                if let Some(c) = unsafe { scan_region(ptr, *gpuva, size) } {
+                    return Some(c);
+                }
+            }
+            None
+        })
+    }
+
+    fn stats(&self) -> AllocStats {
+        let mut stats = self.mm.with_inner(|inner| AllocStats {
+            backing_bytes: inner.backing_objects.iter().map(|o| o.0.size()).sum(),
+            live_objects: inner.live_objects,
+            live_bytes: inner.live_bytes,
+            garbage_objects: inner.garbage.as_ref().map_or(0, |g| g.len()),
+            garbage_bytes: inner.total_garbage,
+            largest_hole: 0,
+            failures: inner.failures,
+        });
+
+        stats.largest_hole = self.largest_hole() as usize;
+        stats
+    }
+
     fn collect_garbage(&mut self, count: usize) {
         // Take the garbage out of the inner block, so we can safely drop it without deadlocking
         let mut garbage = Vec::new();
@@ -1078,3 +1311,375 @@ impl Drop for HeapAllocatorInner {
         }
     }
 }
+
+/// Size of the heap chunks that slab size classes are carved from.
+const SLAB_CHUNK_SIZE: usize = 0x4000;
+
+/// Largest object size served from a slab size class. Bigger objects go to the heap directly.
+const SLAB_MAX_OBJECT: usize = 0x800;
+
+/// A free slab slot.
+#[derive(Copy, Clone)]
+struct SlabSlot {
+    ptr: Option<NonNull<u8>>,
+    gpu_ptr: u64,
+}
+
+/// SAFETY: `SlabSlot` just points to raw memory owned by the slab chunks.
+unsafe impl Send for SlabSlot {}
+unsafe impl Sync for SlabSlot {}
+
+/// A freed slab allocation which cannot be reused until garbage is collected.
+enum SlabGarbage {
+    /// A slot in a given size class.
+    Slot(usize, SlabSlot),
+    /// A direct allocation of a given size, now in the garbage list of the backing allocator.
+    Direct(usize),
+}
+
+/// Shared slab state, referenced by the allocator and by all of its live allocations.
+struct SlabState<R: RawAllocation> {
+    /// Backing chunks. These are never freed while the slab is alive.
+    chunks: Vec<R>,
+    /// Total slot count and free slots, per size class.
+    free: Vec<(usize, Vec<SlabSlot>)>,
+    /// Freed allocations in order of release, if garbage is kept.
+    garbage: Option<Vec<SlabGarbage>>,
+    garbage_bytes: usize,
+    /// Total number of slots carved out of chunks.
+    total_slots: usize,
+    /// Number of direct allocations which are live or in the garbage list.
+    direct: usize,
+    live_slots: usize,
+    live_slot_bytes: usize,
+}
+
+impl<R: RawAllocation> SlabState<R> {
+    /// Make sure the garbage list can take one entry per slot and direct allocation, so that
+    /// dropping an allocation never needs to allocate memory.
+    fn reserve_garbage(&mut self, extra: usize) -> Result {
+        let need = self.total_slots + self.direct + extra;
+        if let Some(garbage) = self.garbage.as_mut() {
+            garbage.try_reserve(need.saturating_sub(garbage.len()))?;
+        }
+        Ok(())
+    }
+}
+
+/// The location of a slab allocation.
+enum SlabKind<R: RawAllocation> {
+    /// A slot in a given size class, with the requested size.
+    Slot(usize, SlabSlot, usize),
+    /// A direct allocation from the backing allocator.
+    ///
+    /// # Invariants
+    /// The `Option` must always be `Some(...)` while this object is alive.
+    Direct(Option<R>),
+}
+
+/// An allocation from a `SlabAllocator`.
+pub(crate) struct SlabAllocation<R: RawAllocation> {
+    dev: AsahiDevRef,
+    kind: SlabKind<R>,
+    class_size: usize,
+    slab: Arc<Mutex<SlabState<R>>>,
+}
+
+impl<R: RawAllocation> Drop for SlabAllocation<R> {
+    fn drop(&mut self) {
+        match &mut self.kind {
+            SlabKind::Slot(class, slot, size) => {
+                let mut slab = self.slab.lock();
+                slab.live_slots -= 1;
+                slab.live_slot_bytes -= *size;
+                if let Some(garbage) = slab.garbage.as_mut() {
+                    garbage.push(SlabGarbage::Slot(*class, *slot));
+                    slab.garbage_bytes += self.class_size;
+                } else {
+                    slab.free[*class].1.push(*slot);
+                }
+            }
+            SlabKind::Direct(raw) => {
+                let raw = raw.take().unwrap();
+                let size = raw.size();
+                // Drop it first, so the backing allocator adds it to its garbage list before we
+                // account for it in ours.
+                core::mem::drop(raw);
+
+                let mut slab = self.slab.lock();
+                if let Some(garbage) = slab.garbage.as_mut() {
+                    garbage.push(SlabGarbage::Direct(size));
+                    slab.garbage_bytes += size;
+                } else {
+                    slab.direct -= 1;
+                }
+            }
+        }
+    }
+}
+
+impl<R: RawAllocation> RawAllocation for SlabAllocation<R> {
+    fn ptr(&self) -> Option<NonNull<u8>> {
+        match &self.kind {
+            SlabKind::Slot(_, slot, _) => slot.ptr,
+            SlabKind::Direct(raw) => raw.as_ref().unwrap().ptr(),
+        }
+    }
+    fn gpu_ptr(&self) -> u64 {
+        match &self.kind {
+            SlabKind::Slot(_, slot, _) => slot.gpu_ptr,
+            SlabKind::Direct(raw) => raw.as_ref().unwrap().gpu_ptr(),
+        }
+    }
+    fn size(&self) -> usize {
+        match &self.kind {
+            SlabKind::Slot(..) => self.class_size,
+            SlabKind::Direct(raw) => raw.as_ref().unwrap().size(),
+        }
+    }
+    fn device(&self) -> &AsahiDevice {
+        &self.dev
+    }
+}
+
+/// A slab allocator layered on top of another allocator.
+///
+/// Objects up to `SLAB_MAX_OBJECT` bytes (including any debug preamble and guard padding) are
+/// served from per-size-class free lists, which are refilled by carving `SLAB_CHUNK_SIZE` chunks
+/// allocated from the backing allocator. Larger objects are passed through. Freed slots follow the
+/// same garbage rules as the backing allocator, so they are only reused after the firmware cache
+/// has been flushed.
+pub(crate) struct SlabAllocator<A: Allocator> {
+    inner: A,
+    classes: Vec<usize>,
+    slab: Arc<Mutex<SlabState<A::Raw>>>,
+}
+
+impl SlabAllocator<HeapAllocator> {
+    /// Create a new SlabAllocator backed by a `HeapAllocator` for a given `Vm` and address range.
+    #[allow(dead_code)]
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        dev: &AsahiDevice,
+        vm: &mmu::Vm,
+        start: u64,
+        end: u64,
+        min_align: usize,
+        prot: u32,
+        block_size: usize,
+        cpu_maps: bool,
+        name: fmt::Arguments<'_>,
+        keep_garbage: bool,
+    ) -> Result<SlabAllocator<HeapAllocator>> {
+        let inner = HeapAllocator::new(
+            dev,
+            vm,
+            start,
+            end,
+            min_align,
+            prot,
+            block_size,
+            cpu_maps,
+            name,
+            keep_garbage,
+        )?;
+        Self::wrap(inner, keep_garbage)
+    }
+}
+
+impl<A: Allocator> SlabAllocator<A> {
+    /// Wrap an existing allocator. `keep_garbage` must match the backing allocator's setting.
+    pub(crate) fn wrap(inner: A, keep_garbage: bool) -> Result<SlabAllocator<A>> {
+        let mut classes = Vec::new();
+        let mut free = Vec::new();
+        let mut class_size = inner.min_align();
+        while class_size <= SLAB_MAX_OBJECT {
+            classes.push(class_size);
+            free.push((0, Vec::new()));
+            class_size <<= 1;
+        }
+
+        let state = SlabState {
+            chunks: Vec::new(),
+            free,
+            garbage: if keep_garbage { Some(Vec::new()) } else { None },
+            garbage_bytes: 0,
+            total_slots: 0,
+            direct: 0,
+            live_slots: 0,
+            live_slot_bytes: 0,
+        };
+
+        Ok(SlabAllocator {
+            inner,
+            classes,
+            slab: Arc::pin_init(Mutex::new_named(state, c_str!("SlabAllocator")))?,
+        })
+    }
+
+    /// Carve a new chunk from the backing allocator into slots of a given size class.
+    fn refill(&mut self, class: usize) -> Result {
+        let class_size = self.classes[class];
+        let count = SLAB_CHUNK_SIZE / class_size;
+
+        // Reserve all the bookkeeping space first, so that we never have to give the chunk back.
+        {
+            let mut guard = self.slab.lock();
+            let slab = &mut *guard;
+            slab.chunks.try_reserve(1)?;
+            let (total, free) = &mut slab.free[class];
+            free.try_reserve(*total + count - free.len())?;
+            slab.reserve_garbage(count)?;
+        }
+
+        let chunk = self.inner.alloc(SLAB_CHUNK_SIZE, class_size)?;
+
+        mod_dev_dbg!(
+            self.device(),
+            "SlabAllocator: new chunk for class {:#x} @ {:#x}\n",
+            class_size,
+            chunk.gpu_ptr()
+        );
+
+        let mut guard = self.slab.lock();
+        let slab = &mut *guard;
+        slab.total_slots += count;
+        let (total, free) = &mut slab.free[class];
+        *total += count;
+
+        // Push in reverse, so slots are handed out in address order.
+        for i in (0..count).rev() {
+            let offset = i * class_size;
+            free.push(SlabSlot {
+                ptr: chunk
+                    .ptr()
+                    .map(|p| unsafe { NonNull::new_unchecked(p.as_ptr().add(offset)) }),
+                gpu_ptr: chunk.gpu_ptr() + offset as u64,
+            });
+        }
+        slab.chunks.push(chunk);
+
+        Ok(())
+    }
+}
+
+impl<A: Allocator> Allocator for SlabAllocator<A> {
+    type Raw = SlabAllocation<A::Raw>;
+
+    fn device(&self) -> &AsahiDevice {
+        self.inner.device()
+    }
+
+    fn cpu_maps(&self) -> bool {
+        self.inner.cpu_maps()
+    }
+
+    fn min_align(&self) -> usize {
+        self.inner.min_align()
+    }
+
+    fn alloc(&mut self, size: usize, align: usize) -> Result<SlabAllocation<A::Raw>> {
+        let class = self
+            .classes
+            .iter()
+            .position(|&class_size| class_size >= size && class_size >= align);
+
+        let class = match class {
+            Some(class) => class,
+            None => {
+                self.slab.lock().reserve_garbage(1)?;
+                let raw = self.inner.alloc(size, align)?;
+                self.slab.lock().direct += 1;
+                return Ok(SlabAllocation {
+                    dev: self.device().into(),
+                    class_size: raw.size(),
+                    kind: SlabKind::Direct(Some(raw)),
+                    slab: self.slab.clone(),
+                });
+            }
+        };
+
+        let slot = self.slab.lock().free[class].1.pop();
+        let slot = match slot {
+            Some(slot) => slot,
+            None => {
+                self.refill(class)?;
+                self.slab.lock().free[class].1.pop().ok_or(ENOMEM)?
+            }
+        };
+
+        {
+            let mut slab = self.slab.lock();
+            slab.live_slots += 1;
+            slab.live_slot_bytes += size;
+        }
+
+        Ok(SlabAllocation {
+            dev: self.device().into(),
+            class_size: self.classes[class],
+            kind: SlabKind::Slot(class, slot, size),
+            slab: self.slab.clone(),
+        })
+    }
+
+    fn garbage(&self) -> (usize, usize) {
+        let slab = self.slab.lock();
+        match slab.garbage.as_ref() {
+            Some(g) => (g.len(), slab.garbage_bytes),
+            None => (0, 0),
+        }
+    }
+
+    fn collect_garbage(&mut self, count: usize) {
+        let mut direct = 0;
+
+        {
+            let mut guard = self.slab.lock();
+            let slab = &mut *guard;
+            if let Some(garbage) = slab.garbage.as_mut() {
+                let count = count.min(garbage.len());
+                for item in garbage.drain(0..count) {
+                    match item {
+                        SlabGarbage::Slot(class, slot) => {
+                            slab.garbage_bytes -= self.classes[class];
+                            slab.free[class].1.push(slot);
+                        }
+                        SlabGarbage::Direct(size) => {
+                            slab.garbage_bytes -= size;
+                            slab.direct -= 1;
+                            direct += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Direct allocations enter the backing allocator's garbage list in the same order as ours,
+        // so this releases exactly the ones we just drained.
+        if direct > 0 {
+            self.inner.collect_garbage(direct);
+        }
+    }
+
+    fn check_integrity(&self) -> Option<Corruption> {
+        // Slab chunks live in the backing allocator's memory, so this covers them too.
+        self.inner.check_integrity()
+    }
+
+    fn stats(&self) -> AllocStats {
+        let mut stats = self.inner.stats();
+        let slab = self.slab.lock();
+
+        // The backing allocator sees each chunk as one live object.
+        stats.live_objects = stats.live_objects.saturating_sub(slab.chunks.len()) + slab.live_slots;
+        stats.live_bytes = stats
+            .live_bytes
+            .saturating_sub(slab.chunks.len() * SLAB_CHUNK_SIZE)
+            + slab.live_slot_bytes;
+        if let Some(g) = slab.garbage.as_ref() {
+            stats.garbage_objects = g.len();
+            stats.garbage_bytes = slab.garbage_bytes;
+        }
+        stats
+    }
+}
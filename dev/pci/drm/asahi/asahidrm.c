@@ -4,11 +4,12 @@
 
 int	asahidrm_match(struct device *, void *, void *);
 void	asahidrm_attach(struct device *, struct device *, void *);
+int	asahidrm_detach(struct device *, int);
 int	asahidrm_activate(struct device *, int);
 
 const struct cfattach asahidrm_ca = {
 	sizeof (struct asahidrm_softc), asahidrm_match, asahidrm_attach,
-	NULL, asahidrm_activate
+	asahidrm_detach, asahidrm_activate
 };
 
 struct cfdriver asahidrm_cd = {
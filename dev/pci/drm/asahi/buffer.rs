@@ -55,6 +55,12 @@ pub(crate) const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
 pub(crate) const PAGES_PER_BLOCK: usize = 4;
 /// Size of a buffer block.
 pub(crate) const BLOCK_SIZE: usize = PAGE_SIZE * PAGES_PER_BLOCK;
+/// Number of blocks allocated up front for a new render queue.
+pub(crate) const INITIAL_BLOCKS: usize = 8;
+/// Maximum size of a buffer. This is the typical max on macOS (8GB machines have this halved).
+pub(crate) const MAX_SIZE: usize = 862_322_688;
+/// Maximum size of a buffer when not using memoryless render targets.
+pub(crate) const MAX_SIZE_NOMEMLESS: usize = MAX_SIZE / 3;
 
 /// Metadata about the tiling configuration for a scene. This is computed in the `render` module.
 /// based on dimensions, tile size, and other info.
@@ -315,13 +321,8 @@ impl Buffer::ver {
         ualloc_priv: Arc<Mutex<alloc::DefaultAllocator>>,
         mgr: &BufferManager::ver,
     ) -> Result<Buffer::ver> {
-        // These are the typical max numbers on macOS.
-        // 8GB machines have this halved.
-        let max_size: usize = 862_322_688; // bytes
-        let max_size_nomemless = max_size / 3;
-
-        let max_blocks = max_size / BLOCK_SIZE;
-        let max_blocks_nomemless = max_size_nomemless / BLOCK_SIZE;
+        let max_blocks = MAX_SIZE / BLOCK_SIZE;
+        let max_blocks_nomemless = MAX_SIZE_NOMEMLESS / BLOCK_SIZE;
         let max_pages = max_blocks * PAGES_PER_BLOCK;
         let max_pages_nomemless = max_blocks_nomemless * PAGES_PER_BLOCK;
 
@@ -55,6 +55,8 @@ pub(crate) enum DebugFlags {
     WaitForPowerOff = 38,
     NoGpuRecovery = 39,
     DisableClustering = 40,
+    ScanAllocations = 41,
+    TraceSubmissions = 42,
 
     // 48-: Misc
     Debug0 = 48,
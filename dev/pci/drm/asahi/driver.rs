@@ -54,5 +54,11 @@ impl drv::Driver for AsahiDriver {
             ioctl::AUTH | ioctl::RENDER_ALLOW, crate::file::File::queue_destroy),
         (ASAHI_SUBMIT,          drm_asahi_submit,
             ioctl::AUTH | ioctl::RENDER_ALLOW, crate::file::File::submit),
+        (ASAHI_GET_TIME,        drm_asahi_get_time,
+            ioctl::AUTH | ioctl::RENDER_ALLOW, crate::file::File::get_time),
+        (ASAHI_GEM_SYNC,        drm_asahi_gem_sync,
+            ioctl::AUTH | ioctl::RENDER_ALLOW, crate::file::File::gem_sync),
+        (ASAHI_TRACE_READ,      drm_asahi_trace_read,
+            ioctl::AUTH | ioctl::RENDER_ALLOW, crate::file::File::trace_read),
     }
 }
@@ -201,6 +201,21 @@ impl EventManager {
         }
     }
 
+    /// Returns the work queue that currently owns an event slot, along with whether any of its work
+    /// up to `wait_value` runs in the VM bound to `vm_slot`. Used to find the culprit of a fault.
+    pub(crate) fn fault_owner(
+        &self,
+        slot: u32,
+        wait_value: u32,
+        vm_slot: u32,
+    ) -> Option<(Arc<dyn workqueue::WorkQueue + Send + Sync>, bool)> {
+        let owner = self
+            .alloc
+            .with_inner(|inner| inner.owners[slot as usize].as_ref().cloned())?;
+        let guilty = owner.owns_vm_slot(EventValue(wait_value), vm_slot);
+        Some((owner, guilty))
+    }
+
     /// Marks the owner of an event as having lost its work due to a GPU error.
     pub(crate) fn mark_error(&self, slot: u32, wait_value: u32, error: workqueue::WorkError) {
         match self
@@ -0,0 +1,91 @@
+// SPDX-License-Identifier: GPL-2.0-only OR MIT
+
+//! uAPI extension chains
+//!
+//! Most ioctl argument structs carry an `extensions` pointer to an optional, singly linked chain
+//! of extension structs in user memory. Every extension struct starts with a common header that
+//! holds its type ID and a pointer to the next extension. This module walks those chains with a
+//! bound on their length, so a malformed (e.g. self-referencing) chain is rejected instead of
+//! keeping the kernel spinning.
+
+use crate::umem::{UserMemory, UserSpace};
+use kernel::prelude::*;
+
+/// Maximum number of extensions accepted in a single chain.
+const MAX_EXTENSIONS: usize = 16;
+
+/// Common header at the start of every extension struct.
+#[repr(C)]
+struct ExtHeader {
+    ext_type: u32,
+    pad: u32,
+    next: u64,
+}
+
+/// A single extension in a chain, as passed to the `walk()` callback.
+pub(crate) struct Extension<'a, M: UserMemory = UserSpace> {
+    mem: &'a M,
+    ext_type: u32,
+    ptr: u64,
+}
+
+impl<M: UserMemory> Extension<'_, M> {
+    /// Returns the type ID of this extension.
+    pub(crate) fn ext_type(&self) -> u32 {
+        self.ext_type
+    }
+
+    /// Reads the full extension struct, including its header.
+    ///
+    /// The header is fetched from user memory again, so its `type` and `next` fields may not
+    /// match what the walker saw. Callers must not use them; the walker follows its own copy.
+    ///
+    /// # Safety
+    /// `T` must be a plain old data type for which any bit pattern is valid.
+    pub(crate) unsafe fn read<T>(&self) -> Result<T> {
+        // SAFETY: Forwarded from our caller.
+        unsafe { self.mem.read(self.ptr) }
+    }
+}
+
+/// Walks the extension chain starting at `ptr`, calling `cb` on each extension in order.
+///
+/// Fails with `EINVAL` if the chain is longer than `MAX_EXTENSIONS` or a header has nonzero
+/// padding, with `EFAULT` if a header cannot be read, and with any error returned by `cb`.
+pub(crate) fn walk(ptr: u64, cb: impl FnMut(&Extension) -> Result) -> Result {
+    walk_in(&UserSpace, ptr, cb)
+}
+
+/// Walks an extension chain in `mem`, see [`walk`].
+pub(crate) fn walk_in<M: UserMemory>(
+    mem: &M,
+    mut ptr: u64,
+    mut cb: impl FnMut(&Extension<'_, M>) -> Result,
+) -> Result {
+    let mut count = 0;
+
+    while ptr != 0 {
+        if count >= MAX_EXTENSIONS {
+            cls_pr_debug!(Errors, "Extension chain too long (> {})\n", MAX_EXTENSIONS);
+            return Err(EINVAL);
+        }
+        count += 1;
+
+        // SAFETY: ExtHeader is plain old data.
+        let hdr: ExtHeader = unsafe { mem.read(ptr)? };
+        if hdr.pad != 0 {
+            cls_pr_debug!(Errors, "Nonzero extension header pad: {}\n", hdr.pad);
+            return Err(EINVAL);
+        }
+
+        cb(&Extension {
+            mem,
+            ext_type: hdr.ext_type,
+            ptr,
+        })?;
+
+        ptr = hdr.next;
+    }
+
+    Ok(())
+}
@@ -9,20 +9,26 @@
 
 use crate::debug::*;
 use crate::driver::AsahiDevice;
-use crate::{alloc, buffer, driver, gem, mmu, queue};
-use core::mem::MaybeUninit;
+use crate::umem::{UserMemory, UserSpace};
+use crate::{alloc, buffer, driver, ext, gem, gpu, mem, mmu, queue, trace};
+use core::sync::atomic::AtomicU32;
+use core::time::Duration;
 use kernel::dma_fence::RawDmaFence;
 use kernel::drm::gem::BaseObject;
-use kernel::io_buffer::{IoBufferReader, IoBufferWriter};
+use kernel::io_buffer::IoBufferWriter;
 use kernel::prelude::*;
 use kernel::sync::{Arc, Mutex};
 use kernel::user_ptr::UserSlicePtr;
-use kernel::{dma_fence, drm, uapi, xarray};
+use kernel::{bindings, dma_fence, drm, uapi, xarray};
 
 const DEBUG_CLASS: DebugFlags = DebugFlags::File;
 
 const MAX_COMMANDS_PER_SUBMISSION: u32 = 64;
 pub(crate) const MAX_COMMANDS_IN_FLIGHT: u32 = 1024;
+pub(crate) const MAX_TIMESTAMPS_IN_FLIGHT: u32 = 1024;
+const MAX_IMPLICIT_SYNC_BOS: u32 = 1024;
+const MAX_TRACE_EVENTS_PER_READ: u32 = 4096;
+const MAX_TRACE_JSON_BYTES_PER_READ: u32 = 256 * 1024;
 
 /// A client instance of an `mmu::Vm` address space.
 struct Vm {
@@ -48,7 +54,8 @@ pub(crate) struct SyncItem {
 }
 
 impl SyncItem {
-    fn parse_one(file: &DrmFile, data: uapi::drm_asahi_sync, out: bool) -> Result<SyncItem> {
+    /// Validates a sync item from userspace, without looking up its sync object.
+    fn validate(data: &uapi::drm_asahi_sync) -> Result {
         if data.extensions != 0 {
             cls_pr_debug!(Errors, "drm_asahi_sync extension unexpected\n");
             return Err(EINVAL);
@@ -60,52 +67,9 @@ impl SyncItem {
                     cls_pr_debug!(Errors, "Non-timeline sync object with a nonzero value\n");
                     return Err(EINVAL);
                 }
-                let syncobj = drm::syncobj::SyncObj::lookup_handle(file, data.handle)?;
-
-                Ok(SyncItem {
-                    fence: if out {
-                        None
-                    } else {
-                        Some(syncobj.fence_get().ok_or_else(|| {
-                            cls_pr_debug!(Errors, "Failed to get fence from sync object\n");
-                            EINVAL
-                        })?)
-                    },
-                    syncobj,
-                    chain_fence: None,
-                    timeline_value: data.timeline_value,
-                })
-            }
-            uapi::drm_asahi_sync_type_DRM_ASAHI_SYNC_TIMELINE_SYNCOBJ => {
-                let syncobj = drm::syncobj::SyncObj::lookup_handle(file, data.handle)?;
-                let fence = if out {
-                    None
-                } else {
-                    Some(
-                        syncobj
-                            .fence_get()
-                            .ok_or_else(|| {
-                                cls_pr_debug!(
-                                    Errors,
-                                    "Failed to get fence from timeline sync object\n"
-                                );
-                                EINVAL
-                            })?
-                            .chain_find_seqno(data.timeline_value)?,
-                    )
-                };
-
-                Ok(SyncItem {
-                    fence,
-                    syncobj,
-                    chain_fence: if out {
-                        Some(dma_fence::FenceChain::new()?)
-                    } else {
-                        None
-                    },
-                    timeline_value: data.timeline_value,
-                })
+                Ok(())
             }
+            uapi::drm_asahi_sync_type_DRM_ASAHI_SYNC_TIMELINE_SYNCOBJ => Ok(()),
             _ => {
                 cls_pr_debug!(Errors, "Invalid sync type {}\n", data.sync_type);
                 Err(EINVAL)
@@ -113,25 +77,124 @@ impl SyncItem {
         }
     }
 
+    /// Looks up the sync object of an already validated sync item.
+    fn parse_one(file: &DrmFile, data: uapi::drm_asahi_sync, out: bool) -> Result<SyncItem> {
+        let syncobj = drm::syncobj::SyncObj::lookup_handle(file, data.handle)?;
+
+        if data.sync_type == uapi::drm_asahi_sync_type_DRM_ASAHI_SYNC_SYNCOBJ {
+            return Ok(SyncItem {
+                fence: if out {
+                    None
+                } else {
+                    Some(syncobj.fence_get().ok_or_else(|| {
+                        cls_pr_debug!(Errors, "Failed to get fence from sync object\n");
+                        EINVAL
+                    })?)
+                },
+                syncobj,
+                chain_fence: None,
+                timeline_value: data.timeline_value,
+            });
+        }
+
+        // An in_sync point that has not been submitted yet has no fence. The queue
+        // waits for it to show up before running the job.
+        let fence = if out {
+            None
+        } else {
+            syncobj
+                .fence_get()
+                .and_then(|fence| fence.chain_find_seqno(data.timeline_value).ok())
+        };
+
+        Ok(SyncItem {
+            fence,
+            syncobj,
+            chain_fence: if out {
+                Some(dma_fence::FenceChain::new()?)
+            } else {
+                None
+            },
+            timeline_value: data.timeline_value,
+        })
+    }
+
+    /// Reads and validates an array of sync items from `mem`.
+    pub(crate) fn read_array(
+        mem: &impl UserMemory,
+        ptr: u64,
+        count: u32,
+    ) -> Result<Vec<uapi::drm_asahi_sync>> {
+        // SAFETY: drm_asahi_sync is plain old data.
+        let syncs: Vec<uapi::drm_asahi_sync> = unsafe { mem.read_array(ptr, count as usize)? };
+
+        for sync in syncs.iter() {
+            SyncItem::validate(sync)?;
+        }
+
+        Ok(syncs)
+    }
+
     fn parse_array(file: &DrmFile, ptr: u64, count: u32, out: bool) -> Result<Vec<SyncItem>> {
         let mut vec = Vec::with_capacity(count as usize);
 
-        const STRIDE: usize = core::mem::size_of::<uapi::drm_asahi_sync>();
-        let size = STRIDE * count as usize;
+        for sync in SyncItem::read_array(&UserSpace, ptr, count)? {
+            vec.push(SyncItem::parse_one(file, sync, out)?);
+        }
 
-        // SAFETY: We only read this once, so there are no TOCTOU issues.
-        let mut reader = unsafe { UserSlicePtr::new(ptr as usize as *mut _, size).reader() };
+        Ok(vec)
+    }
+}
 
-        for _i in 0..count {
-            let mut sync: MaybeUninit<uapi::drm_asahi_sync> = MaybeUninit::uninit();
+/// Buffer object shared with other devices or clients, synchronized implicitly.
+pub(crate) struct BoSync {
+    pub(crate) obj: gem::ObjectRef,
+    pub(crate) write: bool,
+}
 
-            // SAFETY: The size of `sync` is STRIDE
-            unsafe { reader.read_raw(sync.as_mut_ptr() as *mut u8, STRIDE)? };
+impl BoSync {
+    /// Validates BO usage flags, returning whether the job writes to the BO.
+    fn parse_flags(flags: u32) -> Result<bool> {
+        let valid = (uapi::ASAHI_BO_USAGE_READ | uapi::ASAHI_BO_USAGE_WRITE) as u32;
+        if flags == 0 || flags & !valid != 0 {
+            cls_pr_debug!(Errors, "Invalid BO usage flags {:#x}\n", flags);
+            return Err(EINVAL);
+        }
 
-            // SAFETY: All bit patterns in the struct are valid
-            let sync = unsafe { sync.assume_init() };
+        Ok(flags & uapi::ASAHI_BO_USAGE_WRITE as u32 != 0)
+    }
 
-            vec.push(SyncItem::parse_one(file, sync, out)?);
+    fn parse_array(file: &DrmFile, ptr: u64, count: u32) -> Result<Vec<BoSync>> {
+        if count > MAX_IMPLICIT_SYNC_BOS {
+            cls_pr_debug!(
+                Errors,
+                "Too many implicit sync BOs: {} > {}\n",
+                count,
+                MAX_IMPLICIT_SYNC_BOS
+            );
+            return Err(EINVAL);
+        }
+
+        // SAFETY: drm_asahi_bo_usage is plain old data.
+        let mut usages: Vec<uapi::drm_asahi_bo_usage> =
+            unsafe { UserSpace.read_array(ptr, count as usize)? };
+
+        // Each BO's reservation can only be locked once per submission, so merge repeated
+        // handles into a single entry.
+        usages.sort_unstable_by_key(|usage| usage.handle);
+
+        let mut vec: Vec<BoSync> = Vec::with_capacity(usages.len());
+        let mut last_handle = None;
+        for usage in usages {
+            let write = Self::parse_flags(usage.flags)?;
+            match vec.last_mut() {
+                Some(last) if last_handle == Some(usage.handle) => last.write |= write,
+                _ => vec.push(BoSync {
+                    obj: gem::lookup_handle(file, usage.handle)?,
+                    write,
+                }),
+            }
+            last_handle = Some(usage.handle);
         }
 
         Ok(vec)
@@ -139,10 +202,18 @@ impl SyncItem {
 }
 
 /// State associated with a client.
+///
+/// The `vms` and `queues` lookups are deliberately not behind an `RwLock`. Each lookup holds the
+/// XArray's internal spinning mutex only long enough to load an entry and clone an `Arc` or
+/// `mmu::Vm` out of it, and never sleeps. An rwlock is a sleeping lock with a more expensive
+/// fast path, so readers would not gain anything from it. The per-queue `Mutex` is not a read
+/// path either: `Queue::submit` takes `&mut self`, so submissions to one queue must be serialized.
 pub(crate) struct File {
     id: u64,
     vms: xarray::XArray<Box<Vm>>,
     queues: xarray::XArray<Arc<Mutex<Box<dyn queue::Queue>>>>,
+    /// Number of timestamp pages currently mapped for this client's submissions.
+    timestamps: Arc<AtomicU32>,
 }
 
 /// Convenience type alias for our DRM `File` type.
@@ -183,6 +254,7 @@ impl drm::file::DriverFile for File {
             id,
             vms: xarray::XArray::new(xarray::flags::ALLOC1),
             queues: xarray::XArray::new(xarray::flags::ALLOC1),
+            timestamps: Arc::try_new(AtomicU32::new(0))?,
         })))
     }
 }
@@ -201,6 +273,11 @@ impl File {
         unsafe { self.map_unchecked(|s| &s.queues) }
     }
 
+    /// Returns the count of timestamp pages mapped for this client.
+    pub(crate) fn timestamps(&self) -> &Arc<AtomicU32> {
+        &self.timestamps
+    }
+
     /// IOCTL: get_param: Get a driver parameter value.
     pub(crate) fn get_params(
         device: &AsahiDevice,
@@ -211,7 +288,7 @@ impl File {
 
         let gpu = &device.data().gpu;
 
-        if data.extensions != 0 || data.param_group != 0 || data.pad != 0 {
+        if data.extensions != 0 || data.pad != 0 {
             cls_pr_debug!(Errors, "get_params: Invalid arguments\n");
             return Err(EINVAL);
         }
@@ -220,11 +297,56 @@ impl File {
             return Err(ENODEV);
         }
 
+        match data.param_group {
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_GLOBAL => {
+                Self::write_params(data, &Self::params_global(&**gpu)?)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_PERF_STATES => {
+                Self::write_params(data, &Self::params_perf_states(&**gpu))
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_RENDER => {
+                Self::write_params(data, &Self::params_render(&**gpu))
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_TVB => {
+                Self::write_params(data, &Self::params_tvb())
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_FIRMWARE => {
+                Self::write_params(data, &Self::params_firmware(&**gpu))
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_PREEMPTION => {
+                Self::write_params(data, &gpu.preemption_stats().params())
+            }
+            group => {
+                cls_pr_debug!(Errors, "get_params: Unknown param group {}\n", group);
+                Err(EINVAL)
+            }
+        }
+    }
+
+    /// Copy a parameter struct out to the user buffer described by `data`, truncating it to the
+    /// user buffer size, and report the full struct size back in `data.size`.
+    fn write_params<T>(data: &mut uapi::drm_asahi_get_params, params: &T) -> Result<u32> {
+        let size = core::mem::size_of::<T>().min(data.size.try_into()?);
+
+        // SAFETY: We only write to this userptr once, so there are no TOCTOU issues.
+        let mut params_writer =
+            unsafe { UserSlicePtr::new(data.pointer as usize as *mut _, size).writer() };
+
+        // SAFETY: `size` is at most the sizeof of `params`
+        unsafe { params_writer.write_raw(params as *const _ as *const u8, size)? };
+
+        data.size = core::mem::size_of::<T>() as u64;
+
+        Ok(0)
+    }
+
+    fn params_global(gpu: &dyn gpu::GpuManager) -> Result<uapi::drm_asahi_params_global> {
         let mut params = uapi::drm_asahi_params_global {
             unstable_uabi_version: uapi::DRM_ASAHI_UNSTABLE_UABI_VERSION,
             pad0: 0,
 
-            feat_compat: gpu.get_cfg().gpu_feat_compat,
+            feat_compat: gpu.get_cfg().gpu_feat_compat
+                | uapi::drm_asahi_feat_compat_DRM_ASAHI_FEAT_SPARSE as u64,
             feat_incompat: gpu.get_cfg().gpu_feat_incompat,
 
             gpu_generation: gpu.get_dyncfg().id.gpu_gen as u32,
@@ -271,16 +393,93 @@ impl File {
             params.firmware_version[i] = *gpu.get_dyncfg().firmware_version.get(i).unwrap_or(&0);
         }
 
-        let size = core::mem::size_of::<uapi::drm_asahi_params_global>().min(data.size.try_into()?);
+        Ok(params)
+    }
 
-        // SAFETY: We only write to this userptr once, so there are no TOCTOU issues.
-        let mut params_writer =
-            unsafe { UserSlicePtr::new(data.pointer as usize as *mut _, size).writer() };
+    fn params_perf_states(gpu: &dyn gpu::GpuManager) -> uapi::drm_asahi_params_perf_states {
+        let pwr = &gpu.get_dyncfg().pwr;
+        let mut params = uapi::drm_asahi_params_perf_states {
+            size: core::mem::size_of::<uapi::drm_asahi_params_perf_states>() as u32,
+            version: uapi::DRM_ASAHI_PARAMS_PERF_STATES_VERSION,
+            perf_base_pstate: pwr.perf_base_pstate,
+            perf_max_pstate: pwr.perf_max_pstate,
+            max_power_mw: pwr.max_power_mw,
+            ..Default::default()
+        };
 
-        // SAFETY: `size` is at most the sizeof of `params`
-        unsafe { params_writer.write_raw(&params as *const _ as *const u8, size)? };
+        for (out, ps) in params.perf_states.iter_mut().zip(pwr.perf_states.iter()) {
+            *out = uapi::drm_asahi_perf_state {
+                freq_hz: ps.freq_hz,
+                max_volt_mv: ps.max_volt_mv(),
+                pwr_mw: ps.pwr_mw,
+                pad: 0,
+            };
+            params.num_perf_states += 1;
+        }
 
-        Ok(0)
+        for (out, pz) in params.power_zones.iter_mut().zip(pwr.power_zones.iter()) {
+            *out = uapi::drm_asahi_power_zone {
+                target: pz.target,
+                target_offset: pz.target_offset,
+                filter_tc: pz.filter_tc,
+                pad: 0,
+            };
+            params.num_power_zones += 1;
+        }
+
+        params
+    }
+
+    fn params_render(gpu: &dyn gpu::GpuManager) -> uapi::drm_asahi_params_render {
+        let cfg = gpu.get_cfg();
+        let mut params = uapi::drm_asahi_params_render {
+            size: core::mem::size_of::<uapi::drm_asahi_params_render>() as u32,
+            version: uapi::DRM_ASAHI_PARAMS_RENDER_VERSION,
+            tiling_control: cfg.render.tiling_control,
+            ..Default::default()
+        };
+
+        if let Some(clustering) = cfg.clustering.as_ref() {
+            params.has_clustering = 1;
+            params.clustering_meta1_blocksize = clustering.meta1_blocksize as u32;
+            params.clustering_meta2_size = clustering.meta2_size as u32;
+            params.clustering_meta3_size = clustering.meta3_size as u32;
+            params.clustering_meta4_size = clustering.meta4_size as u32;
+            params.clustering_max_splits = clustering.max_splits as u32;
+        }
+
+        params
+    }
+
+    fn params_tvb() -> uapi::drm_asahi_params_tvb {
+        uapi::drm_asahi_params_tvb {
+            size: core::mem::size_of::<uapi::drm_asahi_params_tvb>() as u32,
+            version: uapi::DRM_ASAHI_PARAMS_TVB_VERSION,
+            page_size: buffer::PAGE_SIZE as u32,
+            pages_per_block: buffer::PAGES_PER_BLOCK as u32,
+            block_size: buffer::BLOCK_SIZE as u32,
+            initial_blocks: buffer::INITIAL_BLOCKS as u32,
+            max_size: buffer::MAX_SIZE as u64,
+            max_size_nomemless: buffer::MAX_SIZE_NOMEMLESS as u64,
+        }
+    }
+
+    fn params_firmware(gpu: &dyn gpu::GpuManager) -> uapi::drm_asahi_params_firmware {
+        let mut params = uapi::drm_asahi_params_firmware {
+            size: core::mem::size_of::<uapi::drm_asahi_params_firmware>() as u32,
+            version: uapi::DRM_ASAHI_PARAMS_FIRMWARE_VERSION,
+            ..Default::default()
+        };
+
+        for (out, v) in params
+            .firmware_version
+            .iter_mut()
+            .zip(gpu.get_dyncfg().firmware_version.iter())
+        {
+            *out = *v;
+        }
+
+        params
     }
 
     /// IOCTL: vm_create: Create a new `Vm`.
@@ -478,6 +677,10 @@ impl File {
             uapi::drm_asahi_bind_op_ASAHI_BIND_OP_UNBIND_ALL => {
                 Self::do_gem_unbind_all(device, data, file)
             }
+            uapi::drm_asahi_bind_op_ASAHI_BIND_OP_BIND_SPARSE
+            | uapi::drm_asahi_bind_op_ASAHI_BIND_OP_UNBIND_SPARSE => {
+                Self::do_gem_bind_sparse(device, data, file)
+            }
             _ => {
                 cls_pr_debug!(Errors, "gem_bind: Invalid op {}\n", data.op);
                 Err(EINVAL)
@@ -518,7 +721,53 @@ impl File {
         }
 
         let start = data.addr;
-        let end = data.addr + data.range - 1;
+        Self::check_bind_range(start, data.range, true)?;
+
+        let prot = if data.flags & uapi::ASAHI_BIND_READ != 0 {
+            if data.flags & uapi::ASAHI_BIND_WRITE != 0 {
+                mmu::PROT_GPU_SHARED_RW
+            } else {
+                mmu::PROT_GPU_SHARED_RO
+            }
+        } else if data.flags & uapi::ASAHI_BIND_WRITE != 0 {
+            mmu::PROT_GPU_SHARED_WO
+        } else {
+            cls_pr_debug!(
+                Errors,
+                "gem_bind: Must specify read or write (flags: {:#x})\n",
+                data.flags
+            );
+            return Err(EINVAL); // Must specify one of ASAHI_BIND_{READ,WRITE}
+        };
+
+        // Clone it immediately so we aren't holding the XArray lock
+        let vm = file
+            .inner()
+            .vms()
+            .get(data.vm_id.try_into()?)
+            .ok_or(ENOENT)?
+            .borrow()
+            .vm
+            .clone();
+
+        bo.map_at(&vm, start, prot, true)?;
+
+        Ok(0)
+    }
+
+    /// Check that a range lies entirely within one of the user-managed VA regions. With `guard`,
+    /// the guard page that follows an object mapping must lie within the same region too.
+    fn check_bind_range(start: u64, range: u64, guard: bool) -> Result {
+        if range == 0 {
+            cls_pr_debug!(Errors, "gem_bind: Empty range\n");
+            return Err(EINVAL);
+        }
+
+        let guard_size = if guard { mmu::UAT_PGSZ as u64 } else { 0 };
+        let end = start
+            .checked_add(range - 1)
+            .and_then(|end| end.checked_add(guard_size))
+            .ok_or(EINVAL)?;
 
         if (VM_SHADER_START..=VM_SHADER_END).contains(&start) {
             if !(VM_SHADER_START..=VM_SHADER_END).contains(&end) {
@@ -561,36 +810,7 @@ impl File {
             return Err(EINVAL);
         }
 
-        let prot = if data.flags & uapi::ASAHI_BIND_READ != 0 {
-            if data.flags & uapi::ASAHI_BIND_WRITE != 0 {
-                mmu::PROT_GPU_SHARED_RW
-            } else {
-                mmu::PROT_GPU_SHARED_RO
-            }
-        } else if data.flags & uapi::ASAHI_BIND_WRITE != 0 {
-            mmu::PROT_GPU_SHARED_WO
-        } else {
-            cls_pr_debug!(
-                Errors,
-                "gem_bind: Must specify read or write (flags: {:#x})\n",
-                data.flags
-            );
-            return Err(EINVAL); // Must specify one of ASAHI_BIND_{READ,WRITE}
-        };
-
-        // Clone it immediately so we aren't holding the XArray lock
-        let vm = file
-            .inner()
-            .vms()
-            .get(data.vm_id.try_into()?)
-            .ok_or(ENOENT)?
-            .borrow()
-            .vm
-            .clone();
-
-        bo.map_at(&vm, start, prot, true)?;
-
-        Ok(0)
+        Ok(())
     }
 
     pub(crate) fn do_gem_unbind_all(
@@ -622,6 +842,203 @@ impl File {
         Ok(0)
     }
 
+    pub(crate) fn do_gem_bind_sparse(
+        _device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_gem_bind,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        if data.flags != 0 || data.handle != 0 || data.offset != 0 {
+            cls_pr_debug!(Errors, "gem_bind_sparse: Invalid arguments\n");
+            return Err(EINVAL);
+        }
+
+        if (data.addr | data.range) as usize & mmu::UAT_PGMSK != 0 {
+            cls_pr_debug!(
+                Errors,
+                "gem_bind_sparse: Addr/range not page aligned: {:#x} {:#x}\n",
+                data.addr,
+                data.range
+            );
+            return Err(EINVAL);
+        }
+
+        Self::check_bind_range(data.addr, data.range, false)?;
+
+        // Clone it immediately so we aren't holding the XArray lock
+        let vm = file
+            .inner()
+            .vms()
+            .get(data.vm_id.try_into()?)
+            .ok_or(ENOENT)?
+            .borrow()
+            .vm
+            .clone();
+
+        if data.op == uapi::drm_asahi_bind_op_ASAHI_BIND_OP_BIND_SPARSE {
+            vm.bind_sparse(data.addr, data.range.try_into()?)?;
+        } else {
+            vm.unbind_sparse(data.addr, data.range.try_into()?)?;
+        }
+
+        Ok(0)
+    }
+
+    /// IOCTL: gem_sync: Perform CPU cache maintenance on a range of a writeback GEM object.
+    pub(crate) fn gem_sync(
+        device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_gem_sync,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        mod_dev_dbg!(
+            device,
+            "[File {}]: IOCTL: gem_sync handle={:#x?} dir={} {:#x?}:{:#x?}\n",
+            file.inner().id,
+            data.handle,
+            data.direction,
+            data.offset,
+            data.size
+        );
+
+        if data.extensions != 0 {
+            cls_pr_debug!(Errors, "gem_sync: Unexpected extensions\n");
+            return Err(EINVAL);
+        }
+
+        let op: fn(usize, usize) = match data.direction {
+            uapi::drm_asahi_gem_sync_dir_ASAHI_GEM_SYNC_TO_DEVICE => mem::dcache_clean_range,
+            uapi::drm_asahi_gem_sync_dir_ASAHI_GEM_SYNC_FROM_DEVICE => mem::dcache_inval_range,
+            uapi::drm_asahi_gem_sync_dir_ASAHI_GEM_SYNC_BIDIRECTIONAL => {
+                mem::dcache_clean_inval_range
+            }
+            _ => {
+                cls_pr_debug!(Errors, "gem_sync: Invalid direction {}\n", data.direction);
+                return Err(EINVAL);
+            }
+        };
+
+        let mut bo = gem::lookup_handle(file, data.handle)?;
+
+        let end = data.offset.checked_add(data.size).ok_or(EINVAL)?;
+        if end > bo.size() as u64 {
+            cls_pr_debug!(
+                Errors,
+                "gem_sync: Range {:#x}:{:#x} out of bounds (size {:#x})\n",
+                data.offset,
+                data.size,
+                bo.size()
+            );
+            return Err(EINVAL);
+        }
+
+        // Write-combined objects are never cached on the CPU side.
+        if data.size == 0 || !bo.is_writeback() {
+            return Ok(0);
+        }
+
+        let vmap = bo.vmap()?;
+        op(
+            vmap.as_ptr() as usize + data.offset as usize,
+            data.size as usize,
+        );
+
+        Ok(0)
+    }
+
+    /// IOCTL: get_time: Return a CPU timestamp along with the matching GPU timestamp.
+    pub(crate) fn get_time(
+        _device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_get_time,
+        _file: &DrmFile,
+    ) -> Result<u32> {
+        if data.extensions != 0 || data.flags != 0 {
+            cls_pr_debug!(Errors, "get_time: Unexpected extensions or flags\n");
+            return Err(EINVAL);
+        }
+
+        let mut tp = bindings::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // SAFETY: nanouptime only writes to the timespec it is given.
+        unsafe { bindings::nanouptime(&mut tp) };
+
+        data.gpu_timestamp = mem::read_counter();
+        data.tv_sec = tp.tv_sec;
+        data.tv_nsec = tp.tv_nsec;
+        Ok(0)
+    }
+
+    /// IOCTL: trace_read: Read back this client's submission trace events.
+    pub(crate) fn trace_read(
+        device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_trace_read,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        let json = data.flags & uapi::DRM_ASAHI_TRACE_READ_JSON as u64 != 0;
+
+        if data.extensions != 0
+            || data.flags & !(uapi::DRM_ASAHI_TRACE_READ_JSON as u64) != 0
+            || data.pad != 0
+        {
+            cls_pr_debug!(Errors, "trace_read: Unexpected extensions or flags\n");
+            return Err(EINVAL);
+        }
+
+        let count = data.count.min(MAX_TRACE_EVENTS_PER_READ);
+        let mut events = Vec::with_capacity(count as usize);
+        let trace = device.data().gpu.trace();
+        let (mut seq, dropped) = trace.read(file.inner().id, data.seq, count as usize, &mut events);
+
+        let json_writer = if json {
+            let timer_hz = device.data().gpu.get_cfg().base_clock_hz as u64;
+            let mut writer =
+                trace::JsonWriter::new(data.count.min(MAX_TRACE_JSON_BYTES_PER_READ) as usize)?;
+
+            for event in events.iter() {
+                if writer.write_event(event, timer_hz).is_err() {
+                    if writer.as_bytes().is_empty() {
+                        cls_pr_debug!(Errors, "trace_read: Buffer too small for an event\n");
+                        return Err(ENOSPC);
+                    }
+                    // Continue from this event on the next call
+                    seq = event.seq;
+                    break;
+                }
+            }
+
+            Some(writer)
+        } else {
+            None
+        };
+
+        let (bytes, written) = match json_writer.as_ref() {
+            Some(writer) => (writer.as_bytes(), writer.as_bytes().len()),
+            None => (
+                // SAFETY: `events` holds plain old data.
+                unsafe {
+                    core::slice::from_raw_parts(
+                        events.as_ptr() as *const u8,
+                        core::mem::size_of_val(&events[..]),
+                    )
+                },
+                events.len(),
+            ),
+        };
+
+        // SAFETY: We only write to this userptr once, so there are no TOCTOU issues.
+        let mut writer =
+            unsafe { UserSlicePtr::new(data.events as usize as *mut _, bytes.len()).writer() };
+
+        // SAFETY: `bytes` is valid for reads of its length.
+        unsafe { writer.write_raw(bytes.as_ptr(), bytes.len())? };
+
+        data.count = written as u32;
+        data.seq = seq;
+        data.dropped = dropped;
+
+        Ok(0)
+    }
+
     /// IOCTL: queue_create: Create a new command submission queue of a given type.
     pub(crate) fn queue_create(
         device: &AsahiDevice,
@@ -709,10 +1126,35 @@ impl File {
     ) -> Result<u32> {
         debug::update_debug_flags();
 
-        if data.extensions != 0 {
-            cls_pr_debug!(Errors, "submit: Unexpected extensions\n");
-            return Err(EINVAL);
-        }
+        let mut sync_wait_timeout = queue::MAX_SYNC_WAIT;
+        let mut bo_syncs: Option<Vec<BoSync>> = None;
+
+        ext::walk(data.extensions, |ext| match ext.ext_type() {
+            uapi::ASAHI_SUBMIT_EXT_SYNC_WAIT => {
+                // SAFETY: drm_asahi_submit_sync_wait is plain old data.
+                let sync_wait: uapi::drm_asahi_submit_sync_wait = unsafe { ext.read()? };
+                sync_wait_timeout = Duration::from_nanos(sync_wait.timeout_ns);
+                Ok(())
+            }
+            uapi::ASAHI_SUBMIT_EXT_IMPLICIT_SYNC => {
+                // SAFETY: drm_asahi_submit_implicit_sync is plain old data.
+                let implicit: uapi::drm_asahi_submit_implicit_sync = unsafe { ext.read()? };
+                if implicit.pad1 != 0 {
+                    cls_pr_debug!(Errors, "submit: Nonzero implicit sync pad\n");
+                    return Err(EINVAL);
+                }
+                if bo_syncs.is_some() {
+                    cls_pr_debug!(Errors, "submit: Duplicate implicit sync extension\n");
+                    return Err(EINVAL);
+                }
+                bo_syncs = Some(BoSync::parse_array(file, implicit.bos, implicit.bo_count)?);
+                Ok(())
+            }
+            ext_type => {
+                cls_pr_debug!(Errors, "submit: Unknown extension {}\n", ext_type);
+                Err(EINVAL)
+            }
+        })?;
 
         if data.flags != 0 {
             cls_pr_debug!(Errors, "submit: Unexpected flags {:#x}\n", data.flags);
@@ -787,28 +1229,28 @@ impl File {
             data.queue_id,
             id
         );
-        let mut commands = Vec::with_capacity(data.command_count as usize);
-
-        const STRIDE: usize = core::mem::size_of::<uapi::drm_asahi_command>();
-        let size = STRIDE * data.command_count as usize;
-
-        // SAFETY: We only read this once, so there are no TOCTOU issues.
-        let mut reader =
-            unsafe { UserSlicePtr::new(data.commands as usize as *mut _, size).reader() };
-
-        for _i in 0..data.command_count {
-            let mut cmd: MaybeUninit<uapi::drm_asahi_command> = MaybeUninit::uninit();
-
-            // SAFETY: The size of `sync` is STRIDE
-            unsafe { reader.read_raw(cmd.as_mut_ptr() as *mut u8, STRIDE)? };
-
-            // SAFETY: All bit patterns in the struct are valid
-            commands.push(unsafe { cmd.assume_init() });
+        // SAFETY: drm_asahi_command is plain old data.
+        let commands: Vec<uapi::drm_asahi_command> =
+            unsafe { UserSpace.read_array(data.commands, data.command_count as usize)? };
+
+        let mut timestamp_cmds = Vec::new();
+        for cmd in commands.iter() {
+            if cmd.cmd_type == uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_TIMESTAMP {
+                timestamp_cmds.try_reserve(1)?;
+                timestamp_cmds.push(queue::TimestampCommand::parse(file, cmd)?);
+            }
         }
 
-        let ret = queue
-            .lock()
-            .submit(id, in_syncs, out_syncs, result_buf, commands);
+        let ret = queue.lock().submit(
+            id,
+            in_syncs,
+            out_syncs,
+            result_buf,
+            commands,
+            sync_wait_timeout,
+            bo_syncs.unwrap_or_default(),
+            timestamp_cmds,
+        );
 
         match ret {
             Err(ERESTARTSYS) => Err(ERESTARTSYS),
@@ -835,6 +1277,24 @@ impl File {
 
 impl Drop for File {
     fn drop(&mut self) {
-        mod_pr_debug!("[File {}]: Closing...\n", self.id);
+        // SAFETY: We are being dropped, so the pinned fields will never move again.
+        let this = unsafe { Pin::new_unchecked(&*self) };
+
+        mod_pr_debug!(
+            "[File {}]: Closing ({} queues, {} VMs)...\n",
+            self.id,
+            this.queues().len(),
+            this.vms().len()
+        );
+
+        // Queues hold references into the VMs, so tear them down first.
+        let queues = this.queues().clear();
+        let vms = this.vms().clear();
+        mod_pr_debug!(
+            "[File {}]: Dropped {} queues and {} VMs\n",
+            self.id,
+            queues,
+            vms
+        );
     }
 }
@@ -75,7 +75,7 @@ pub(crate) mod raw {
         pub(crate) meta: job::raw::JobMeta,
         pub(crate) cur_ts: U64,
         pub(crate) timestamp_pointers: job::raw::TimestampPointers<'a>,
-        pub(crate) user_timestamp_pointers: job::raw::TimestampPointers<'a>,
+        pub(crate) user_timestamp_pointers: job::raw::UserTimestampPointers,
         pub(crate) client_sequence: u8,
         pub(crate) pad_2d1: Array<3, u8>,
         pub(crate) unk_2d4: u32,
@@ -252,7 +252,7 @@ pub(crate) mod raw {
         pub(crate) unk_buf_10: U64,
         pub(crate) cur_ts: U64,
         pub(crate) timestamp_pointers: job::raw::TimestampPointers<'a>,
-        pub(crate) user_timestamp_pointers: job::raw::TimestampPointers<'a>,
+        pub(crate) user_timestamp_pointers: job::raw::UserTimestampPointers,
         pub(crate) client_sequence: u8,
         pub(crate) pad_925: Array<3, u8>,
         pub(crate) unk_928: u32,
@@ -115,6 +115,14 @@ pub(crate) mod raw {
         pub(crate) start_addr: Option<GpuPointer<'a, AtomicU64>>,
         pub(crate) end_addr: Option<GpuPointer<'a, AtomicU64>>,
     }
+
+    /// Firmware VAs of user timestamp buffer slots, see `queue::timestamp`. Zero means none.
+    #[derive(Debug, Default)]
+    #[repr(C)]
+    pub(crate) struct UserTimestampPointers {
+        pub(crate) start_addr: U64,
+        pub(crate) end_addr: U64,
+    }
 }
 
 trivial_gpustruct!(JobTimestamps);
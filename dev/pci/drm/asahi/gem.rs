@@ -59,6 +59,11 @@ crate::no_debug!(ObjectRef);
 static GEM_ID: AtomicU64 = AtomicU64::new(0);
 
 impl DriverObject {
+    /// Returns the debug ID of this object.
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
     /// Drop all object mappings for a given file ID.
     ///
     /// Used on file close.
@@ -118,6 +123,27 @@ impl ObjectRef {
         self.gem.size()
     }
 
+    /// Returns whether this object is CPU-cached (created with `ASAHI_GEM_WRITEBACK`).
+    pub(crate) fn is_writeback(&self) -> bool {
+        self.gem.flags & uapi::ASAHI_GEM_WRITEBACK != 0
+    }
+
+    /// Returns the DMA address of the page containing `offset` in this object.
+    pub(crate) fn page_dma_address(&self, offset: usize) -> Result<usize> {
+        let sgt = self.gem.sg_table()?;
+        let mut start = 0;
+
+        for range in sgt.iter() {
+            let len = range.dma_len();
+            if offset - start < len {
+                return Ok((range.dma_address() + offset - start) & !mmu::UAT_PGMSK);
+            }
+            start += len;
+        }
+
+        Err(EINVAL)
+    }
+
     /// Maps an object into a given `Vm` at any free address within a given range.
     ///
     /// Returns Err(EBUSY) if there is already a mapping.
@@ -12,7 +12,7 @@
 //! itself with version dependence.
 
 use core::any::Any;
-use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use core::time::Duration;
 
 use kernel::{
@@ -20,6 +20,7 @@ use kernel::{
     delay::coarse_sleep,
     device::RawDevice,
     error::code::*,
+    irq,
     macros::versions,
     of, platform,
     prelude::*,
@@ -30,15 +31,16 @@ use kernel::{
     },
     time::{clock, Now},
     types::ForeignOwnable,
+    workqueue::{BoxedQueue, DelayedWork, HasDelayedWork, HasWork, Queue, Work, WorkItem},
 };
 
 use crate::alloc::Allocator;
 use crate::debug::*;
-use crate::driver::{AsahiDevRef, AsahiDevice};
+use crate::driver::{AsahiDevRef, AsahiDevice, DeviceData};
 use crate::fw::channels::PipeType;
 use crate::fw::types::{U32, U64};
 use crate::{
-    alloc, buffer, channel, event, fw, gem, hw, initdata, mem, mmu, queue, regs, workqueue,
+    alloc, buffer, channel, event, fw, gem, hw, initdata, mem, mmu, queue, regs, trace, workqueue,
 };
 
 const DEBUG_CLASS: DebugFlags = DebugFlags::Gpu;
@@ -93,6 +95,10 @@ const IOVA_KERN_GPU_TOP: u64 = 0xffffffadffffffff;
 const IOVA_KERN_RTKIT_BASE: u64 = 0xffffffae00000000;
 /// GPU/FW shared structure VA range top.
 const IOVA_KERN_RTKIT_TOP: u64 = 0xffffffae0fffffff;
+/// User timestamp buffer VA range base.
+const IOVA_KERN_TIMESTAMP_BASE: u64 = 0xffffffae10000000;
+/// User timestamp buffer VA range top.
+const IOVA_KERN_TIMESTAMP_TOP: u64 = 0xffffffae1fffffff;
 /// FW MMIO VA range base.
 const IOVA_KERN_MMIO_BASE: u64 = 0xffffffaf00000000;
 /// FW MMIO VA range top.
@@ -111,6 +117,9 @@ const HALT_ENTER_TIMEOUT: Duration = Duration::from_millis(100);
 /// reasonably high.
 const MAX_FW_ALLOC_GARBAGE: usize = 16 * 1024 * 1024;
 
+/// Interval between kernel allocator integrity scans, when enabled.
+const ALLOC_SCAN_INTERVAL: Duration = Duration::from_secs(5);
+
 /// Global allocators used for kernel-half structures.
 pub(crate) struct KernelAllocators {
     pub(crate) private: alloc::DefaultAllocator,
@@ -121,6 +130,39 @@ pub(crate) struct KernelAllocators {
     pub(crate) gpu_ro: alloc::DefaultAllocator,
 }
 
+impl KernelAllocators {
+    /// Returns all kernel allocators along with their names.
+    fn all(&self) -> [(&'static str, &alloc::DefaultAllocator); 5] {
+        [
+            ("private", &self.private),
+            ("shared", &self.shared),
+            ("shared_ro", &self.shared_ro),
+            ("gpu", &self.gpu),
+            ("gpu_ro", &self.gpu_ro),
+        ]
+    }
+
+    /// Log usage statistics for all kernel allocators.
+    fn log_stats(&self, dev: &AsahiDevice) {
+        for (name, alloc) in self.all() {
+            dev_info!(dev, "kalloc/{}: {}\n", name, alloc.stats());
+        }
+    }
+
+    /// Check all kernel allocators for corrupted allocations, logging the first one found.
+    ///
+    /// Returns `true` if corruption was found.
+    fn check_integrity(&self, dev: &AsahiDevice) -> bool {
+        for (name, alloc) in self.all() {
+            if let Some(c) = alloc.check_integrity() {
+                dev_err!(dev, "kalloc/{}: Corruption in {}\n", name, c);
+                return true;
+            }
+        }
+        false
+    }
+}
+
 /// Receive (GPU->driver) ring buffer channels.
 #[versions(AGX)]
 #[pin_data]
@@ -195,6 +237,112 @@ pub(crate) struct SequenceIDs {
     pub(crate) queue: ID,
 }
 
+/// Deferred garbage collection for the GPU manager.
+///
+/// Collecting garbage can invalidate GPU contexts and flush the firmware cache, both of which wait
+/// on the firmware, so it runs from the driver task queue instead of inline in submission paths.
+#[pin_data]
+struct GarbageCollector {
+    dev: AsahiDevRef,
+    #[pin]
+    work: Work<GarbageCollector>,
+}
+
+impl WorkItem for GarbageCollector {
+    fn run(this: Arc<Self>) {
+        this.dev.data().gpu.collect_garbage();
+    }
+}
+
+impl HasWork for GarbageCollector {
+    fn work(&self) -> &Work<Self> {
+        &self.work
+    }
+}
+
+/// Periodic integrity scan of the kernel allocators.
+///
+/// Guard padding is otherwise only checked when an object is freed, which never happens for
+/// long-lived objects. Enabled with `DebugFlags::ScanAllocations`, and only useful together with
+/// `DebugFlags::DebugAllocations` (and `DebugFlags::DetectOverflows` to check guards).
+#[pin_data]
+struct AllocScanner {
+    dev: AsahiDevRef,
+    #[pin]
+    work: DelayedWork<AllocScanner>,
+}
+
+impl AllocScanner {
+    /// Queue the next scan on `queue`.
+    fn schedule(this: Arc<Self>, queue: &Queue) {
+        queue.enqueue_delayed(this, ALLOC_SCAN_INTERVAL);
+    }
+}
+
+impl WorkItem for AllocScanner {
+    fn run(this: Arc<Self>) {
+        let gpu = &this.dev.data().gpu;
+        // Stop after the first report, since the corruption will not go away.
+        if !gpu.check_allocations() {
+            Self::schedule(this.clone(), gpu.task_queue());
+        }
+    }
+}
+
+impl HasDelayedWork for AllocScanner {
+    fn delayed_work(&self) -> &DelayedWork<Self> {
+        &self.work
+    }
+}
+
+/// Driver-owned task queue and the GPU manager work items that run on it.
+///
+/// The system task queue is shared with the rest of the kernel and is not MP-safe, so work that
+/// waits on the firmware must not run there. Dropping this cancels the work items and waits for
+/// any that are running before the task queue itself is destroyed.
+struct DeferredWork {
+    gc: Arc<GarbageCollector>,
+    scanner: Arc<AllocScanner>,
+    queue: BoxedQueue,
+}
+
+impl Drop for DeferredWork {
+    fn drop(&mut self) {
+        Work::cancel_sync(&self.gc);
+        DelayedWork::cancel_sync(&self.scanner);
+    }
+}
+
+/// Handler for the GPU MMU fault interrupt.
+///
+/// The top half only checks whether a fault is latched. Decoding it and recovering the firmware
+/// sleeps, so that happens in the threaded handler.
+pub(crate) struct FaultIrq;
+
+impl irq::Handler for FaultIrq {
+    type Data = Arc<DeviceData>;
+
+    fn handle_irq(data: <Self::Data as ForeignOwnable>::Borrowed<'_>) -> irq::Return {
+        match data.res() {
+            Some(res) if res.fault_pending(data.gpu.get_cfg()) => irq::Return::WakeThread,
+            _ => irq::Return::None,
+        }
+    }
+
+    fn handle_threaded_irq(data: <Self::Data as ForeignOwnable>::Borrowed<'_>) -> irq::Return {
+        // There is nothing left to recover once the firmware has crashed.
+        if !data.gpu.is_crashed() {
+            data.gpu.handle_fault();
+        }
+
+        // Faults stay latched until the GPU is reset, so keep the line masked if it still is.
+        match data.res() {
+            Some(res) if !res.fault_pending(data.gpu.get_cfg()) => irq::Return::Handled,
+            _ => irq::Return::Disable,
+        }
+    }
+}
+
 /// Top-level GPU manager that owns all the global state relevant to the driver instance.
 #[versions(AGX)]
 #[pin_data]
@@ -205,6 +353,7 @@ pub(crate) struct GpuManager {
     pub(crate) initdata: fw::types::GpuObject<fw::initdata::InitData::ver>,
     uat: mmu::Uat,
     crashed: AtomicBool,
+    handled_halt: AtomicU32,
     #[pin]
     alloc: Mutex<KernelAllocators>,
     io_mappings: Vec<mmu::Mapping>,
@@ -226,6 +375,10 @@ pub(crate) struct GpuManager {
     #[allow(clippy::vec_box)]
     #[pin]
     garbage_contexts: Mutex<Vec<Box<fw::types::GpuObject<fw::workqueue::GpuContextData>>>>,
+    deferred: DeferredWork,
+    #[pin]
+    trace: trace::TraceRing,
+    preemption_stats: queue::PreemptionStats,
 }
 
 /// Trait used to abstract the firmware/GPU-dependent variants of the GpuManager.
@@ -257,6 +410,12 @@ pub(crate) trait GpuManager: Send + Sync {
     ) -> Result<Box<dyn queue::Queue>>;
     /// Return a reference to the global `SequenceIDs` instance.
     fn ids(&self) -> &SequenceIDs;
+    /// Return a reference to the submission trace ring.
+    fn trace(&self) -> &trace::TraceRing;
+    /// Return the driver task queue, for deferred work that may wait on the firmware.
+    fn task_queue(&self) -> &Queue;
+    /// Return the device-wide command preemption counters.
+    fn preemption_stats(&self) -> &queue::PreemptionStats;
     /// Kick the firmware (wake it up if asleep).
     ///
     /// This should be useful to reduce latency on work submission, so we can ask the firmware to
@@ -268,7 +427,7 @@ pub(crate) trait GpuManager: Send + Sync {
     fn flush_fw_cache(&self) -> Result;
     /// Handle a GPU work timeout event.
     fn handle_timeout(&self, counter: u32, event_slot: i32);
-    /// Handle a GPU fault event.
+    /// Handle a GPU fault, reported by the fault interrupt or a firmware event.
     fn handle_fault(&self);
     /// Acknowledge a Buffer grow op.
     fn ack_grow(&self, buffer_slot: u32, vm_slot: u32, counter: u32);
@@ -284,6 +443,12 @@ pub(crate) trait GpuManager: Send + Sync {
     fn add_completed_work(&self, work: Vec<Box<dyn workqueue::GenSubmittedWork>>);
     /// Register an unused context as garbage
     fn free_context(&self, data: Box<fw::types::GpuObject<fw::workqueue::GpuContextData>>);
+    /// Free completed work, invalidate idle contexts and collect firmware allocator garbage.
+    ///
+    /// This waits on the firmware, so it is normally run from the garbage collection work item.
+    fn collect_garbage(&self);
+    /// Check the kernel allocators for corrupted objects. Returns `true` if any were found.
+    fn check_allocations(&self) -> bool;
     /// Check whether the GPU is crashed
     fn is_crashed(&self) -> bool;
 }
@@ -684,6 +849,22 @@ impl GpuManager::ver {
             device_control: channel::DeviceControlChannel::ver::new(dev, alloc_ref)?,
         }))?;
 
+        let gc = Arc::pin_init(pin_init!(GarbageCollector {
+            dev: dev.into(),
+            work <- Work::new(),
+        }))?;
+
+        let scanner = Arc::pin_init(pin_init!(AllocScanner {
+            dev: dev.into(),
+            work <- DelayedWork::new(),
+        }))?;
+
+        let deferred = DeferredWork {
+            gc,
+            scanner,
+            queue: BoxedQueue::try_new(c_str!("asahi_gpu"), 1)?,
+        };
+
         let x = UniqueArc::pin_init(try_pin_init!(GpuManager::ver {
             dev: dev.into(),
             cfg,
@@ -694,6 +875,7 @@ impl GpuManager::ver {
             next_mmio_iova: IOVA_KERN_MMIO_BASE,
             rtkit <- Mutex::new_named(None, c_str!("rtkit")),
             crashed: AtomicBool::new(false),
+            handled_halt: AtomicU32::new(0),
             event_manager,
             alloc <- Mutex::new_named(alloc, c_str!("alloc")),
             fwctl_channel <- Mutex::new_named(fwctl_channel, c_str!("fwctl_channel")),
@@ -704,6 +886,9 @@ impl GpuManager::ver {
             ids: Default::default(),
             garbage_work <- Mutex::new_named(Vec::new(), c_str!("garbage_work")),
             garbage_contexts <- Mutex::new_named(Vec::new(), c_str!("garbage_contexts")),
+            deferred,
+            trace <- trace::TraceRing::new(),
+            preemption_stats: Default::default(),
         }))?;
 
         Ok(x)
@@ -885,9 +1070,24 @@ impl GpuManager::ver {
 
     /// Mark work associated with currently in-progress event slots as failed, after a fault or
     /// timeout.
-    fn mark_pending_events(&self, culprit_slot: Option<u32>, error: workqueue::WorkError) {
+    ///
+    /// For faults, only work running in the faulting VM is failed. Other pending work is left
+    /// alone, and the queues it belongs to are returned so they can be resubmitted once the
+    /// firmware has recovered. Contexts that fault too often are banned.
+    fn mark_pending_events(
+        &self,
+        culprit_slot: Option<u32>,
+        error: workqueue::WorkError,
+    ) -> Vec<Arc<dyn workqueue::WorkQueue + Send + Sync>> {
         dev_err!(self.dev, "  Pending events:\n");
 
+        let fault_vm_slot = match error {
+            workqueue::WorkError::Fault(info) => Some(info.vm_slot),
+            _ => None,
+        };
+        let mut innocent: Vec<Arc<dyn workqueue::WorkQueue + Send + Sync>> = Vec::new();
+        let mut culprits: Vec<Arc<workqueue::GpuContext>> = Vec::new();
+
         self.initdata.globals.with(|raw, _inner| {
             for (index, i) in raw.pending_stamps.iter().enumerate() {
                 let info = i.info.load(Ordering::Relaxed);
@@ -910,6 +1110,21 @@ impl GpuManager::ver {
                         flags,
                         wait_value
                     );
+
+                    if let Some((owner, guilty)) = fault_vm_slot.and_then(|vm_slot| {
+                        self.event_manager.fault_owner(slot, wait_value, vm_slot)
+                    }) {
+                        if !guilty {
+                            dev_err!(self.dev, "      Not in faulting VM, resubmitting\n");
+                            innocent.push(owner);
+                            continue;
+                        }
+                        let ctx = owner.gpu_context();
+                        if !culprits.iter().any(|c| Arc::ptr_eq(c, &ctx)) {
+                            culprits.push(ctx);
+                        }
+                    }
+
                     let error = if culprit_slot.is_some() && culprit_slot != Some(slot) {
                         workqueue::WorkError::Killed
                     } else {
@@ -921,6 +1136,14 @@ impl GpuManager::ver {
                 }
             }
         });
+
+        for ctx in culprits {
+            if ctx.add_fault() {
+                dev_err!(self.dev, "  Context faulted too many times, banning it\n");
+            }
+        }
+
+        innocent
     }
 
     /// Fetch the GPU MMU fault information from the hardware registers.
@@ -942,6 +1165,34 @@ impl GpuManager::ver {
         info
     }
 
+    /// Wait for the firmware to halt, and claim that halt for recovery.
+    ///
+    /// A GPU fault is reported both by the fault interrupt and by a firmware event, and may also
+    /// surface as a timeout. All of these refer to the same firmware halt, so the halt count is
+    /// latched here and only the first path to see a given halt gets `true`. If the firmware does
+    /// not halt in time, the event is still claimed so that the in-flight jobs get failed.
+    fn claim_halt(&self) -> bool {
+        let (halted, halt_count) = self.initdata.fw_status.with(|raw, _inner| {
+            let start = clock::KernelTime::now();
+            while raw.flags.halted.load(Ordering::Relaxed) == 0
+                && start.elapsed() < HALT_ENTER_TIMEOUT
+            {
+                mem::sync();
+            }
+            (
+                raw.flags.halted.load(Ordering::Relaxed) != 0,
+                raw.flags.halt_count.load(Ordering::Relaxed),
+            )
+        });
+
+        if !halted {
+            dev_err!(self.dev, "Timed out waiting for the firmware to halt\n");
+            return true;
+        }
+
+        self.handled_halt.swap(halt_count, Ordering::AcqRel) != halt_count
+    }
+
     /// Resume the GPU firmware after it halts (due to a timeout, fault, or request).
     fn recover(&self) {
         self.initdata.fw_status.with(|raw, _inner| {
@@ -981,6 +1232,68 @@ impl GpuManager::ver {
         self.dyncfg.id.core_masks_packed.as_slice()
     }
 
+    /// Map the page of a user object holding a timestamp into the firmware VM, so that the
+    /// firmware can write the timestamp. Only that page is mapped, and only for as long as the
+    /// returned `Mapping` lives.
+    pub(crate) fn map_timestamp_page(
+        &self,
+        bo: &gem::ObjectRef,
+        offset: usize,
+    ) -> Result<mmu::Mapping> {
+        let phys = bo.page_dma_address(offset)?;
+
+        self.uat.kernel_vm().map_page_in_range(
+            phys,
+            IOVA_KERN_TIMESTAMP_BASE,
+            IOVA_KERN_TIMESTAMP_TOP,
+            mmu::PROT_FW_SHARED_RW,
+        )
+    }
+
+    /// Resubmit the work queues returned by `mark_pending_events()` once the firmware has been
+    /// recovered, since their pending work was not at fault and was not failed.
+    fn resubmit_innocent(&self, innocent: Vec<Arc<dyn workqueue::WorkQueue + Send + Sync>>) {
+        for owner in innocent {
+            let wq = match owner.as_any().downcast_ref::<workqueue::WorkQueue::ver>() {
+                Some(wq) => wq,
+                None => {
+                    dev_crit!(self.dev, "WorkQueue mismatched with GpuManager!\n");
+                    continue;
+                }
+            };
+            if let Err(e) =
+                wq.resubmit(|pipe_type, priority, msg| self.run_message(pipe_type, priority, msg))
+            {
+                dev_err!(self.dev, "Failed to resubmit work queue: {:?}\n", e);
+            }
+        }
+    }
+
+    /// Send a work queue run message on the matching submission pipe and kick it, used to
+    /// resubmit work after a fault.
+    fn run_message(
+        &self,
+        pipe_type: PipeType,
+        priority: u32,
+        msg: &fw::channels::RunWorkQueueMsg::ver,
+    ) -> Result {
+        let pipes = match pipe_type {
+            PipeType::Vertex => &self.pipes.vtx,
+            PipeType::Fragment => &self.pipes.frag,
+            PipeType::Compute => &self.pipes.comp,
+        };
+
+        let index: usize = priority as usize;
+        pipes.get(index).ok_or(EIO)?.lock().send(msg);
+
+        let mut guard = self.rtkit.lock();
+        let rtk = guard.as_mut().unwrap();
+        rtk.send_message(
+            EP_DOORBELL,
+            MSG_TX_DOORBELL | pipe_type as u64 | ((index as u64) << 2),
+        )
+    }
+
     /// Kick a submission pipe for a submitted job to tell the firmware to start processing it.
     pub(crate) fn run_job(&self, job: workqueue::JobSubmission::ver<'_>) -> Result {
         mod_dev_dbg!(self.dev, "GPU: run_job\n");
@@ -1091,6 +1404,11 @@ impl GpuManager::ver {
 
         Ok(())
     }
+
+    /// Queue the garbage collection work item, if it is not already queued.
+    fn queue_gc(&self) {
+        self.deferred.queue.enqueue(self.deferred.gc.clone());
+    }
 }
 
 #[versions(AGX)]
@@ -1120,6 +1438,11 @@ impl GpuManager for GpuManager::ver {
         core::mem::drop(guard);
 
         self.kick_firmware()?;
+
+        if debug_enabled(DebugFlags::ScanAllocations) {
+            AllocScanner::schedule(self.deferred.scanner.clone(), &self.deferred.queue);
+        }
+
         Ok(())
     }
 
@@ -1137,13 +1460,24 @@ impl GpuManager for GpuManager::ver {
     }
 
     fn alloc(&self) -> Guard<'_, KernelAllocators, MutexBackend> {
-        /*
-         * TODO: This should be done in a workqueue or something.
-         * Clean up completed jobs
-         */
-        self.garbage_work.lock().clear();
+        let guard = self.alloc.lock();
+
+        if guard.private.garbage().1 > MAX_FW_ALLOC_GARBAGE
+            || guard.gpu_ro.garbage().1 > MAX_FW_ALLOC_GARBAGE
+        {
+            self.queue_gc();
+        }
 
-        /* Clean up idle contexts */
+        guard
+    }
+
+    fn collect_garbage(&self) {
+        // Clean up completed jobs
+        let mut garbage_work = Vec::new();
+        core::mem::swap(&mut *self.garbage_work.lock(), &mut garbage_work);
+        core::mem::drop(garbage_work);
+
+        // Clean up idle contexts
         let mut garbage_ctx = Vec::new();
         core::mem::swap(&mut *self.garbage_contexts.lock(), &mut garbage_ctx);
 
@@ -1187,7 +1521,13 @@ impl GpuManager for GpuManager::ver {
             }
         }
 
-        guard
+        if debug_enabled(DebugFlags::MemStats) {
+            guard.log_stats(&self.dev);
+        }
+    }
+
+    fn check_allocations(&self) -> bool {
+        self.alloc.lock().check_integrity(&self.dev)
     }
 
     fn new_vm(&self, file_id: u64) -> Result<mmu::Vm> {
@@ -1281,7 +1621,28 @@ impl GpuManager for GpuManager::ver {
         &self.ids
     }
 
+    fn trace(&self) -> &trace::TraceRing {
+        &self.trace
+    }
+
+    fn task_queue(&self) -> &Queue {
+        &self.deferred.queue
+    }
+
+    fn preemption_stats(&self) -> &queue::PreemptionStats {
+        &self.preemption_stats
+    }
+
     fn handle_timeout(&self, counter: u32, event_slot: i32) {
+        if !self.claim_halt() {
+            mod_dev_dbg!(
+                self.dev,
+                "GPU timeout already handled (slot {})\n",
+                event_slot
+            );
+            return;
+        }
+
         dev_err!(self.dev, " (\\________/) \n");
         dev_err!(self.dev, "  |        |  \n");
         dev_err!(self.dev, "'.| \\  , / |.'\n");
@@ -1294,14 +1655,23 @@ impl GpuManager for GpuManager::ver {
 
         // If we have fault info, consider it a fault.
         let error = match self.get_fault_info() {
-            Some(info) => workqueue::WorkError::Fault(info),
+            Some(mut info) => {
+                self.uat.report_fault(&mut info);
+                workqueue::WorkError::Fault(info)
+            }
             None => workqueue::WorkError::Timeout,
         };
-        self.mark_pending_events(event_slot.try_into().ok(), error);
+        let innocent = self.mark_pending_events(event_slot.try_into().ok(), error);
         self.recover();
+        self.resubmit_innocent(innocent);
     }
 
     fn handle_fault(&self) {
+        if !self.claim_halt() {
+            mod_dev_dbg!(self.dev, "GPU fault already handled\n");
+            return;
+        }
+
         dev_err!(self.dev, " (\\________/) \n");
         dev_err!(self.dev, "  |        |  \n");
         dev_err!(self.dev, "'.| \\  , / |.'\n");
@@ -1310,11 +1680,15 @@ impl GpuManager for GpuManager::ver {
         dev_err!(self.dev, "  |________|  \n");
         dev_err!(self.dev, "GPU fault nya~!!!!!\n");
         let error = match self.get_fault_info() {
-            Some(info) => workqueue::WorkError::Fault(info),
+            Some(mut info) => {
+                self.uat.report_fault(&mut info);
+                workqueue::WorkError::Fault(info)
+            }
             None => workqueue::WorkError::Unknown,
         };
-        self.mark_pending_events(None, error);
+        let innocent = self.mark_pending_events(None, error);
         self.recover();
+        self.resubmit_innocent(innocent);
     }
 
     fn ack_grow(&self, buffer_slot: u32, vm_slot: u32, counter: u32) {
@@ -1394,12 +1768,18 @@ impl GpuManager for GpuManager::ver {
         for i in work {
             garbage.push(i);
         }
+        core::mem::drop(garbage);
+
+        self.queue_gc();
     }
 
     fn free_context(&self, ctx: Box<fw::types::GpuObject<fw::workqueue::GpuContextData>>) {
         let mut garbage = self.garbage_contexts.lock();
 
         garbage.push(ctx);
+        core::mem::drop(garbage);
+
+        self.queue_gc();
     }
 
     fn is_crashed(&self) -> bool {
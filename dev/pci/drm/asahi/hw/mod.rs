@@ -487,8 +487,11 @@ impl PwrConfig {
         let opps = node.parse_phandle(name, 0).ok_or(EIO)?;
 
         for opp in opps.child() {
-            let freq_hz: u64 = opp.get_property(c_str!("opp-hz"))?;
-            let mut volt_uv: Vec<u32> = opp.get_property(c_str!("opp-microvolt"))?;
+            let freq_hz = *opp
+                .read_u64_array(c_str!("opp-hz"))?
+                .first()
+                .ok_or(EINVAL)?;
+            let mut volt_uv = opp.read_u32_array(c_str!("opp-microvolt"))?;
             let pwr_uw: u32 = if is_main {
                 opp.get_property(c_str!("opp-microwatt"))?
             } else {
@@ -504,7 +507,8 @@ impl PwrConfig {
             if volt_uv.len() != voltage_count as usize {
                 dev_err!(
                     dev,
-                    "Invalid opp-microvolt length (expected {}, got {})\n",
+                    "{}: Invalid opp-microvolt length (expected {}, got {})\n",
+                    &*opp.full_name()?,
                     voltage_count,
                     volt_uv.len()
                 );
@@ -553,20 +557,19 @@ impl PwrConfig {
             }};
         }
 
-        let pz_data = prop!("apple,power-zones", Vec::new());
+        let pz_data: Vec<u32> = prop!("apple,power-zones", Vec::new());
 
         if pz_data.len() > 3 * MAX_POWERZONES || pz_data.len() % 3 != 0 {
             dev_err!(dev, "Invalid apple,power-zones value\n");
             return Err(EINVAL);
         }
 
-        let pz_count = pz_data.len() / 3;
         let mut power_zones = Vec::new();
-        for i in (0..pz_count).step_by(3) {
+        for pz in pz_data.chunks_exact(3) {
             power_zones.push(PowerZone {
-                target: pz_data[i],
-                target_offset: pz_data[i + 1],
-                filter_tc: pz_data[i + 2],
+                target: pz[0],
+                target_offset: pz[1],
+                filter_tc: pz[2],
             });
         }
 
@@ -13,6 +13,7 @@ pub(crate) mod channel;
 pub(crate) mod debug;
 pub(crate) mod driver;
 pub(crate) mod event;
+pub(crate) mod ext;
 pub(crate) mod file;
 pub(crate) mod float;
 pub(crate) mod fw;
@@ -24,9 +25,12 @@ pub(crate) mod mem;
 pub(crate) mod microseq;
 pub(crate) mod mmu;
 pub(crate) mod object;
+pub(crate) mod pgtable;
 pub(crate) mod queue;
 pub(crate) mod regs;
 pub(crate) mod slotalloc;
+pub(crate) mod trace;
+pub(crate) mod umem;
 pub(crate) mod util;
 pub(crate) mod workqueue;
 
@@ -35,7 +39,7 @@ use core::time::Duration;
 use kernel::{
     delay::coarse_sleep,
     device::{Device, RawDevice},
-    drm, of, platform,
+    drm, irq, of, platform,
     prelude::*,
     str::CStr,
     sync::Arc,
@@ -48,6 +52,9 @@ const __LOG_PREFIX: &'static str = "asahidrm";
 static mut INFO: Option<&'static HwConfig> = None;
 static mut PMAP: bindings::pmap_t = core::ptr::null_mut();
 static mut DMAT: Option<bindings::bus_dma_tag_t> = None;
+/// The GPU fault interrupt. This lives outside the device data, since the handler holds a
+/// reference to the device data and would otherwise keep it alive forever.
+static mut FAULT_IRQ: Option<irq::Registration<gpu::FaultIrq>> = None;
 
 id_table! { ASAHI_ID_TABLE, &'static hw::HwConfig, [
     (c_str!("apple,agx-t8103"), Some(&hw::t8103::HWCONFIG)),
@@ -70,7 +77,7 @@ pub extern "C" fn asahidrm_match(
     let handle = faa.fa_node;
     if let Some(node) = of::Node::from_handle(handle) {
         unsafe {
-            INFO = compatible_info!(node, ASAHI_ID_TABLE);
+            INFO = node.match_compatible(&ASAHI_ID_TABLE).copied();
         }
         compatible!(node, ASAHI_ID_TABLE)
     } else {
@@ -114,7 +121,7 @@ pub extern "C" fn asahidrm_attachhook(_self: *mut bindings::device) {
     let sc = _self as *mut bindings::asahidrm_softc;
     if let Some(node) = of::Node::from_handle(unsafe { (*sc).sc_node }) {
         unsafe {
-            INFO = compatible_info!(node, ASAHI_ID_TABLE);
+            INFO = node.match_compatible(&ASAHI_ID_TABLE).copied();
         }
     }
     unsafe {
@@ -188,6 +195,21 @@ pub extern "C" fn asahidrm_attachhook(_self: *mut bindings::device) {
         0
     )
     .unwrap();
+
+    // The fault interrupt is optional; without it faults are still reported by the firmware.
+    if node.find_property(c_str!("interrupts")).is_some() {
+        match irq::Registration::try_new(&node, 0, c_str!("asahidrm"), data.clone()) {
+            Ok(reg) => unsafe { FAULT_IRQ = Some(reg) },
+            Err(e) => dev_err!(data.dev, "Failed to establish fault interrupt: {:?}\n", e),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn asahidrm_detach(_self: *mut bindings::device, _flags: i32) -> i32 {
+    // Disestablish the fault interrupt and drop its reference to the device data.
+    drop(unsafe { FAULT_IRQ.take() });
+    0
 }
 
 #[no_mangle]
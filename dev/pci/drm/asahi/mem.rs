@@ -136,3 +136,82 @@ pub(crate) fn sync() {
         asm!("dsb sy");
     }
 }
+
+/// Returns the smallest CPU data cache line size in bytes, from `CTR_EL0.DminLine`.
+#[inline(always)]
+fn dcache_line_size() -> usize {
+    let ctr: u64;
+    unsafe {
+        asm!("mrs {x}, ctr_el0", x = out(reg) ctr);
+    }
+    4 << ((ctr >> 16) & 0xf)
+}
+
+/// Clean a range of the CPU data cache to the point of coherency, so the GPU sees CPU writes.
+pub(crate) fn dcache_clean_range(va: usize, len: usize) {
+    let line = dcache_line_size();
+    let end = va + len;
+    let mut p = va & !(line - 1);
+
+    while p < end {
+        unsafe {
+            asm!("dc cvac, {x}", x = in(reg) p);
+        }
+        p += line;
+    }
+    sync();
+}
+
+/// Clean and invalidate a range of the CPU data cache.
+pub(crate) fn dcache_clean_inval_range(va: usize, len: usize) {
+    let line = dcache_line_size();
+    let end = va + len;
+    let mut p = va & !(line - 1);
+
+    while p < end {
+        unsafe {
+            asm!("dc civac, {x}", x = in(reg) p);
+        }
+        p += line;
+    }
+    sync();
+}
+
+/// Invalidate a range of the CPU data cache, so the CPU sees GPU writes.
+///
+/// Partial cache lines at either end are cleaned first, so that we don't throw away CPU writes
+/// to the bytes around the range.
+pub(crate) fn dcache_inval_range(va: usize, len: usize) {
+    let line = dcache_line_size();
+    let end = va + len;
+    let mut p = va & !(line - 1);
+
+    while p < end {
+        if p < va || p + line > end {
+            unsafe {
+                asm!("dc civac, {x}", x = in(reg) p);
+            }
+        } else {
+            unsafe {
+                asm!("dc ivac, {x}", x = in(reg) p);
+            }
+        }
+        p += line;
+    }
+    sync();
+}
+
+/// Read the ARM system counter, which GPU timestamps are also taken from.
+///
+/// The firmware stamps work with the SoC system counter (ticking at `HwConfig::base_clock_hz`),
+/// as the Linux driver also assumes for its GET_TIME ioctl. This reads the physical count, since
+/// the virtual count is offset by CNTVOFF_EL2, which is only zeroed when the kernel is entered at
+/// EL2.
+#[inline(always)]
+pub(crate) fn read_counter() -> u64 {
+    let val: u64;
+    unsafe {
+        asm!("isb", "mrs {x}, cntpct_el0", x = out(reg) val);
+    }
+    val
+}
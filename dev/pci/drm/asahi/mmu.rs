@@ -8,7 +8,7 @@
 //! to currently active GPU VM contexts, as well as the individual `Vm` operations to map and
 //! unmap buffer objects into a single user or kernel address space.
 //!
-//! The actual page table management is delegated to the common kernel `io_pgtable` code.
+//! The page tables themselves are managed by the native UAT page table code in [`crate::pgtable`].
 
 use core::fmt::Debug;
 use core::mem::size_of;
@@ -20,22 +20,21 @@ use kernel::{
     bindings, c_str, delay, device,
     drm::mm,
     error::{to_result, Result},
-    io_pgtable,
-    io_pgtable::{prot, AppleUAT, IoPageTable},
     prelude::*,
     static_lock_class,
+    str::CString,
     sync::{
-        lock::{mutex::MutexBackend, Guard},
-        Arc, Mutex,
+        lock::{mutex::MutexBackend, rwlock::ReadGuard, rwlock::RwLockBackend, Guard},
+        Arc, Mutex, RwLock,
     },
     time::{clock, Now},
-    types::ForeignOwnable,
     of,
 };
 
 use crate::debug::*;
 use crate::no_debug;
-use crate::{driver, fw, gem, hw, mem, slotalloc};
+use crate::pgtable::{prot, TableAlloc, TablePage, UatPageTable};
+use crate::{driver, fw, gem, hw, mem, regs, slotalloc};
 
 const DEBUG_CLASS: DebugFlags = DebugFlags::Mmu;
 
@@ -61,11 +60,21 @@ type Pte = AtomicU64;
 /// Number of PTEs per page.
 const UAT_NPTE: usize = UAT_PGSZ / size_of::<Pte>();
 
+/// Number of bits in a level 2 block offset.
+const UAT_BLKBIT: usize = UAT_PGBIT + UAT_NPTE.trailing_zeros() as usize;
+/// UAT level 2 block size (32M).
+const UAT_BLKSZ: usize = 1 << UAT_BLKBIT;
+/// UAT level 2 block offset mask.
+const UAT_BLKMSK: usize = UAT_BLKSZ - 1;
+
 /// UAT input address space (user)
 pub(crate) const UAT_IAS: usize = 39;
 /// "Fake" kernel UAT input address space (one page level lower)
 pub(crate) const UAT_IAS_KERN: usize = 36;
 
+/// Number of significant bits in a reported fault address
+const FAULT_ADDR_BITS: usize = 40;
+
 /// Lower/user base VA
 const IOVA_USER_BASE: usize = UAT_PGSZ;
 /// Lower/user top VA
@@ -115,6 +124,8 @@ pub(crate) const PROT_GPU_SHARED_RW: u32 = prot::READ | prot::WRITE | prot::CACH
 pub(crate) const PROT_GPU_SHARED_RO: u32 = prot::READ | prot::CACHE | prot::NOEXEC;
 /// GPU shared/coherent WO
 pub(crate) const PROT_GPU_SHARED_WO: u32 = prot::WRITE | prot::CACHE | prot::NOEXEC;
+/// GPU sparse range scratch page RW
+const PROT_SPARSE: u32 = PROT_GPU_SHARED_RW;
 /*
 /// GPU private/noncoherent RW
 pub(crate) const PROT_GPU_PRIV_RW: u32 = prot::READ | prot::WRITE | prot::NOEXEC;
@@ -178,19 +189,64 @@ const SLOTS_SIZE: usize = UAT_NUM_CTX * size_of::<SlotTTBS>();
 // We need at least page 0 (ttb0)
 const PAGETABLES_SIZE: usize = UAT_PGSZ;
 
+/// The process that created a user `Vm`, for fault reporting.
+struct VmOwner {
+    pid: bindings::pid_t,
+    comm: CString,
+}
+
+/// A mapping found near a fault address.
+struct FaultMapping {
+    start: u64,
+    size: u64,
+    prot: u32,
+    bo_id: Option<u64>,
+}
+
+impl FaultMapping {
+    fn new(node: &mm::NodeData<(), MappingInner>) -> FaultMapping {
+        FaultMapping {
+            start: node.start(),
+            size: node.mapped_size as u64,
+            prot: node.prot,
+            bo_id: node.sgt.as_ref().map(|sgt| sgt.owner().id()),
+        }
+    }
+}
+
+impl core::fmt::Display for FaultMapping {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:#x}:{:#x} prot={:#x} ",
+            self.start, self.size, self.prot
+        )?;
+        match self.bo_id {
+            Some(id) => write!(f, "bo={}", id),
+            None => write!(f, "(no bo)"),
+        }
+    }
+}
+
 /// Inner data for a Vm instance. This is reference-counted by the outer Vm object.
 struct VmInner {
     dev: driver::AsahiDevRef,
     is_kernel: bool,
     min_va: usize,
     max_va: usize,
-    page_table: AppleUAT<Uat>,
+    page_table: UatPageTable<DmaTableAlloc>,
     mm: mm::Allocator<(), MappingInner>,
     uat_inner: Arc<UatInner>,
     active_users: usize,
     binding: Option<slotalloc::Guard<SlotInner>>,
     bind_token: Option<slotalloc::SlotToken>,
     id: u64,
+    file_id: u64,
+    owner: Option<VmOwner>,
+    /// Sparse ranges, backed by `scratch` where no object is bound.
+    sparse: Vec<core::ops::Range<usize>>,
+    // Must be dropped after `page_table`, which may still point to it.
+    scratch: Option<ScratchPage>,
 }
 
 impl VmInner {
@@ -216,10 +272,66 @@ impl VmInner {
 
     /// Returns the translation table base for this Vm
     fn ttb(&self) -> u64 {
-        self.page_table.cfg().ttbr
+        self.page_table.ttbr()
     }
 
-    /// Map an IOVA to the shifted address the underlying io_pgtable uses.
+    /// Log the owner of this Vm and the mappings at or around a faulting address.
+    fn report_fault(&self, addr: u64) {
+        match self.owner.as_ref() {
+            Some(owner) => dev_err!(
+                self.dev,
+                "  Faulting VM: {} (file {}, pid {} {})\n",
+                self.id,
+                self.file_id,
+                owner.pid,
+                &*owner.comm
+            ),
+            None => dev_err!(
+                self.dev,
+                "  Faulting VM: {} (kernel={})\n",
+                self.id,
+                self.is_kernel
+            ),
+        }
+
+        let mut below = None;
+        let mut hit = None;
+        let mut above = None;
+        self.mm.for_each_node(|node| {
+            let start = node.start();
+            if addr < start {
+                above = Some(FaultMapping::new(node));
+                false
+            } else if addr < start + node.size() {
+                hit = Some((
+                    FaultMapping::new(node),
+                    addr >= start + node.mapped_size as u64,
+                ));
+                false
+            } else {
+                below = Some(FaultMapping::new(node));
+                true
+            }
+        });
+
+        match hit {
+            Some((mapping, false)) => dev_err!(self.dev, "  Faulting mapping: {}\n", mapping),
+            Some((mapping, true)) => {
+                dev_err!(self.dev, "  Fault in guard page of mapping: {}\n", mapping)
+            }
+            None => {
+                dev_err!(self.dev, "  Fault address is not mapped\n");
+                if let Some(mapping) = below {
+                    dev_err!(self.dev, "  Nearest mapping below: {}\n", mapping);
+                }
+                if let Some(mapping) = above {
+                    dev_err!(self.dev, "  Nearest mapping above: {}\n", mapping);
+                }
+            }
+        }
+    }
+
+    /// Map an IOVA to the shifted address the underlying page table uses.
     fn map_iova(&self, iova: usize, size: usize) -> Result<usize> {
         if iova < self.min_va || (iova + size - 1) > self.max_va {
             Err(EINVAL)
@@ -269,6 +381,53 @@ impl VmInner {
         Ok(pgcount * pgsize)
     }
 
+    /// Map a contiguous range, using level 2 blocks wherever the IOVA and physical address are
+    /// both block-aligned and pages elsewhere.
+    fn map_range(
+        &mut self,
+        mut iova: usize,
+        mut paddr: usize,
+        mut len: usize,
+        prot: u32,
+    ) -> Result {
+        while len > 0 {
+            let (pgsize, count) = if (iova | paddr) & UAT_BLKMSK == 0 && len >= UAT_BLKSZ {
+                (UAT_BLKSZ, len >> UAT_BLKBIT)
+            } else {
+                // Map pages up to the next block boundary, where we may be able to use blocks.
+                let to_boundary = UAT_BLKSZ - (iova & UAT_BLKMSK);
+                (UAT_PGSZ, len.min(to_boundary) >> UAT_PGBIT)
+            };
+
+            let mapped = self.map_pages(iova, paddr, pgsize, count, prot)?;
+            iova += mapped;
+            paddr += mapped;
+            len -= mapped;
+        }
+        Ok(())
+    }
+
+    /// Unmap a contiguous range that may have been mapped with [`VmInner::map_range`].
+    ///
+    /// Whole aligned blocks are unmapped at level 2, which frees any page table below them. Pages
+    /// inside a block are unmapped at level 3, which splits the block if there is one, so this is
+    /// correct for any mix of block and page mappings as long as the range covers entire mappings.
+    fn unmap_range(&mut self, mut iova: usize, mut len: usize) -> Result {
+        while len > 0 {
+            let (pgsize, count) = if iova & UAT_BLKMSK == 0 && len >= UAT_BLKSZ {
+                (UAT_BLKSZ, len >> UAT_BLKBIT)
+            } else {
+                let to_boundary = UAT_BLKSZ - (iova & UAT_BLKMSK);
+                (UAT_PGSZ, len.min(to_boundary) >> UAT_PGBIT)
+            };
+
+            let unmapped = self.unmap_pages(iova, pgsize, count)?;
+            iova += unmapped;
+            len -= unmapped;
+        }
+        Ok(())
+    }
+
     /// Map an `mm::Node` representing an mapping in VA space.
     fn map_node(&mut self, node: &mm::Node<(), MappingInner>, prot: u32) -> Result {
         let mut iova = node.start() as usize;
@@ -297,12 +456,80 @@ impl VmInner {
                 iova
             );
 
-            self.map_pages(iova, addr, UAT_PGSZ, len >> UAT_PGBIT, prot)?;
+            if node.sparse {
+                self.replace_range(iova, addr, len, prot)?;
+            } else {
+                self.map_range(iova, addr, len, prot)?;
+            }
 
             iova += len;
         }
         Ok(())
     }
+
+    /// Unmap an `mm::Node` mapped with [`VmInner::map_node`]. Mappings inside a sparse range go
+    /// back to the scratch page instead.
+    fn unmap_node(&mut self, node: &mm::Node<(), MappingInner>) -> Result {
+        let iova = node.start() as usize;
+        if node.sparse {
+            self.fill_scratch(iova, node.mapped_size)
+        } else {
+            self.unmap_range(iova, node.mapped_size)
+        }
+    }
+
+    /// Replace the existing mappings of a contiguous range in place.
+    fn replace_range(&mut self, iova: usize, paddr: usize, len: usize, prot: u32) -> Result {
+        let mapped_iova = self.map_iova(iova, len)?;
+        self.page_table
+            .replace_pages(mapped_iova, paddr, len >> UAT_PGBIT, prot)?;
+        Ok(())
+    }
+
+    /// Point an existing range back at the scratch page.
+    fn fill_scratch(&mut self, iova: usize, len: usize) -> Result {
+        let paddr = self.scratch.as_ref().ok_or(EINVAL)?.pa();
+        let mapped_iova = self.map_iova(iova, len)?;
+        self.page_table
+            .replace_repeated(mapped_iova, paddr, len >> UAT_PGBIT, PROT_SPARSE)?;
+        Ok(())
+    }
+
+    /// Returns whether a range lies within a sparse range. Ranges that partially overlap a sparse
+    /// range are rejected.
+    fn check_sparse(&self, iova: usize, len: usize) -> Result<bool> {
+        let end = iova + len;
+        for range in self.sparse.iter() {
+            if range.start <= iova && end <= range.end {
+                return Ok(true);
+            } else if iova < range.end && range.start < end {
+                return Err(EINVAL);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns whether any mapping overlaps a range.
+    fn is_mapped(&self, iova: usize, len: usize) -> bool {
+        let (iova, end) = (iova as u64, (iova + len) as u64);
+        let mut mapped = false;
+        self.mm.for_each_node(|node| {
+            if node.start() >= end {
+                return false;
+            }
+            mapped = node.start() + node.size() > iova;
+            !mapped
+        });
+        mapped
+    }
+
+    /// Invalidate a range from the TLB, if this Vm has ever been bound.
+    fn flush_tlb_range(&self, iova: usize, len: usize) {
+        if let Some(asid) = self.slot() {
+            mem::tlbi_range(asid as u8, iova, len);
+            mem::sync();
+        }
+    }
 }
 
 /// Shared reference to a virtual memory address space ([`Vm`]).
@@ -342,6 +569,7 @@ impl Drop for VmBind {
         inner.active_users -= 1;
         mod_pr_debug!("MMU: slot {} active users {}\n", self.1, inner.active_users);
         if inner.active_users == 0 {
+            inner.uat_inner.lock_mut().slot_vms[self.1 as usize] = None;
             inner.binding = None;
         }
     }
@@ -364,6 +592,8 @@ pub(crate) struct MappingInner {
     prot: u32,
     mapped_size: usize,
     sgt: Option<gem::SGTable>,
+    /// Whether this mapping replaces part of a sparse range.
+    sparse: bool,
 }
 
 /// An object mapping into a [`Vm`], which reserves the address range from use by other mappings.
@@ -394,10 +624,7 @@ impl Mapping {
         // The IOMMU API does not allow us to remap things in-place...
         // just do an unmap and map again for now.
         // Do not try to unmap guard page (-1)
-        if owner
-            .unmap_pages(self.iova(), UAT_PGSZ, self.size() >> UAT_PGBIT)
-            .is_err()
-        {
+        if owner.unmap_node(&self.0).is_err() {
             dev_err!(
                 owner.dev,
                 "MMU: unmap for remap {:#x}:{:#x} failed\n",
@@ -431,10 +658,10 @@ impl Mapping {
             let ttb = owner.ttb() | TTBR_VALID | (slot as u64) << TTBR_ASID_SHIFT;
 
             let uat_inner = self.0.uat_inner.lock();
-            uat_inner.handoff().lock();
-            let cur_slot = uat_inner.handoff().current_slot();
+            let handoff = self.0.uat_inner.lock_handoff(&uat_inner);
+            let cur_slot = handoff.current_slot();
             let ttb_cur = uat_inner.ttbs()[slot as usize].ttb0.load(Ordering::Relaxed);
-            uat_inner.handoff().unlock();
+            core::mem::drop(handoff);
             if cur_slot == Some(slot) && ttb_cur == ttb {
                 slot
             } else {
@@ -543,10 +770,7 @@ impl Drop for Mapping {
             self.size()
         );
 
-        if owner
-            .unmap_pages(self.iova(), UAT_PGSZ, self.size() >> UAT_PGBIT)
-            .is_err()
-        {
+        if owner.unmap_node(&self.0).is_err() {
             dev_err!(
                 owner.dev,
                 "MMU: unmap {:#x}:{:#x} failed\n",
@@ -575,6 +799,8 @@ struct UatShared {
     map_kernel_to_user: bool,
     handoff_rgn: UatRegion,
     ttbs_rgn: UatRegion,
+    /// User Vms currently bound to each context slot, for fault reporting.
+    slot_vms: [Option<Arc<Mutex<VmInner>>>; UAT_NUM_CTX],
 }
 
 impl UatShared {
@@ -598,15 +824,33 @@ unsafe impl Send for UatShared {}
 #[pin_data]
 struct UatInner {
     #[pin]
-    shared: Mutex<UatShared>,
+    shared: RwLock<UatShared>,
+    #[pin]
+    handoff_ap: Mutex<()>,
     #[pin]
     handoff_flush: [Mutex<HandoffFlush>; UAT_NUM_CTX + 1],
 }
 
 impl UatInner {
-    /// Take the lock on the shared data and return the guard.
-    fn lock(&self) -> Guard<'_, UatShared, MutexBackend> {
-        self.shared.lock()
+    /// Take a shared lock on the shared data and return the guard.
+    fn lock(&self) -> ReadGuard<'_, UatShared> {
+        self.shared.read()
+    }
+
+    /// Take an exclusive lock on the shared data and return the guard.
+    fn lock_mut(&self) -> Guard<'_, UatShared, RwLockBackend> {
+        self.shared.write()
+    }
+
+    /// Lock the handoff region from firmware access and return the guard.
+    ///
+    /// The handoff lock only arbitrates between the firmware and a single AP-side owner, so
+    /// AP-side users are serialized here. This lets the shared data itself be read concurrently.
+    fn lock_handoff<'a>(&'a self, shared: &'a UatShared) -> HandoffGuard<'a> {
+        let ap = self.handoff_ap.lock();
+        let handoff = shared.handoff();
+        handoff.lock();
+        HandoffGuard { handoff, _ap: ap }
     }
 
     /// Take a lock on a handoff flush slot and return the guard.
@@ -628,6 +872,26 @@ pub(crate) struct Uat {
     kernel_lower_vm: Vm,
 }
 
+/// Guard for the handoff region lock, which unlocks it when dropped.
+struct HandoffGuard<'a> {
+    handoff: &'a Handoff,
+    _ap: Guard<'a, (), MutexBackend>,
+}
+
+impl core::ops::Deref for HandoffGuard<'_> {
+    type Target = Handoff;
+
+    fn deref(&self) -> &Handoff {
+        self.handoff
+    }
+}
+
+impl Drop for HandoffGuard<'_> {
+    fn drop(&mut self) {
+        self.handoff.unlock();
+    }
+}
+
 impl Drop for UatRegion {
     fn drop(&mut self) {
         // SAFETY: the pointer is valid by the type invariant
@@ -741,24 +1005,85 @@ impl HandoffFlush {
     }
 }
 
-// We do not implement FlushOps, since we flush manually in this module after
-// page table operations. Just provide dummy implementations.
-impl io_pgtable::FlushOps for Uat {
-    type Data = ();
+/// Page table memory allocator backed by DMA memory.
+struct DmaTableAlloc {
+    dmat: bindings::bus_dma_tag_t,
+}
+
+// SAFETY: The DMA tag is global and may be used from any thread.
+unsafe impl Send for DmaTableAlloc {}
+
+impl TableAlloc for DmaTableAlloc {
+    fn alloc(&mut self) -> Result<TablePage> {
+        // SAFETY: The DMA tag is valid for the lifetime of the driver.
+        let mem = unsafe {
+            bindings::drm_dmamem_alloc(
+                self.dmat,
+                UAT_PGSZ as _,
+                UAT_PGSZ as _,
+                1,
+                UAT_PGSZ as _,
+                0,
+                0,
+            )
+        };
+        if mem.is_null() {
+            return Err(ENOMEM);
+        }
 
-    fn tlb_flush_all(_data: <Self::Data as ForeignOwnable>::Borrowed<'_>) {}
-    fn tlb_flush_walk(
-        _data: <Self::Data as ForeignOwnable>::Borrowed<'_>,
-        _iova: usize,
-        _size: usize,
-        _granule: usize,
-    ) {
+        // SAFETY: `mem` is a valid, loaded single-segment allocation, zeroed by `drm_dmamem_alloc`.
+        let (pa, kva) = unsafe { ((*mem).segs[0].ds_addr as u64, (*mem).kva) };
+        Ok(TablePage {
+            pa,
+            ptr: NonNull::new(kva as *mut AtomicU64).ok_or(ENOMEM)?,
+            cookie: mem as usize,
+        })
     }
-    fn tlb_add_page(
-        _data: <Self::Data as ForeignOwnable>::Borrowed<'_>,
-        _iova: usize,
-        _granule: usize,
-    ) {
+
+    fn free(&mut self, page: TablePage) {
+        // SAFETY: `cookie` is the allocation returned by `drm_dmamem_alloc` in `alloc`, and the
+        // page table no longer references it.
+        unsafe { bindings::drm_dmamem_free(self.dmat, page.cookie as *mut _) };
+    }
+
+    fn sync(&mut self) {
+        mem::sync();
+    }
+}
+
+/// A page backing the unbound parts of a `Vm`'s sparse ranges.
+///
+/// It is mapped GPU-writable so that accesses to unbound parts never fault. Writes land in the
+/// page and are effectively discarded, so reads return undefined data (zero until the first
+/// write). The page is private to its `Vm`, so this never leaks data between address spaces.
+struct ScratchPage {
+    alloc: DmaTableAlloc,
+    page: Option<TablePage>,
+}
+
+impl ScratchPage {
+    fn new() -> Result<ScratchPage> {
+        let mut alloc = DmaTableAlloc {
+            dmat: unsafe { crate::DMAT.expect("Uninitialized") },
+        };
+        let page = alloc.alloc()?;
+        Ok(ScratchPage {
+            alloc,
+            page: Some(page),
+        })
+    }
+
+    /// Returns the physical address of the page.
+    fn pa(&self) -> usize {
+        self.page.as_ref().unwrap().pa as usize
+    }
+}
+
+impl Drop for ScratchPage {
+    fn drop(&mut self) {
+        if let Some(page) = self.page.take() {
+            self.alloc.free(page);
+        }
     }
 }
 
@@ -772,17 +1097,12 @@ impl Vm {
         id: u64,
         file_id: u64,
     ) -> Result<Vm> {
-        let page_table = AppleUAT::new(
-            dev,
-            io_pgtable::Config {
-                pgsize_bitmap: UAT_PGSZ,
-                ias: if is_kernel { UAT_IAS_KERN } else { UAT_IAS },
-                oas: cfg.uat_oas,
-                coherent_walk: true,
-                quirks: 0,
+        let page_table = UatPageTable::new(
+            DmaTableAlloc {
                 dmat: unsafe { crate::DMAT.expect("Uninitialized") },
             },
-            (),
+            if is_kernel { UAT_IAS_KERN } else { UAT_IAS },
+            cfg.uat_oas,
         )?;
         let min_va = if is_kernel {
             IOVA_KERN_BASE
@@ -813,6 +1133,10 @@ impl Vm {
                     bind_token: None,
                     active_users: 0,
                     id,
+                    file_id,
+                    owner: None,
+                    sparse: Vec::new(),
+                    scratch: None,
                 },
                 c_str!("VmInner"),
             ))?,
@@ -846,6 +1170,7 @@ impl Vm {
                 prot,
                 sgt: Some(sgt),
                 mapped_size: size,
+                sparse: false,
             },
             (size + if guard { UAT_PGSZ } else { 0 }) as u64, // Add guard page
             alignment,
@@ -871,6 +1196,10 @@ impl Vm {
     ) -> Result<Mapping> {
         let mut inner = self.inner.lock();
 
+        let sparse = inner.check_sparse(addr as usize, size)?;
+        // Inside a sparse range, the page after the mapping is backed by the scratch page (or
+        // the next bound page), so a guard page would not fault and would only block binding it.
+        let guard = guard && !sparse;
         let uat_inner = inner.uat_inner.clone();
         let node = inner.mm.reserve_node(
             MappingInner {
@@ -879,13 +1208,134 @@ impl Vm {
                 prot,
                 sgt: Some(sgt),
                 mapped_size: size,
+                sparse,
             },
             addr,
             (size + if guard { UAT_PGSZ } else { 0 }) as u64, // Add guard page
             0,
         )?;
 
-        inner.map_node(&node, prot)?;
+        if let Err(e) = inner.map_node(&node, prot) {
+            if sparse {
+                // Don't leave a partial mapping behind in the sparse range.
+                if inner.fill_scratch(addr as usize, size).is_err() {
+                    dev_err!(
+                        inner.dev,
+                        "MMU: restore sparse {:#x}:{:#x} failed\n",
+                        addr,
+                        size
+                    );
+                }
+                inner.flush_tlb_range(addr as usize, size);
+            }
+            return Err(e);
+        }
+        if sparse {
+            // The scratch page may be cached in the TLB.
+            inner.flush_tlb_range(addr as usize, size);
+        }
+        Ok(Mapping(node))
+    }
+
+    /// Reserve a sparse range in this Vm, backed by the writable scratch page until objects are
+    /// mapped into it with [`Vm::map_at`]. Accesses to unbound parts of the range never fault.
+    pub(crate) fn bind_sparse(&self, addr: u64, size: usize) -> Result {
+        let mut inner = self.inner.lock();
+        let iova = addr as usize;
+
+        if (iova | size) & UAT_PGMSK != 0 || size == 0 {
+            return Err(EINVAL);
+        }
+        let mapped_iova = inner.map_iova(iova, size)?;
+        if inner.check_sparse(iova, size)? || inner.is_mapped(iova, size) {
+            return Err(EBUSY);
+        }
+
+        if inner.scratch.is_none() {
+            inner.scratch = Some(ScratchPage::new()?);
+        }
+        let paddr = inner.scratch.as_ref().unwrap().pa();
+
+        inner.sparse.try_reserve(1)?;
+        if let Err(e) =
+            inner
+                .page_table
+                .map_repeated(mapped_iova, paddr, size >> UAT_PGBIT, PROT_SPARSE)
+        {
+            if inner.unmap_range(iova, size).is_err() {
+                dev_err!(
+                    inner.dev,
+                    "MMU: unmap sparse {:#x}:{:#x} failed\n",
+                    iova,
+                    size
+                );
+            }
+            return Err(e);
+        }
+        inner.sparse.push(iova..iova + size);
+
+        mod_dev_dbg!(inner.dev, "MMU: sparse bind {:#x}:{:#x}\n", iova, size);
+        Ok(())
+    }
+
+    /// Release a sparse range previously reserved with [`Vm::bind_sparse`]. All objects mapped
+    /// into it must have been unmapped. Accesses to the range fault again afterwards.
+    pub(crate) fn unbind_sparse(&self, addr: u64, size: usize) -> Result {
+        let mut inner = self.inner.lock();
+        let iova = addr as usize;
+
+        let index = inner
+            .sparse
+            .iter()
+            .position(|r| *r == (iova..iova + size))
+            .ok_or(ENOENT)?;
+        if inner.is_mapped(iova, size) {
+            return Err(EBUSY);
+        }
+
+        inner.sparse.swap_remove(index);
+        inner.unmap_range(iova, size)?;
+        inner.flush_tlb_range(iova, size);
+
+        mod_dev_dbg!(inner.dev, "MMU: sparse unbind {:#x}:{:#x}\n", iova, size);
+        Ok(())
+    }
+
+    /// Map a single physical page into this Vm at a free address in a given range.
+    ///
+    /// The caller must keep the page alive for as long as the returned `Mapping`.
+    pub(crate) fn map_page_in_range(
+        &self,
+        phys: usize,
+        start: u64,
+        end: u64,
+        prot: u32,
+    ) -> Result<Mapping> {
+        let mut inner = self.inner.lock();
+
+        if phys & UAT_PGMSK != 0 {
+            return Err(EINVAL);
+        }
+
+        let uat_inner = inner.uat_inner.clone();
+        let node = inner.mm.insert_node_in_range(
+            MappingInner {
+                owner: self.inner.clone(),
+                uat_inner,
+                prot,
+                sgt: None,
+                mapped_size: UAT_PGSZ,
+                sparse: false,
+            },
+            UAT_PGSZ as u64,
+            UAT_PGSZ as u64,
+            0,
+            start,
+            end,
+            mm::InsertMode::Best,
+        )?;
+
+        inner.map_range(node.start() as usize, phys, UAT_PGSZ, prot)?;
         Ok(Mapping(node))
     }
 
@@ -920,13 +1370,14 @@ impl Vm {
                 prot,
                 sgt: None,
                 mapped_size: size,
+                sparse: false,
             },
             iova,
             size as u64,
             0,
         )?;
 
-        inner.map_pages(iova as usize, phys, UAT_PGSZ, size >> UAT_PGBIT, prot)?;
+        inner.map_range(iova as usize, phys, size, prot)?;
 
         Ok(Mapping(node))
     }
@@ -958,8 +1409,8 @@ impl Drop for VmInner {
             let ttb = self.ttb() | TTBR_VALID | (idx as u64) << TTBR_ASID_SHIFT;
 
             let uat_inner = self.uat_inner.lock();
-            uat_inner.handoff().lock();
-            let handoff_cur = uat_inner.handoff().current_slot();
+            let handoff = self.uat_inner.lock_handoff(&uat_inner);
+            let handoff_cur = handoff.current_slot();
             let ttb_cur = uat_inner.ttbs()[idx].ttb0.load(Ordering::SeqCst);
             let inval = ttb_cur == ttb;
             if inval {
@@ -972,7 +1423,7 @@ impl Drop for VmInner {
                 uat_inner.ttbs()[idx].ttb0.store(0, Ordering::SeqCst);
                 uat_inner.ttbs()[idx].ttb1.store(0, Ordering::SeqCst);
             }
-            uat_inner.handoff().unlock();
+            core::mem::drop(handoff);
             core::mem::drop(uat_inner);
 
             // In principle we dropped all the Mappings already, but we might as
@@ -1127,8 +1578,8 @@ impl Uat {
                 };
 
                 let ttbs = uat_inner.ttbs();
-                uat_inner.handoff().lock();
-                if uat_inner.handoff().current_slot() == Some(idx as u32) {
+                let handoff = self.inner.lock_handoff(&uat_inner);
+                if handoff.current_slot() == Some(idx as u32) {
                     pr_err!(
                         "Vm::bind to slot {}, but it is currently in use by the ASC?\n",
                         idx
@@ -1136,7 +1587,7 @@ impl Uat {
                 }
                 ttbs[idx].ttb0.store(ttb, Ordering::Relaxed);
                 ttbs[idx].ttb1.store(ttb1, Ordering::Relaxed);
-                uat_inner.handoff().unlock();
+                core::mem::drop(handoff);
                 core::mem::drop(uat_inner);
 
                 // Make sure all TLB entries from the previous owner of this ASID are gone
@@ -1144,6 +1595,9 @@ impl Uat {
                 mem::sync();
             }
 
+            let idx = (slot.slot() as usize) + UAT_USER_CTX_START;
+            self.inner.lock_mut().slot_vms[idx] = Some(vm.inner.clone());
+
             inner.bind_token = Some(slot.token());
             inner.binding = Some(slot);
         }
@@ -1155,9 +1609,52 @@ impl Uat {
         Ok(VmBind(vm.clone(), slot))
     }
 
-    /// Creates a new `Vm` linked to this UAT.
+    /// Creates a new `Vm` linked to this UAT, owned by the current process.
     pub(crate) fn new_vm(&self, id: u64, file_id: u64) -> Result<Vm> {
-        Vm::new(&self.dev, self.inner.clone(), self.cfg, false, id, file_id)
+        let vm = Vm::new(&self.dev, self.inner.clone(), self.cfg, false, id, file_id)?;
+
+        let proc = current!();
+        vm.inner.lock().owner = Some(VmOwner {
+            pid: proc.pid(),
+            comm: CString::try_from_fmt(fmt!("{}", proc.comm()))?,
+        });
+
+        Ok(vm)
+    }
+
+    /// Log which `Vm`, process and mapping a GPU fault belongs to.
+    ///
+    /// The hardware only reports the low bits of the fault address, so faults in the kernel
+    /// (upper half) address space have `info.address` sign-extended to the full VA.
+    pub(crate) fn report_fault(&self, info: &mut regs::FaultInfo) {
+        let slot = info.vm_slot as usize;
+
+        let vm = if slot < UAT_USER_CTX_START {
+            if info.address >> UAT_IAS != 0 && info.address >> FAULT_ADDR_BITS == 0 {
+                info.address |= !0 << FAULT_ADDR_BITS;
+            }
+            if info.address as usize >= IOVA_KERN_BASE {
+                self.kernel_vm.inner.clone()
+            } else {
+                self.kernel_lower_vm.inner.clone()
+            }
+        } else {
+            let vm = self
+                .inner
+                .lock()
+                .slot_vms
+                .get(slot)
+                .and_then(|vm| vm.clone());
+            match vm {
+                Some(vm) => vm,
+                None => {
+                    dev_err!(self.dev, "  Fault in unbound VM slot {}\n", slot);
+                    return;
+                }
+            }
+        };
+
+        vm.lock().report_fault(info.address);
     }
 
     /// Creates the reference-counted inner data for a new `Uat` instance.
@@ -1178,12 +1675,14 @@ impl Uat {
             handoff_flush <- init::pin_init_array_from_fn(|i| {
                 Mutex::new_named(HandoffFlush(&handoff.flush[i]), c_str!("handoff_flush"))
             }),
-            shared <- Mutex::new_named(
+            handoff_ap <- Mutex::new_named((), c_str!("uat_handoff_ap")),
+            shared <- RwLock::new_named(
                 UatShared {
                     kernel_ttb1: 0,
                     map_kernel_to_user: false,
                     handoff_rgn,
                     ttbs_rgn,
+                    slot_vms: core::array::from_fn(|_| None),
                 },
                 c_str!("uat_shared")
             ),
@@ -1232,7 +1731,7 @@ impl Uat {
             )?,
         };
 
-        let mut inner = uat.inner.lock();
+        let mut inner = uat.inner.lock_mut();
 
         inner.map_kernel_to_user = map_kernel_to_user;
         inner.kernel_ttb1 = uat.pagetables_rgn.base;
@@ -1241,7 +1740,7 @@ impl Uat {
 
         dev_info!(dev, "MMU: Initializing TTBs\n");
 
-        inner.handoff().lock();
+        let handoff = uat.inner.lock_handoff(&inner);
 
         let ttbs = inner.ttbs();
 
@@ -1255,8 +1754,7 @@ impl Uat {
             ctx.ttb1.store(0, Ordering::Relaxed);
         }
 
-        inner.handoff().unlock();
-
+        core::mem::drop(handoff);
         core::mem::drop(inner);
 
         uat.kpt0()[2].store(ttb1 | PTE_TABLE, Ordering::Relaxed);
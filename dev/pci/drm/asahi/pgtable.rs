@@ -0,0 +1,601 @@
+// SPDX-License-Identifier: GPL-2.0-only OR MIT
+
+//! UAT page table management
+//!
+//! The UAT uses a variant of the ARM64 stage 1 LPAE page table format with a 16K granule. User
+//! address spaces have a 39-bit IAS, so the walk starts at level 1 with an 8-entry root table. The
+//! kernel address space is a "fake" 36-bit IAS one level lower, so its walk starts at level 2.
+//! Leaf entries are either 16K pages at level 3 or 32M blocks at level 2.
+//!
+//! Page table memory is obtained through a [`TableAlloc`], so the walker does not depend on how
+//! table pages are allocated. Alongside the hardware tables, we keep a shadow tree of the allocated
+//! tables with a count of live entries in each, so we never need to translate table physical
+//! addresses back to kernel virtual addresses, and tables that become empty on unmap can be
+//! reclaimed.
+//!
+//! This module does not perform any TLB invalidation, which is left to the caller.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::num::NonZeroU64;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kernel::error::{code::*, Result};
+
+/// Number of bits in a page offset.
+const PGBIT: usize = 14;
+/// Page size.
+const PGSZ: usize = 1 << PGBIT;
+/// Number of PTEs per table.
+const NPTE: usize = PGSZ / core::mem::size_of::<u64>();
+/// Number of IOVA bits resolved by each level.
+const BITS_PER_LEVEL: usize = PGBIT - 3;
+/// The level at which pages are mapped.
+const PAGE_LEVEL: usize = 3;
+/// The level at which blocks are mapped.
+const BLOCK_LEVEL: usize = 2;
+
+/// Mapping protection flags, with the same values as the common `IOMMU_*` flags.
+pub(crate) mod prot {
+    /// Readable.
+    pub(crate) const READ: u32 = 1 << 0;
+    /// Writable.
+    pub(crate) const WRITE: u32 = 1 << 1;
+    /// Cache coherent (uncached on the GPU side).
+    pub(crate) const CACHE: u32 = 1 << 2;
+    /// Not executable (GPU access only).
+    pub(crate) const NOEXEC: u32 = 1 << 3;
+    /// Device memory.
+    pub(crate) const MMIO: u32 = 1 << 4;
+    /// Privileged (firmware) mapping.
+    pub(crate) const PRIV: u32 = 1 << 5;
+}
+
+const PTE_VALID: u64 = 1 << 0;
+const PTE_TYPE_MASK: u64 = 0x3;
+const PTE_TYPE_BLOCK: u64 = 0x1;
+const PTE_TYPE_TABLE: u64 = 0x3;
+const PTE_TYPE_PAGE: u64 = 0x3;
+
+const PTE_MEMATTR_PRIV: u64 = 0 << 2;
+const PTE_MEMATTR_DEV: u64 = 1 << 2;
+const PTE_MEMATTR_SHARED: u64 = 2 << 2;
+
+const PTE_AP0: u64 = 1 << 6; // Firmware mapping
+const PTE_AP1: u64 = 1 << 7; // GPU-only mapping
+const PTE_AF: u64 = 1 << 10;
+const PTE_NG: u64 = 1 << 11;
+const PTE_PXN: u64 = 1 << 53;
+const PTE_UXN: u64 = 1 << 54;
+const PTE_GPU_ACCESS: u64 = 1 << 55;
+
+/// Output address bits of a PTE.
+const PTE_ADDR_MASK: u64 = ((1 << 48) - 1) & !((PGSZ as u64) - 1);
+
+/// Returns the attribute bits of a leaf PTE for the given protection flags.
+///
+/// This follows the Apple UAT flavor of the LPAE format, where AP0 marks firmware (privileged)
+/// mappings and the execute-never bits are reused as GPU/firmware access permissions.
+pub(crate) fn prot_to_pte(prot: u32) -> u64 {
+    let mut pte = PTE_GPU_ACCESS | PTE_AF;
+
+    if prot & prot::PRIV != 0 {
+        pte |= PTE_AP0;
+        if prot & prot::WRITE != 0 {
+            pte |= PTE_UXN;
+            if prot & prot::READ == 0 {
+                pte |= PTE_PXN;
+            }
+        } else if prot & prot::READ == 0 {
+            pte |= PTE_PXN;
+        }
+    } else if prot & prot::NOEXEC != 0 {
+        pte |= PTE_AP1 | PTE_NG;
+        if prot & prot::READ == 0 {
+            pte |= PTE_PXN;
+            if prot & prot::WRITE == 0 {
+                pte |= PTE_UXN;
+            }
+        } else if prot & prot::WRITE != 0 {
+            pte |= PTE_UXN;
+        }
+    } else {
+        pte |= PTE_NG;
+        if prot & prot::WRITE != 0 {
+            pte |= PTE_UXN;
+        }
+        if prot & prot::READ != 0 {
+            pte |= PTE_PXN;
+        }
+    }
+
+    if prot & prot::MMIO != 0 {
+        pte |= PTE_MEMATTR_DEV;
+    } else if prot & prot::CACHE != 0 {
+        pte |= PTE_MEMATTR_SHARED;
+    } else {
+        pte |= PTE_MEMATTR_PRIV;
+    }
+
+    pte
+}
+
+/// Returns the IOVA shift of entries at `level`.
+const fn level_shift(level: usize) -> usize {
+    PGBIT + (PAGE_LEVEL - level) * BITS_PER_LEVEL
+}
+
+/// Returns whether `pte` is a leaf (page or block) at `level`.
+fn is_leaf(pte: u64, level: usize) -> bool {
+    if level == PAGE_LEVEL {
+        pte & PTE_TYPE_MASK == PTE_TYPE_PAGE
+    } else {
+        pte & PTE_TYPE_MASK == PTE_TYPE_BLOCK
+    }
+}
+
+/// A single page of page table memory.
+pub(crate) struct TablePage {
+    /// Physical address, as seen by the UAT.
+    pub(crate) pa: u64,
+    /// CPU mapping of the `NPTE` entries.
+    pub(crate) ptr: NonNull<AtomicU64>,
+    /// Opaque allocator data.
+    pub(crate) cookie: usize,
+}
+
+/// Page table memory allocator.
+pub(crate) trait TableAlloc {
+    /// Allocates a zeroed, page-aligned table page.
+    fn alloc(&mut self) -> Result<TablePage>;
+
+    /// Frees a table page previously returned by [`TableAlloc::alloc`].
+    fn free(&mut self, page: TablePage);
+
+    /// Makes prior PTE writes visible to the page table walker.
+    fn sync(&mut self) {}
+}
+
+/// A page table page and the shadow state needed to manage it.
+struct Table {
+    page: TablePage,
+    /// Number of non-zero entries.
+    used: usize,
+    /// Next level tables, indexed like the entries. Empty for leaf-only tables.
+    children: Vec<Option<Box<Table>>>,
+}
+
+impl Table {
+    fn new<A: TableAlloc>(alloc: &mut A, level: usize) -> Result<Box<Table>> {
+        let page = alloc.alloc()?;
+        let mut children = Vec::new();
+        if level < PAGE_LEVEL {
+            if children.try_reserve_exact(NPTE).is_err() {
+                alloc.free(page);
+                return Err(ENOMEM);
+            }
+            children.resize_with(NPTE, || None);
+        }
+
+        Ok(Box::new(Table {
+            page,
+            used: 0,
+            children,
+        }))
+    }
+
+    fn entry(&self, idx: usize) -> &AtomicU64 {
+        assert!(idx < NPTE);
+        // SAFETY: The page holds `NPTE` entries and lives as long as this table.
+        unsafe { &*self.page.ptr.as_ptr().add(idx) }
+    }
+
+    fn get(&self, idx: usize) -> u64 {
+        self.entry(idx).load(Ordering::Relaxed)
+    }
+
+    fn set(&mut self, idx: usize, pte: u64) {
+        let old = self.get(idx);
+        match (old != 0, pte != 0) {
+            (false, true) => self.used += 1,
+            (true, false) => self.used -= 1,
+            _ => (),
+        }
+        self.entry(idx).store(pte, Ordering::Release);
+    }
+
+    /// Frees this table and all tables below it.
+    fn free<A: TableAlloc>(self, alloc: &mut A) {
+        let Table { page, children, .. } = self;
+        for child in children.into_iter().flatten() {
+            (*child).free(alloc);
+        }
+        alloc.free(page);
+    }
+}
+
+/// A UAT page table.
+pub(crate) struct UatPageTable<A: TableAlloc> {
+    alloc: A,
+    root: Option<Box<Table>>,
+    root_level: usize,
+    ias: usize,
+    oas: usize,
+}
+
+// SAFETY: The table pages are only accessed through `&mut self`, or `&self` for lookups.
+unsafe impl<A: TableAlloc + Send> Send for UatPageTable<A> {}
+
+impl<A: TableAlloc> UatPageTable<A> {
+    /// Creates a new, empty page table with the given input and output address sizes.
+    pub(crate) fn new(mut alloc: A, ias: usize, oas: usize) -> Result<Self> {
+        if ias <= level_shift(BLOCK_LEVEL) || ias > 48 || oas > 48 {
+            return Err(EINVAL);
+        }
+
+        let levels = (ias - PGBIT).div_ceil(BITS_PER_LEVEL);
+        let root_level = PAGE_LEVEL + 1 - levels;
+        let root = Table::new(&mut alloc, root_level)?;
+
+        Ok(UatPageTable {
+            alloc,
+            root: Some(root),
+            root_level,
+            ias,
+            oas,
+        })
+    }
+
+    /// Returns the physical address of the root table, for use as the TTB.
+    pub(crate) fn ttbr(&self) -> u64 {
+        self.root().page.pa
+    }
+
+    fn root(&self) -> &Table {
+        self.root.as_ref().unwrap()
+    }
+
+    fn index(&self, iova: usize, level: usize) -> usize {
+        let idx = iova >> level_shift(level);
+        if level == self.root_level {
+            idx
+        } else {
+            idx & (NPTE - 1)
+        }
+    }
+
+    /// Returns the level at which mappings of `pgsize` are made.
+    fn leaf_level(&self, pgsize: usize) -> Result<usize> {
+        match pgsize {
+            _ if pgsize == 1 << level_shift(PAGE_LEVEL) => Ok(PAGE_LEVEL),
+            _ if pgsize == 1 << level_shift(BLOCK_LEVEL) => Ok(BLOCK_LEVEL),
+            _ => Err(EINVAL),
+        }
+    }
+
+    fn check_iova(&self, iova: usize, pgsize: usize, pgcount: usize) -> Result {
+        let size = pgsize.checked_mul(pgcount).ok_or(EINVAL)?;
+        let end = iova.checked_add(size).ok_or(EINVAL)?;
+        if iova & (pgsize - 1) != 0 || (end - 1) >> self.ias != 0 {
+            Err(EINVAL)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Maps `pgcount` physically contiguous pages of size `pgsize`, which must be the page or
+    /// block size.
+    ///
+    /// Mapping over an existing mapping fails with `EEXIST`. On failure, pages mapped so far are
+    /// left in place. Returns the number of bytes mapped.
+    pub(crate) fn map_pages(
+        &mut self,
+        iova: usize,
+        paddr: usize,
+        pgsize: usize,
+        pgcount: usize,
+        prot: u32,
+    ) -> Result<usize> {
+        self.map_leaves(iova, paddr, pgsize, pgsize, pgcount, prot)
+    }
+
+    /// Maps `pgcount` pages, all backed by the single page at `paddr`.
+    ///
+    /// This is used to back sparse ranges with a scratch page. Errors are as for
+    /// [`UatPageTable::map_pages`].
+    pub(crate) fn map_repeated(
+        &mut self,
+        iova: usize,
+        paddr: usize,
+        pgcount: usize,
+        prot: u32,
+    ) -> Result<usize> {
+        self.map_leaves(iova, paddr, 0, PGSZ, pgcount, prot)
+    }
+
+    fn check_paddr(&self, paddr: usize, stride: usize, pgsize: usize, pgcount: usize) -> Result {
+        let end = pgcount
+            .checked_sub(1)
+            .and_then(|n| n.checked_mul(stride))
+            .and_then(|off| paddr.checked_add(off + pgsize))
+            .ok_or(EINVAL)?;
+        if paddr & (pgsize - 1) != 0 || (end - 1) as u64 >> self.oas != 0 {
+            Err(EINVAL)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn map_leaves(
+        &mut self,
+        iova: usize,
+        paddr: usize,
+        stride: usize,
+        pgsize: usize,
+        pgcount: usize,
+        prot: u32,
+    ) -> Result<usize> {
+        let level = self.leaf_level(pgsize)?;
+        self.check_iova(iova, pgsize, pgcount)?;
+        self.check_paddr(paddr, stride, pgsize, pgcount)?;
+
+        let attrs = prot_to_pte(prot)
+            | if level == PAGE_LEVEL {
+                PTE_TYPE_PAGE
+            } else {
+                PTE_TYPE_BLOCK
+            };
+
+        let mut root = self.root.take().unwrap();
+        let mut ret = Ok(pgcount * pgsize);
+        for i in 0..pgcount {
+            let pte = attrs | ((paddr + i * stride) as u64 & PTE_ADDR_MASK);
+            if let Err(e) = self.map_one(&mut root, self.root_level, iova + i * pgsize, level, pte)
+            {
+                ret = Err(e);
+                break;
+            }
+        }
+        self.root = Some(root);
+        self.alloc.sync();
+
+        ret
+    }
+
+    /// Replaces the existing mappings of `pgcount` pages with physically contiguous pages.
+    ///
+    /// Each page is switched over with a single PTE write, so the range never becomes unmapped
+    /// while this runs. Blocks covering the range are split first. Replacing a page that is not
+    /// mapped fails with `ENOENT`, leaving the pages replaced so far in place. Returns the number
+    /// of bytes replaced.
+    pub(crate) fn replace_pages(
+        &mut self,
+        iova: usize,
+        paddr: usize,
+        pgcount: usize,
+        prot: u32,
+    ) -> Result<usize> {
+        self.replace_leaves(iova, paddr, PGSZ, pgcount, prot)
+    }
+
+    /// Replaces the existing mappings of `pgcount` pages with the single page at `paddr`, like
+    /// [`UatPageTable::replace_pages`].
+    pub(crate) fn replace_repeated(
+        &mut self,
+        iova: usize,
+        paddr: usize,
+        pgcount: usize,
+        prot: u32,
+    ) -> Result<usize> {
+        self.replace_leaves(iova, paddr, 0, pgcount, prot)
+    }
+
+    fn replace_leaves(
+        &mut self,
+        iova: usize,
+        paddr: usize,
+        stride: usize,
+        pgcount: usize,
+        prot: u32,
+    ) -> Result<usize> {
+        self.check_iova(iova, PGSZ, pgcount)?;
+        self.check_paddr(paddr, stride, PGSZ, pgcount)?;
+
+        let attrs = prot_to_pte(prot) | PTE_TYPE_PAGE;
+
+        let mut root = self.root.take().unwrap();
+        let mut ret = Ok(pgcount * PGSZ);
+        for i in 0..pgcount {
+            let pte = attrs | ((paddr + i * stride) as u64 & PTE_ADDR_MASK);
+            if let Err(e) = self.replace_one(&mut root, self.root_level, iova + i * PGSZ, pte) {
+                ret = Err(e);
+                break;
+            }
+        }
+        self.root = Some(root);
+        self.alloc.sync();
+
+        ret
+    }
+
+    fn replace_one(&mut self, table: &mut Table, level: usize, iova: usize, pte: u64) -> Result {
+        let idx = self.index(iova, level);
+        let old = table.get(idx);
+
+        if old == 0 {
+            return Err(ENOENT);
+        }
+
+        if level == PAGE_LEVEL {
+            table.set(idx, pte);
+            return Ok(());
+        }
+
+        if is_leaf(old, level) {
+            self.split_block(table, level, idx)?;
+        }
+
+        let child = table.children[idx].as_mut().unwrap();
+        self.replace_one(child, level + 1, iova, pte)
+    }
+
+    fn map_one(
+        &mut self,
+        table: &mut Table,
+        level: usize,
+        iova: usize,
+        target: usize,
+        pte: u64,
+    ) -> Result {
+        let idx = self.index(iova, level);
+        let old = table.get(idx);
+
+        if level == target {
+            if old != 0 {
+                return Err(EEXIST);
+            }
+            table.set(idx, pte);
+            return Ok(());
+        }
+
+        if old == 0 {
+            let child = Table::new(&mut self.alloc, level + 1)?;
+            // Make sure the zeroed table is visible before the walker can reach it.
+            self.alloc.sync();
+            table.set(idx, child.page.pa | PTE_TYPE_TABLE);
+            table.children[idx] = Some(child);
+        } else if is_leaf(old, level) {
+            return Err(EEXIST);
+        }
+
+        let child = table.children[idx].as_mut().unwrap();
+        let ret = self.map_one(child, level + 1, iova, target, pte);
+        if table.children[idx].as_ref().unwrap().used == 0 {
+            // Don't leave behind a table we just allocated if the mapping failed.
+            self.reclaim(table, idx);
+        }
+        ret
+    }
+
+    /// Unmaps `pgcount` pages of size `pgsize`.
+    ///
+    /// Unmapping part of a block splits it, and tables left empty are freed. Holes in the range
+    /// are skipped. Returns the number of bytes processed.
+    pub(crate) fn unmap_pages(&mut self, iova: usize, pgsize: usize, pgcount: usize) -> usize {
+        let level = match self.leaf_level(pgsize) {
+            Ok(level) => level,
+            Err(_) => return 0,
+        };
+        if self.check_iova(iova, pgsize, pgcount).is_err() {
+            return 0;
+        }
+
+        let mut root = self.root.take().unwrap();
+        let mut done = 0;
+        for i in 0..pgcount {
+            if self
+                .unmap_one(&mut root, self.root_level, iova + i * pgsize, level)
+                .is_err()
+            {
+                break;
+            }
+            done += pgsize;
+        }
+        self.root = Some(root);
+        self.alloc.sync();
+
+        done
+    }
+
+    fn unmap_one(&mut self, table: &mut Table, level: usize, iova: usize, target: usize) -> Result {
+        let idx = self.index(iova, level);
+        let old = table.get(idx);
+
+        if old == 0 {
+            return Ok(());
+        }
+
+        if level == target {
+            table.set(idx, 0);
+            if let Some(child) = table.children.get_mut(idx).and_then(|c| c.take()) {
+                (*child).free(&mut self.alloc);
+            }
+            return Ok(());
+        }
+
+        if is_leaf(old, level) {
+            self.split_block(table, level, idx)?;
+        }
+
+        let child = table.children[idx].as_mut().unwrap();
+        let ret = self.unmap_one(child, level + 1, iova, target);
+        if table.children[idx].as_ref().unwrap().used == 0 {
+            self.reclaim(table, idx);
+        }
+        ret
+    }
+
+    /// Replaces the block at `table[idx]` with a table of next level entries mapping the same
+    /// range with the same attributes.
+    fn split_block(&mut self, table: &mut Table, level: usize, idx: usize) -> Result {
+        let old = table.get(idx);
+        let mut child = Table::new(&mut self.alloc, level + 1)?;
+
+        let attrs = old & !(PTE_ADDR_MASK | PTE_TYPE_MASK);
+        let ty = if level + 1 == PAGE_LEVEL {
+            PTE_TYPE_PAGE
+        } else {
+            PTE_TYPE_BLOCK
+        };
+        let base = old & PTE_ADDR_MASK;
+        let step = 1u64 << level_shift(level + 1);
+        for i in 0..NPTE {
+            child.set(i, attrs | ty | (base + i as u64 * step));
+        }
+
+        self.alloc.sync();
+        table.set(idx, child.page.pa | PTE_TYPE_TABLE);
+        table.children[idx] = Some(child);
+        Ok(())
+    }
+
+    /// Frees the empty table at `table[idx]`.
+    fn reclaim(&mut self, table: &mut Table, idx: usize) {
+        table.set(idx, 0);
+        if let Some(child) = table.children[idx].take() {
+            (*child).free(&mut self.alloc);
+        }
+    }
+
+    /// Translates an IOVA to a physical address, if it is mapped.
+    pub(crate) fn iova_to_phys(&self, iova: usize) -> Option<NonZeroU64> {
+        if iova >> self.ias != 0 {
+            return None;
+        }
+
+        let mut table = self.root();
+        let mut level = self.root_level;
+        loop {
+            let idx = self.index(iova, level);
+            let pte = table.get(idx);
+            if pte & PTE_VALID == 0 {
+                return None;
+            }
+            if is_leaf(pte, level) {
+                let mask = (1u64 << level_shift(level)) - 1;
+                return NonZeroU64::new((pte & PTE_ADDR_MASK & !mask) | (iova as u64 & mask));
+            }
+            table = table.children[idx].as_ref()?;
+            level += 1;
+        }
+    }
+}
+
+impl<A: TableAlloc> Drop for UatPageTable<A> {
+    fn drop(&mut self) {
+        if let Some(root) = self.root.take() {
+            (*root).free(&mut self.alloc);
+        }
+    }
+}
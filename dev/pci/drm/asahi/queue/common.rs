@@ -6,36 +6,27 @@
 
 use crate::fw::microseq;
 use crate::fw::types::*;
+use crate::umem::UserMemory;
 
-use kernel::io_buffer::IoBufferReader;
 use kernel::prelude::*;
 use kernel::uapi;
-use kernel::user_ptr::UserSlicePtr;
 
-use core::mem::MaybeUninit;
-
-pub(super) fn build_attachments(pointer: u64, count: u32) -> Result<microseq::Attachments> {
+/// Reads and validates the `count` attachments at `pointer` in `mem`.
+pub(super) fn build_attachments(
+    mem: &impl UserMemory,
+    pointer: u64,
+    count: u32,
+) -> Result<microseq::Attachments> {
     if count as usize > microseq::MAX_ATTACHMENTS {
         return Err(EINVAL);
     }
 
-    const STRIDE: usize = core::mem::size_of::<uapi::drm_asahi_attachment>();
-    let size = STRIDE * count as usize;
-
-    // SAFETY: We only read this once, so there are no TOCTOU issues.
-    let mut reader = unsafe { UserSlicePtr::new(pointer as usize as *mut _, size).reader() };
+    // SAFETY: drm_asahi_attachment is plain old data.
+    let atts: Vec<uapi::drm_asahi_attachment> = unsafe { mem.read_array(pointer, count as usize)? };
 
     let mut attachments: microseq::Attachments = Default::default();
 
-    for i in 0..count {
-        let mut att: MaybeUninit<uapi::drm_asahi_attachment> = MaybeUninit::uninit();
-
-        // SAFETY: The size of `att` is STRIDE
-        unsafe { reader.read_raw(att.as_mut_ptr() as *mut u8, STRIDE)? };
-
-        // SAFETY: All bit patterns in the struct are valid
-        let att = unsafe { att.assume_init() };
-
+    for (i, att) in atts.iter().enumerate() {
         if att.flags != 0 {
             return Err(EINVAL);
         }
@@ -44,7 +35,7 @@ pub(super) fn build_attachments(pointer: u64, count: u32) -> Result<microseq::At
         }
 
         let cache_lines = (att.size + 127) >> 7;
-        attachments.list[i as usize] = microseq::Attachment {
+        attachments.list[i] = microseq::Attachment {
             address: U64(att.pointer),
             size: cache_lines.try_into()?,
             unk_c: 0x17,
@@ -12,7 +12,8 @@ use crate::alloc::Allocator;
 use crate::debug::*;
 use crate::fw::types::*;
 use crate::gpu::GpuManager;
-use crate::{fw, gpu, microseq};
+use crate::umem::UserSpace;
+use crate::{fw, gpu, microseq, trace};
 use crate::{inner_ptr, inner_weak_ptr};
 use core::mem::MaybeUninit;
 use core::sync::atomic::Ordering;
@@ -34,6 +35,7 @@ impl super::Queue::ver {
         job: &mut Job<super::QueueJob::ver>,
         cmd: &uapi::drm_asahi_command,
         result_writer: Option<super::ResultWriter>,
+        user_timestamps: super::timestamp::CommandTimestamps,
         id: u64,
         flush_stamps: bool,
     ) -> Result {
@@ -72,7 +74,7 @@ impl super::Queue::ver {
         }
         let cmdbuf = unsafe { cmdbuf.assume_init() };
 
-        if cmdbuf.flags != 0 {
+        if cmdbuf.flags & !(uapi::ASAHI_COMPUTE_NO_PREEMPTION as u64) != 0 {
             return Err(EINVAL);
         }
 
@@ -81,6 +83,8 @@ impl super::Queue::ver {
         let slot_client_seq: u8 = (self.id & 0xff) as u8;
 
         let vm_bind = job.vm_bind.clone();
+        let trace_tag = job.trace.pipe(trace::PIPE_COMPUTE);
+        let trace_timestamps = trace::enabled();
 
         mod_dev_dbg!(
             self.dev,
@@ -128,7 +132,10 @@ impl super::Queue::ver {
         let comp = GpuObject::new_init_prealloc(
             kalloc.gpu_ro.alloc_object()?,
             |ptr: GpuWeakPointer<fw::compute::RunCompute::ver>| {
-                let has_result = result_writer.is_some();
+                let has_timestamps = result_writer.is_some()
+                    || trace_timestamps
+                    || user_timestamps.start.is_some()
+                    || user_timestamps.end.is_some();
                 let notifier = notifier.clone();
                 let vm_bind = vm_bind.clone();
                 try_init!(fw::compute::RunCompute::ver {
@@ -158,6 +165,7 @@ impl super::Queue::ver {
                             unk_44: 0x0,
                             uuid,
                             attachments: common::build_attachments(
+                                &UserSpace,
                                 cmdbuf.attachments,
                                 cmdbuf.attachment_count,
                             )?,
@@ -170,7 +178,7 @@ impl super::Queue::ver {
                             notifier_buf: inner_weak_ptr!(notifier.weak_pointer(), state.unk_buf),
                         })?;
 
-                        if has_result {
+                        if has_timestamps {
                             builder.add(microseq::Timestamp::ver {
                                 header: microseq::op::Timestamp::new(true),
                                 cur_ts: inner_weak_ptr!(ptr, cur_ts),
@@ -194,7 +202,7 @@ impl super::Queue::ver {
                             header: microseq::op::WaitForIdle2::HEADER,
                         })?;
 
-                        if has_result {
+                        if has_timestamps {
                             builder.add(microseq::Timestamp::ver {
                                 header: microseq::op::Timestamp::new(false),
                                 cur_ts: inner_weak_ptr!(ptr, cur_ts),
@@ -341,7 +349,6 @@ impl super::Queue::ver {
                     meta <- try_init!(fw::job::raw::JobMeta {
                         unk_0: 0,
                         unk_2: 0,
-                        // TODO: make separate flag
                         no_preemption: (cmdbuf.flags
                         & uapi::ASAHI_COMPUTE_NO_PREEMPTION as u64
                         != 0) as u8,
@@ -359,10 +366,10 @@ impl super::Queue::ver {
                         start_addr: Some(inner_ptr!(inner.timestamps.gpu_pointer(), start)),
                         end_addr: Some(inner_ptr!(inner.timestamps.gpu_pointer(), end)),
                     }),
-                    user_timestamp_pointers <- try_init!(fw::job::raw::TimestampPointers {
-                        start_addr: None,
-                        end_addr: None,
-                    }),
+                    user_timestamp_pointers: fw::job::raw::UserTimestampPointers {
+                        start_addr: user_timestamps.start_addr(),
+                        end_addr: user_timestamps.end_addr(),
+                    },
                     client_sequence: slot_client_seq,
                     pad_2d1: Default::default(),
                     unk_2d4: 0,
@@ -381,8 +388,20 @@ impl super::Queue::ver {
 
         core::mem::drop(alloc);
 
+        let trace_dev = self.dev.clone();
         fence.add_command();
         comp_job.add_cb(comp, vm_bind.slot(), move |cmd, error| {
+            if trace_timestamps {
+                cmd.timestamps.with(|raw, _inner| {
+                    trace_dev.data().gpu.trace().record_fw(
+                        trace_tag,
+                        raw.start.load(Ordering::Relaxed),
+                        raw.end.load(Ordering::Relaxed),
+                    );
+                });
+            }
+            // The firmware is done writing to the user timestamp buffers
+            core::mem::drop(user_timestamps);
             if let Some(err) = error {
                 fence.set_error(err.into())
             }
@@ -412,6 +431,9 @@ impl super::Queue::ver {
 
         comp_job.next_seq();
 
+        gpu.preemption_stats()
+            .record_compute(cmdbuf.flags & uapi::ASAHI_COMPUTE_NO_PREEMPTION as u64 != 0);
+
         Ok(())
     }
 }
@@ -9,7 +9,7 @@ use kernel::dma_fence::*;
 use kernel::prelude::*;
 use kernel::{
     c_str, dma_fence,
-    drm::gem::shmem::VMap,
+    drm::gem::{shmem::VMap, BaseObject, ReservationGuard},
     drm::sched,
     macros::versions,
     sync::{Arc, Mutex},
@@ -22,9 +22,10 @@ use crate::driver::{AsahiDevRef, AsahiDevice};
 use crate::fw::types::*;
 use crate::gpu::GpuManager;
 use crate::inner_weak_ptr;
-use crate::{alloc, buffer, channel, event, file, fw, gem, gpu, mmu, workqueue};
+use crate::{alloc, buffer, channel, event, file, fw, gem, gpu, mmu, trace, workqueue};
 
 use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
 
 const DEBUG_CLASS: DebugFlags = DebugFlags::Queue;
 
@@ -33,9 +34,15 @@ const WQ_SIZE: u32 = 0x500;
 mod common;
 mod compute;
 mod render;
+mod syncwait;
+mod timestamp;
+
+pub(crate) use syncwait::MAX_SYNC_WAIT;
+pub(crate) use timestamp::TimestampCommand;
 
 /// Trait implemented by all versioned queues.
 pub(crate) trait Queue: Send + Sync {
+    #[allow(clippy::too_many_arguments)]
     fn submit(
         &mut self,
         id: u64,
@@ -43,6 +50,9 @@ pub(crate) trait Queue: Send + Sync {
         out_syncs: Vec<file::SyncItem>,
         result_buf: Option<gem::ObjectRef>,
         commands: Vec<uapi::drm_asahi_command>,
+        sync_wait_timeout: Duration,
+        bo_syncs: Vec<file::BoSync>,
+        timestamp_cmds: Vec<TimestampCommand>,
     ) -> Result;
 }
 
@@ -53,11 +63,12 @@ struct SubQueue {
 
 #[versions(AGX)]
 impl SubQueue::ver {
-    fn new_job(&mut self, fence: dma_fence::Fence) -> SubQueueJob::ver {
+    fn new_job(&mut self, fence: dma_fence::Fence, trace: trace::Tag) -> SubQueueJob::ver {
         SubQueueJob::ver {
             wq: self.wq.clone(),
             fence: Some(fence),
             job: None,
+            trace,
         }
     }
 }
@@ -67,6 +78,7 @@ struct SubQueueJob {
     wq: Arc<workqueue::WorkQueue::ver>,
     job: Option<workqueue::Job::ver>,
     fence: Option<dma_fence::Fence>,
+    trace: trace::Tag,
 }
 
 #[versions(AGX)]
@@ -75,7 +87,7 @@ impl SubQueueJob::ver {
         if self.job.is_none() {
             mod_pr_debug!("SubQueueJob: Creating {:?} job\n", self.wq.pipe_type());
             self.job
-                .replace(self.wq.new_job(self.fence.take().unwrap())?);
+                .replace(self.wq.new_job(self.fence.take().unwrap(), self.trace)?);
         }
         Ok(self.job.as_mut().expect("expected a Job"))
     }
@@ -92,6 +104,47 @@ impl SubQueueJob::ver {
     }
 }
 
+/// Device-wide counts of committed commands, split by whether they requested no preemption.
+///
+/// The firmware does not report when it actually preempts work, so this only counts what
+/// userspace asked for. Exported through `DRM_ASAHI_PARAM_GROUP_PREEMPTION`.
+#[derive(Default)]
+pub(crate) struct PreemptionStats {
+    render: AtomicU64,
+    render_no_preemption: AtomicU64,
+    compute: AtomicU64,
+    compute_no_preemption: AtomicU64,
+}
+
+impl PreemptionStats {
+    fn record(total: &AtomicU64, no_preemption_count: &AtomicU64, no_preemption: bool) {
+        total.fetch_add(1, Ordering::Relaxed);
+        if no_preemption {
+            no_preemption_count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_render(&self, no_preemption: bool) {
+        Self::record(&self.render, &self.render_no_preemption, no_preemption);
+    }
+
+    pub(crate) fn record_compute(&self, no_preemption: bool) {
+        Self::record(&self.compute, &self.compute_no_preemption, no_preemption);
+    }
+
+    /// Returns the current counts as the userspace params struct.
+    pub(crate) fn params(&self) -> uapi::drm_asahi_params_preemption {
+        uapi::drm_asahi_params_preemption {
+            size: core::mem::size_of::<uapi::drm_asahi_params_preemption>() as u32,
+            version: uapi::DRM_ASAHI_PARAMS_PREEMPTION_VERSION,
+            render_commands: self.render.load(Ordering::Relaxed),
+            render_no_preemption: self.render_no_preemption.load(Ordering::Relaxed),
+            compute_commands: self.compute.load(Ordering::Relaxed),
+            compute_no_preemption: self.compute_no_preemption.load(Ordering::Relaxed),
+        }
+    }
+}
+
 #[versions(AGX)]
 pub(crate) struct Queue {
     dev: AsahiDevRef,
@@ -163,8 +216,12 @@ pub(crate) struct QueueJob {
     sj_frag: Option<SubQueueJob::ver>,
     sj_comp: Option<SubQueueJob::ver>,
     fence: UserFence<JobFence::ver>,
+    pending_syncs: Vec<syncwait::PendingSync>,
+    sync_timeout: Option<Arc<syncwait::SyncWaitTimeout>>,
+    sync_error: Option<Error>,
     did_run: bool,
     id: u64,
+    trace: trace::Tag,
 }
 
 #[versions(AGX)]
@@ -214,6 +271,42 @@ impl sched::JobImpl for QueueJob::ver {
     fn prepare(job: &mut sched::Job<Self>) -> Option<Fence> {
         mod_dev_dbg!(job.dev, "QueueJob {}: Checking runnability\n", job.id);
 
+        // Swap in the real fences of the points once their point fences have signaled.
+        while let Some(sync) = job.pending_syncs.pop() {
+            if sync.is_pending() {
+                mod_dev_dbg!(
+                    job.dev,
+                    "QueueJob {}: Blocking on unsubmitted timeline point\n",
+                    job.id
+                );
+                let fence = Fence::from_fence(&sync.point_fence());
+                job.pending_syncs.push(sync);
+                return Some(fence);
+            }
+            match sync.resolve() {
+                Ok(Some(fence)) => {
+                    mod_dev_dbg!(
+                        job.dev,
+                        "QueueJob {}: Blocking on submitted timeline point\n",
+                        job.id
+                    );
+                    return Some(fence);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    cls_pr_debug!(
+                        Errors,
+                        "QueueJob {}: Timeline point was not submitted in time\n",
+                        job.id
+                    );
+                    job.sync_error = Some(e);
+                }
+            }
+        }
+        if let Some(timeout) = job.sync_timeout.take() {
+            syncwait::SyncWaitTimeout::cancel(&timeout);
+        }
+
         if let Some(sj) = job.sj_vtx.as_ref() {
             if let Some(fence) = sj.can_submit() {
                 mod_dev_dbg!(
@@ -244,6 +337,11 @@ impl sched::JobImpl for QueueJob::ver {
                 return Some(fence);
             }
         }
+
+        job.dev.data().gpu.trace().record(
+            job.trace,
+            uapi::drm_asahi_trace_event_type_DRM_ASAHI_TRACE_DEPS_RESOLVED,
+        );
         None
     }
 
@@ -251,6 +349,10 @@ impl sched::JobImpl for QueueJob::ver {
     fn run(job: &mut sched::Job<Self>) -> Result<Option<dma_fence::Fence>> {
         mod_dev_dbg!(job.dev, "QueueJob {}: Running Job\n", job.id);
 
+        if let Some(err) = job.sync_error {
+            return Err(err);
+        }
+
         let dev = job.dev.data();
         let gpu = match dev
             .gpu
@@ -319,6 +421,10 @@ impl sched::JobImpl for QueueJob::ver {
         core::mem::drop(frag_job);
 
         job.did_run = true;
+        gpu.trace().record(
+            job.trace,
+            uapi::drm_asahi_trace_event_type_DRM_ASAHI_TRACE_RUN,
+        );
 
         Ok(Some(Fence::from_fence(&job.fence)))
     }
@@ -338,6 +444,9 @@ impl sched::JobImpl for QueueJob::ver {
 impl Drop for QueueJob::ver {
     fn drop(&mut self) {
         mod_dev_dbg!(self.dev, "QueueJob {}: Dropping\n", self.id);
+        if let Some(timeout) = self.sync_timeout.take() {
+            syncwait::SyncWaitTimeout::cancel(&timeout);
+        }
     }
 }
 
@@ -348,6 +457,52 @@ struct ResultWriter {
 }
 
 impl ResultWriter {
+    /// Returns the writer for the result of `cmd`, if it requested one.
+    fn for_command(
+        result_buf: Option<&gem::ObjectRef>,
+        cmd: &uapi::drm_asahi_command,
+    ) -> Result<Option<ResultWriter>> {
+        let buf = match result_buf {
+            None => {
+                if cmd.result_offset != 0 || cmd.result_size != 0 {
+                    cls_pr_debug!(Errors, "No result buffer but result requested\n");
+                    return Err(EINVAL);
+                }
+                return Ok(None);
+            }
+            Some(buf) => buf,
+        };
+
+        if cmd.result_size == 0 {
+            return Ok(None);
+        }
+
+        let end_offset = cmd
+            .result_offset
+            .checked_add(cmd.result_size)
+            .ok_or_else(|| {
+                cls_pr_debug!(Errors, "result_offset + result_size overflow\n");
+                EINVAL
+            })?;
+        if end_offset > buf.size() as u64 {
+            cls_pr_debug!(
+                Errors,
+                "Result buffer overflow ({} + {} > {})\n",
+                cmd.result_offset,
+                cmd.result_size,
+                buf.size()
+            );
+
+            return Err(EINVAL);
+        }
+
+        Ok(Some(ResultWriter {
+            vmap: buf.gem.vmap()?,
+            offset: cmd.result_offset.try_into()?,
+            len: cmd.result_size.try_into()?,
+        }))
+    }
+
     fn write<T>(&mut self, mut value: T) {
         let p: *mut u8 = &mut value as *mut _ as *mut u8;
         // SAFETY: We know `p` points to a type T of that size, and UAPI types must have
@@ -446,9 +601,10 @@ impl Queue::ver {
 
         // Rendering structures
         if caps & uapi::drm_asahi_queue_cap_DRM_ASAHI_QUEUE_CAP_RENDER != 0 {
-            let tvb_blocks = 0x8;
-
-            ret.buffer.as_ref().unwrap().ensure_blocks(tvb_blocks)?;
+            ret.buffer
+                .as_ref()
+                .unwrap()
+                .ensure_blocks(buffer::INITIAL_BLOCKS)?;
 
             ret.q_vtx = Some(SubQueue::ver {
                 wq: workqueue::WorkQueue::ver::new(
@@ -512,15 +668,45 @@ const SQ_RENDER: usize = uapi::drm_asahi_subqueue_DRM_ASAHI_SUBQUEUE_RENDER as u
 const SQ_COMPUTE: usize = uapi::drm_asahi_subqueue_DRM_ASAHI_SUBQUEUE_COMPUTE as usize;
 const SQ_COUNT: usize = uapi::drm_asahi_subqueue_DRM_ASAHI_SUBQUEUE_COUNT as usize;
 
+/// Checks the barriers of every command in a submission.
+///
+/// A barrier on a subqueue may refer to index 0, meaning all work submitted before this
+/// submission, or to the Nth preceding render or compute command on that subqueue within the
+/// submission. Timestamp commands may have barriers too, but are not counted.
+fn check_barriers(commands: &[uapi::drm_asahi_command]) -> Result {
+    let mut counts = [0u32; SQ_COUNT];
+
+    for cmd in commands {
+        for (queue_idx, index) in cmd.barriers.iter().enumerate() {
+            if *index != uapi::DRM_ASAHI_BARRIER_NONE as u32 && *index > counts[queue_idx] {
+                cls_pr_debug!(Errors, "Invalid barrier #{}: {}\n", queue_idx, index);
+                return Err(EINVAL);
+            }
+        }
+
+        match cmd.cmd_type {
+            uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_RENDER => counts[SQ_RENDER] += 1,
+            uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_COMPUTE => counts[SQ_COMPUTE] += 1,
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
 #[versions(AGX)]
 impl Queue for Queue::ver {
+    #[allow(clippy::too_many_arguments)]
     fn submit(
         &mut self,
         id: u64,
         in_syncs: Vec<file::SyncItem>,
         out_syncs: Vec<file::SyncItem>,
         result_buf: Option<gem::ObjectRef>,
-        commands: Vec<uapi::drm_asahi_command>,
+        mut commands: Vec<uapi::drm_asahi_command>,
+        sync_wait_timeout: Duration,
+        bo_syncs: Vec<file::BoSync>,
+        timestamp_cmds: Vec<TimestampCommand>,
     ) -> Result {
         let dev = self.dev.data();
         let gpu = match dev
@@ -547,12 +733,19 @@ impl Queue for Queue::ver {
             return Err(ENODEV);
         }
 
+        if self.gpu_context.is_banned() {
+            cls_pr_debug!(Errors, "Queue is banned after repeated GPU faults\n");
+            return Err(ECANCELED);
+        }
+
         // Empty submissions are not legal
         if commands.is_empty() {
             cls_pr_debug!(Errors, "Empty submission\n");
             return Err(EINVAL);
         }
 
+        check_barriers(&commands)?;
+
         let op_guard = if !in_syncs.is_empty() {
             Some(gpu.start_op()?)
         } else {
@@ -581,51 +774,179 @@ impl Queue for Queue::ver {
             )?
             .into();
 
+        // Timeline points that have not been submitted yet are waited for by the scheduler,
+        // behind a point fence that signals once they are.
+        let mut in_fences = Vec::new();
+        let mut pending_syncs = Vec::new();
+        let mut point_fences = Vec::new();
+        for sync in in_syncs {
+            match sync.fence {
+                Some(fence) => in_fences.push(fence),
+                None => {
+                    let pending = syncwait::PendingSync::new(sync.syncobj, sync.timeline_value)?;
+                    in_fences.push(Fence::from_fence(&pending.point_fence()));
+                    point_fences.push(pending.point_fence());
+                    pending_syncs.push(pending);
+                }
+            }
+        }
+
+        let sync_timeout = if point_fences.is_empty() {
+            None
+        } else {
+            mod_dev_dbg!(
+                self.dev,
+                "[Submission {}] Waiting for {} unsubmitted timeline points\n",
+                id,
+                point_fences.len()
+            );
+            Some(syncwait::SyncWaitTimeout::start(
+                self.dev.data().gpu.task_queue(),
+                point_fences,
+                sync_wait_timeout.min(MAX_SYNC_WAIT),
+            )?)
+        };
+
+        let trace_tag = trace::Tag {
+            file_id: self.vm.file_id(),
+            queue_id: self.id,
+            submission_id: id,
+            pipe: trace::PIPE_JOB,
+        };
+
         let mut job = self.entity.new_job(QueueJob::ver {
             dev: self.dev.clone(),
             vm_bind,
             op_guard,
-            sj_vtx: self
-                .q_vtx
-                .as_mut()
-                .map(|a| a.new_job(Fence::from_fence(&fence))),
-            sj_frag: self
-                .q_frag
-                .as_mut()
-                .map(|a| a.new_job(Fence::from_fence(&fence))),
-            sj_comp: self
-                .q_comp
-                .as_mut()
-                .map(|a| a.new_job(Fence::from_fence(&fence))),
+            sj_vtx: self.q_vtx.as_mut().map(|a| {
+                a.new_job(
+                    Fence::from_fence(&fence),
+                    trace_tag.pipe(trace::PIPE_VERTEX),
+                )
+            }),
+            sj_frag: self.q_frag.as_mut().map(|a| {
+                a.new_job(
+                    Fence::from_fence(&fence),
+                    trace_tag.pipe(trace::PIPE_FRAGMENT),
+                )
+            }),
+            sj_comp: self.q_comp.as_mut().map(|a| {
+                a.new_job(
+                    Fence::from_fence(&fence),
+                    trace_tag.pipe(trace::PIPE_COMPUTE),
+                )
+            }),
             fence,
+            pending_syncs,
+            sync_timeout,
+            sync_error: None,
             did_run: false,
             id,
+            trace: trace_tag,
         })?;
 
         mod_dev_dbg!(
             self.dev,
             "[Submission {}] Adding {} in_syncs\n",
             id,
-            in_syncs.len()
+            in_fences.len()
         );
-        for sync in in_syncs {
-            job.add_dependency(sync.fence.expect("in_sync missing fence"))?;
+        for fence in in_fences {
+            job.add_dependency(fence)?;
+        }
+
+        mod_dev_dbg!(
+            self.dev,
+            "[Submission {}] Adding {} implicit sync BOs\n",
+            id,
+            bo_syncs.len()
+        );
+        // The reservations stay locked until the job's finished fence has been added to them, so
+        // that the dependencies taken here and the fence added afterwards are ordered with other
+        // submissions using the same BOs.
+        let mut objs = Vec::new();
+        objs.try_reserve(bo_syncs.len())?;
+        for bo in bo_syncs.iter() {
+            objs.push(&*bo.obj.gem);
+        }
+        let mut resvs = ReservationGuard::lock(objs)?;
+        resvs.reserve_fences(1)?;
+        for (i, bo) in bo_syncs.iter().enumerate() {
+            job.add_implicit_dependencies(&resvs, i, bo.write)?;
         }
 
         let mut last_render = None;
         let mut last_compute = None;
-
-        for (i, cmd) in commands.iter().enumerate() {
-            match cmd.cmd_type {
-                uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_RENDER => last_render = Some(i),
-                uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_COMPUTE => last_compute = Some(i),
+        // User timestamps, indexed by the command they are recorded in.
+        let mut timestamps: Vec<timestamp::CommandTimestamps> = Vec::new();
+        timestamps.try_reserve(commands.len())?;
+        // Timestamps that start a subqueue, recorded at the start of its next command.
+        let mut leading: [Option<timestamp::UserTimestamp>; SQ_COUNT] = Default::default();
+        // Barriers of those timestamps, which the command they are recorded in must also wait on.
+        let mut leading_barriers: [Option<[u32; SQ_COUNT]>; SQ_COUNT] = Default::default();
+        let mut timestamp_cmds = timestamp_cmds.into_iter();
+
+        for (i, cmd) in commands.iter_mut().enumerate() {
+            timestamps.push(Default::default());
+            let sq = match cmd.cmd_type {
+                uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_RENDER => {
+                    last_render = Some(i);
+                    SQ_RENDER
+                }
+                uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_COMPUTE => {
+                    last_compute = Some(i);
+                    SQ_COMPUTE
+                }
+                uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_TIMESTAMP => {
+                    let ts_cmd = timestamp_cmds.next().ok_or(EINVAL)?;
+                    let sq = ts_cmd.subqueue;
+                    let ts = ts_cmd.map(|obj, offset| gpu.map_timestamp_page(obj, offset))?;
+                    // The end of the previous command is already past any barrier, so a
+                    // timestamp that has to wait goes to the start of the next command instead.
+                    let has_barriers = cmd
+                        .barriers
+                        .iter()
+                        .any(|b| *b != uapi::DRM_ASAHI_BARRIER_NONE as u32);
+                    let slot = match (sq, last_render, last_compute) {
+                        (SQ_RENDER, Some(prev), _) | (SQ_COMPUTE, _, Some(prev))
+                            if !has_barriers =>
+                        {
+                            &mut timestamps[prev].end
+                        }
+                        _ => &mut leading[sq],
+                    };
+                    if slot.replace(ts).is_some() {
+                        cls_pr_debug!(Errors, "Consecutive timestamps on subqueue {}\n", sq);
+                        return Err(EINVAL);
+                    }
+                    if has_barriers {
+                        leading_barriers[sq] = Some(cmd.barriers);
+                    }
+                    continue;
+                }
                 _ => {
                     cls_pr_debug!(Errors, "Unknown command type {}\n", cmd.cmd_type);
                     return Err(EINVAL);
                 }
+            };
+            timestamps[i].start = leading[sq].take();
+            if let Some(barriers) = leading_barriers[sq].take() {
+                // Waiting on a later command on a subqueue implies waiting on the earlier ones.
+                for (index, ts_index) in cmd.barriers.iter_mut().zip(barriers) {
+                    if ts_index != uapi::DRM_ASAHI_BARRIER_NONE as u32
+                        && (*index == uapi::DRM_ASAHI_BARRIER_NONE as u32 || ts_index > *index)
+                    {
+                        *index = ts_index;
+                    }
+                }
             }
         }
 
+        if leading.iter().any(|ts| ts.is_some()) {
+            cls_pr_debug!(Errors, "Timestamp on a subqueue without commands\n");
+            return Err(EINVAL);
+        }
+
         mod_dev_dbg!(
             self.dev,
             "[Submission {}] Submitting {} commands\n",
@@ -633,6 +954,10 @@ impl Queue for Queue::ver {
             commands.len()
         );
         for (i, cmd) in commands.into_iter().enumerate() {
+            if cmd.cmd_type == uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_TIMESTAMP {
+                continue;
+            }
+
             for (queue_idx, index) in cmd.barriers.iter().enumerate() {
                 if *index == uapi::DRM_ASAHI_BARRIER_NONE as u32 {
                     continue;
@@ -671,44 +996,7 @@ impl Queue for Queue::ver {
                 }
             }
 
-            let result_writer = match result_buf.as_ref() {
-                None => {
-                    if cmd.result_offset != 0 || cmd.result_size != 0 {
-                        cls_pr_debug!(Errors, "No result buffer but result requested\n");
-                        return Err(EINVAL);
-                    }
-                    None
-                }
-                Some(buf) => {
-                    if cmd.result_size != 0 {
-                        let end_offset = cmd
-                            .result_offset
-                            .checked_add(cmd.result_size)
-                            .ok_or_else(|| {
-                                cls_pr_debug!(Errors, "result_offset + result_size overflow\n");
-                                EINVAL
-                            })?;
-                        if end_offset > buf.size() as u64 {
-                            cls_pr_debug!(
-                                Errors,
-                                "Result buffer overflow ({} + {} > {})\n",
-                                cmd.result_offset,
-                                cmd.result_size,
-                                buf.size()
-                            );
-
-                            return Err(EINVAL);
-                        }
-                        Some(ResultWriter {
-                            vmap: buf.gem.vmap()?,
-                            offset: cmd.result_offset.try_into()?,
-                            len: cmd.result_size.try_into()?,
-                        })
-                    } else {
-                        None
-                    }
-                }
-            };
+            let result_writer = ResultWriter::for_command(result_buf.as_ref(), &cmd)?;
 
             match cmd.cmd_type {
                 uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_RENDER => {
@@ -716,6 +1004,7 @@ impl Queue for Queue::ver {
                         &mut job,
                         &cmd,
                         result_writer,
+                        core::mem::take(&mut timestamps[i]),
                         id,
                         last_render.unwrap() == i,
                     )?;
@@ -734,6 +1023,7 @@ impl Queue for Queue::ver {
                         &mut job,
                         &cmd,
                         result_writer,
+                        core::mem::take(&mut timestamps[i]),
                         id,
                         last_compute.unwrap() == i,
                     )?;
@@ -755,10 +1045,27 @@ impl Queue for Queue::ver {
         job.commit()?;
 
         mod_dev_dbg!(self.dev, "Queue: Arming job\n");
+        // Record this before the job can run, so events for it are in order.
+        gpu.trace().record(
+            trace_tag,
+            uapi::drm_asahi_trace_event_type_DRM_ASAHI_TRACE_SUBMIT,
+        );
         let job = job.arm();
         let out_fence = job.fences().finished();
+        for (i, bo) in bo_syncs.iter().enumerate() {
+            // Cannot fail, since a fence slot was reserved for every BO above.
+            if resvs.add_fence(i, &out_fence, bo.write).is_err() {
+                dev_crit!(
+                    self.dev,
+                    "[Submission {}] No fence slot reserved for BO {}\n",
+                    id,
+                    i
+                );
+            }
+        }
         mod_dev_dbg!(self.dev, "Queue: Pushing job\n");
         job.push();
+        core::mem::drop(resvs);
 
         mod_dev_dbg!(self.dev, "Queue: Adding {} out_syncs\n", out_syncs.len());
         for mut sync in out_syncs {
@@ -780,3 +1087,169 @@ impl Drop for Queue::ver {
         mod_dev_dbg!(self.dev, "[Queue {}] Dropping queue\n", self.id);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::umem::host::HostMemory;
+
+    const NONE: u32 = uapi::DRM_ASAHI_BARRIER_NONE as u32;
+
+    fn command(cmd_type: u32, barriers: [u32; SQ_COUNT]) -> uapi::drm_asahi_command {
+        uapi::drm_asahi_command {
+            cmd_type,
+            barriers,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn barriers() {
+        let render = uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_RENDER;
+        let compute = uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_COMPUTE;
+        let timestamp = uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_TIMESTAMP;
+
+        let ok = [
+            command(render, [0, 0]),
+            command(compute, [1, NONE]),
+            command(timestamp, [NONE, NONE]),
+            command(timestamp, [NONE, 1]),
+            command(render, [1, 1]),
+            command(compute, [2, 1]),
+        ];
+        assert_eq!(check_barriers(&ok), Ok(()));
+
+        // Commands cannot wait on themselves or later commands
+        assert_eq!(check_barriers(&[command(render, [1, NONE])]), Err(EINVAL));
+        assert_eq!(
+            check_barriers(&[command(render, [0, 0]), command(compute, [1, 1])]),
+            Err(EINVAL)
+        );
+        // Timestamp commands do not count as commands to wait on
+        assert_eq!(
+            check_barriers(&[command(timestamp, [NONE, NONE]), command(render, [1, NONE])]),
+            Err(EINVAL)
+        );
+        // Timestamp barriers are checked like any other
+        assert_eq!(
+            check_barriers(&[command(timestamp, [1, NONE]), command(render, [NONE, NONE])]),
+            Err(EINVAL)
+        );
+    }
+
+    #[test]
+    fn attachments() {
+        let mut mem = HostMemory::default();
+        let att = uapi::drm_asahi_attachment {
+            pointer: 0x1234_0000,
+            size: 0x1001,
+            order: 1,
+            flags: 0,
+        };
+        mem.map_items(0x1000, &[att, att]);
+
+        let atts = common::build_attachments(&mem, 0x1000, 2).unwrap();
+        assert_eq!(atts.count, 2);
+        assert_eq!(atts.list[1].size, 0x21);
+
+        assert_eq!(common::build_attachments(&mem, 0x1000, 0).unwrap().count, 0);
+        assert!(common::build_attachments(&mem, 0x1000, 3).is_err());
+        let too_many = crate::microseq::MAX_ATTACHMENTS as u32 + 1;
+        assert!(common::build_attachments(&mem, 0x1000, too_many).is_err());
+
+        mem.map_items(0x2000, &[uapi::drm_asahi_attachment { order: 7, ..att }]);
+        assert!(common::build_attachments(&mem, 0x2000, 1).is_err());
+    }
+
+    #[test]
+    fn syncs() {
+        let mut mem = HostMemory::default();
+        let syncobj = uapi::drm_asahi_sync {
+            sync_type: uapi::drm_asahi_sync_type_DRM_ASAHI_SYNC_SYNCOBJ,
+            handle: 1,
+            ..Default::default()
+        };
+        let timeline = uapi::drm_asahi_sync {
+            sync_type: uapi::drm_asahi_sync_type_DRM_ASAHI_SYNC_TIMELINE_SYNCOBJ,
+            handle: 2,
+            timeline_value: 5,
+            ..Default::default()
+        };
+        mem.map_items(0x1000, &[syncobj, timeline]);
+        mem.map_items(
+            0x2000,
+            &[uapi::drm_asahi_sync {
+                timeline_value: 1,
+                ..syncobj
+            }],
+        );
+        mem.map_items(
+            0x3000,
+            &[uapi::drm_asahi_sync {
+                sync_type: 7,
+                ..syncobj
+            }],
+        );
+
+        assert_eq!(
+            file::SyncItem::read_array(&mem, 0x1000, 2).unwrap().len(),
+            2
+        );
+        assert!(file::SyncItem::read_array(&mem, 0x1000, 3).is_err());
+        assert!(file::SyncItem::read_array(&mem, 0x2000, 1).is_err());
+        assert!(file::SyncItem::read_array(&mem, 0x3000, 1).is_err());
+    }
+
+    #[test]
+    fn render_command() {
+        let mut mem = HostMemory::default();
+        let cmdbuf = uapi::drm_asahi_cmd_render {
+            fb_width: 1920,
+            fb_height: 1080,
+            layers: 1,
+            samples: 1,
+            utile_width: 32,
+            utile_height: 32,
+            ..Default::default()
+        };
+        mem.map_items(0x1000, &[cmdbuf]);
+        mem.map_items(
+            0x2000,
+            &[uapi::drm_asahi_cmd_render {
+                flags: 1 << 63,
+                ..cmdbuf
+            }],
+        );
+        mem.map_items(
+            0x3000,
+            &[uapi::drm_asahi_cmd_render {
+                fb_width: 16385,
+                ..cmdbuf
+            }],
+        );
+        mem.map_items(
+            0x4000,
+            &[uapi::drm_asahi_cmd_render {
+                extensions: 0x8000,
+                ..cmdbuf
+            }],
+        );
+
+        let mut cmd = uapi::drm_asahi_command {
+            cmd_type: uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_RENDER,
+            cmd_buffer: 0x1000,
+            ..Default::default()
+        };
+        let (parsed, _) = render::parse_render_cmd(&mem, &cmd).unwrap();
+        assert_eq!(parsed.fb_width, 1920);
+
+        for (addr, err) in [(0x2000, EINVAL), (0x3000, EINVAL), (0x4000, EFAULT)] {
+            cmd.cmd_buffer = addr;
+            assert_eq!(render::parse_render_cmd(&mem, &cmd).err(), Some(err));
+        }
+
+        cmd.cmd_type = uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_COMPUTE;
+        cmd.cmd_buffer = 0x1000;
+        assert_eq!(render::parse_render_cmd(&mem, &cmd).err(), Some(EINVAL));
+    }
+}
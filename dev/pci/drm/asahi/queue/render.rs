@@ -12,20 +12,18 @@ use crate::alloc::Allocator;
 use crate::debug::*;
 use crate::fw::types::*;
 use crate::gpu::GpuManager;
+use crate::umem::{UserMemory, UserSpace};
 use crate::util::*;
 use crate::workqueue::WorkError;
-use crate::{buffer, fw, gpu, microseq, workqueue};
+use crate::{buffer, ext, fw, gpu, hw, microseq, trace, workqueue};
 use crate::{inner_ptr, inner_weak_ptr};
-use core::mem::MaybeUninit;
 use core::sync::atomic::Ordering;
 use kernel::dma_fence::RawDmaFence;
 use kernel::drm::sched::Job;
-use kernel::io_buffer::IoBufferReader;
 use kernel::new_mutex;
 use kernel::prelude::*;
 use kernel::sync::Arc;
 use kernel::uapi;
-use kernel::user_ptr::UserSlicePtr;
 
 const DEBUG_CLASS: DebugFlags = DebugFlags::Render;
 
@@ -67,244 +65,240 @@ impl RenderResult {
     }
 }
 
-#[versions(AGX)]
-impl super::Queue::ver {
-    /// Get the appropriate tiling parameters for a given userspace command buffer.
-    fn get_tiling_params(
-        cmdbuf: &uapi::drm_asahi_cmd_render,
-        num_clusters: u32,
-    ) -> Result<buffer::TileInfo> {
-        let width: u32 = cmdbuf.fb_width;
-        let height: u32 = cmdbuf.fb_height;
-        let layers: u32 = cmdbuf.layers;
-
-        if width > 65536 || height > 65536 {
-            cls_pr_debug!(Errors, "Framebuffer too large ({} x {})\n", width, height);
-            return Err(EINVAL);
-        }
+/// Get the appropriate tiling parameters for a given userspace command buffer.
+///
+/// `num_clusters` is the number of clusters the vertex work is spread across, or 1 if clustering
+/// is disabled for this command. This only depends on its arguments.
+///
+/// `num_clusters` must be between 1 and the chip's `max_num_clusters`, and the clustering-only
+/// outputs (`meta1_blocks` and the larger minimum TVB size) are only produced for chips with a
+/// clustering config. Neither check changes the result for `submit_render`, which passes the
+/// cluster count reported by the firmware: that is always in range, and only multi-cluster chips
+/// report more than one cluster, all of which have a clustering config. They keep the function
+/// total for other inputs instead of computing clustered sizes for chips that cannot cluster.
+pub(super) fn get_tiling_params(
+    cfg: &hw::HwConfig,
+    cmdbuf: &uapi::drm_asahi_cmd_render,
+    num_clusters: u32,
+) -> Result<buffer::TileInfo> {
+    let width: u32 = cmdbuf.fb_width;
+    let height: u32 = cmdbuf.fb_height;
+    let layers: u32 = cmdbuf.layers;
+
+    if width > 65536 || height > 65536 {
+        cls_pr_debug!(Errors, "Framebuffer too large ({} x {})\n", width, height);
+        return Err(EINVAL);
+    }
+
+    if layers == 0 || layers > 2048 {
+        cls_pr_debug!(Errors, "Layer count invalid ({})\n", layers);
+        return Err(EINVAL);
+    }
+
+    if num_clusters == 0 || num_clusters > cfg.max_num_clusters {
+        cls_pr_debug!(Errors, "Cluster count invalid ({})\n", num_clusters);
+        return Err(EINVAL);
+    }
+    let clustered = num_clusters > 1 && cfg.clustering.is_some();
+
+    let tile_width = 32u32;
+    let tile_height = 32u32;
 
-        if layers == 0 || layers > 2048 {
-            cls_pr_debug!(Errors, "Layer count invalid ({})\n", layers);
+    let utile_width = cmdbuf.utile_width;
+    let utile_height = cmdbuf.utile_height;
+
+    match (utile_width, utile_height) {
+        (32, 32) | (32, 16) | (16, 16) => (),
+        _ => {
+            cls_pr_debug!(
+                Errors,
+                "uTile size invalid ({} x {})\n",
+                utile_width,
+                utile_height
+            );
             return Err(EINVAL);
         }
+    };
+
+    let utiles_per_tile_x = tile_width / utile_width;
+    let utiles_per_tile_y = tile_height / utile_height;
+
+    let utiles_per_tile = utiles_per_tile_x * utiles_per_tile_y;
+
+    let tiles_x = (width + tile_width - 1) / tile_width;
+    let tiles_y = (height + tile_height - 1) / tile_height;
+    let tiles = tiles_x * tiles_y;
+
+    let mtiles_x = 4u32;
+    let mtiles_y = 4u32;
+    let mtiles = mtiles_x * mtiles_y;
+
+    let tiles_per_mtile_x = align(div_ceil(tiles_x, mtiles_x), 4);
+    let tiles_per_mtile_y = align(div_ceil(tiles_y, mtiles_y), 4);
+    let tiles_per_mtile = tiles_per_mtile_x * tiles_per_mtile_y;
+
+    let mtile_x1 = tiles_per_mtile_x;
+    let mtile_x2 = 2 * tiles_per_mtile_x;
+    let mtile_x3 = 3 * tiles_per_mtile_x;
+
+    let mtile_y1 = tiles_per_mtile_y;
+    let mtile_y2 = 2 * tiles_per_mtile_y;
+    let mtile_y3 = 3 * tiles_per_mtile_y;
+
+    let rgn_entry_size = 5;
+    // Macrotile stride in 32-bit words
+    let rgn_size = align(rgn_entry_size * tiles_per_mtile * utiles_per_tile, 4) / 4;
+    let tilemap_size = (4 * rgn_size * mtiles * layers) as usize;
+
+    let tpc_entry_size = 8;
+    // TPC stride in 32-bit words
+    let tpc_mtile_stride = tpc_entry_size * utiles_per_tile * tiles_per_mtile / 4;
+    let tpc_size = (num_clusters * (4 * tpc_mtile_stride * mtiles) * layers) as usize;
+
+    // No idea where this comes from, but it fits what macOS does...
+    // GUESS: Number of 32K heap blocks to fit a 5-byte region header/pointer per tile?
+    // That would make a ton of sense...
+    // TODO: Layers? Why the sample count factor here?
+    let meta1_blocks = if clustered {
+        div_ceil(
+            align(tiles_x, 2) * align(tiles_y, 4) * utiles_per_tile,
+            0x1980,
+        )
+    } else {
+        0
+    };
+
+    let mut min_tvb_blocks = align(div_ceil(tiles_x * tiles_y, 128), 8);
+
+    if clustered {
+        min_tvb_blocks = min_tvb_blocks.max(7 + 2 * layers);
+    }
 
-        let tile_width = 32u32;
-        let tile_height = 32u32;
+    Ok(buffer::TileInfo {
+        tiles_x,
+        tiles_y,
+        tiles,
+        utile_width,
+        utile_height,
+        //mtiles_x,
+        //mtiles_y,
+        tiles_per_mtile_x,
+        tiles_per_mtile_y,
+        //tiles_per_mtile,
+        utiles_per_mtile_x: tiles_per_mtile_x * utiles_per_tile_x,
+        utiles_per_mtile_y: tiles_per_mtile_y * utiles_per_tile_y,
+        //utiles_per_mtile: tiles_per_mtile * utiles_per_tile,
+        tilemap_size,
+        tpc_size,
+        meta1_blocks,
+        layermeta_size: if layers > 1 { 0x100 } else { 0 },
+        min_tvb_blocks: min_tvb_blocks as usize,
+        params: fw::vertex::raw::TilingParameters {
+            rgn_size,
+            unk_4: 0x88,
+            ppp_ctrl: cmdbuf.ppp_ctrl,
+            x_max: (width - 1) as u16,
+            y_max: (height - 1) as u16,
+            te_screen: ((tiles_y - 1) << 12) | (tiles_x - 1),
+            te_mtile1: mtile_x3 | (mtile_x2 << 9) | (mtile_x1 << 18),
+            te_mtile2: mtile_y3 | (mtile_y2 << 9) | (mtile_y1 << 18),
+            tiles_per_mtile,
+            tpc_stride: tpc_mtile_stride,
+            unk_24: 0x100,
+            unk_28: if layers > 1 {
+                0xe000 | (layers - 1)
+            } else {
+                0x8000
+            },
+            __pad: Default::default(),
+        },
+    })
+}
 
-        let utile_width = cmdbuf.utile_width;
-        let utile_height = cmdbuf.utile_height;
+/// Reads a render command buffer from `mem` and validates its flags, dimensions and extensions.
+pub(super) fn parse_render_cmd(
+    mem: &impl UserMemory,
+    cmd: &uapi::drm_asahi_command,
+) -> Result<(
+    uapi::drm_asahi_cmd_render,
+    uapi::drm_asahi_cmd_render_unknowns,
+)> {
+    if cmd.cmd_type != uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_RENDER {
+        cls_pr_debug!(Errors, "Not a render command ({})\n", cmd.cmd_type);
+        return Err(EINVAL);
+    }
 
-        match (utile_width, utile_height) {
-            (32, 32) | (32, 16) | (16, 16) => (),
-            _ => {
-                cls_pr_debug!(
-                    Errors,
-                    "uTile size invalid ({} x {})\n",
-                    utile_width,
-                    utile_height
-                );
-                return Err(EINVAL);
-            }
-        };
+    // SAFETY: drm_asahi_cmd_render is plain old data.
+    let cmdbuf: uapi::drm_asahi_cmd_render = unsafe { mem.read(cmd.cmd_buffer)? };
+
+    if cmdbuf.flags
+        & !(uapi::ASAHI_RENDER_NO_CLEAR_PIPELINE_TEXTURES
+            | uapi::ASAHI_RENDER_SET_WHEN_RELOADING_Z_OR_S
+            | uapi::ASAHI_RENDER_PROCESS_EMPTY_TILES
+            | uapi::ASAHI_RENDER_NO_VERTEX_CLUSTERING
+            | uapi::ASAHI_RENDER_MSAA_ZS
+            | uapi::ASAHI_RENDER_NO_PREEMPTION) as u64
+        != 0
+    {
+        cls_pr_debug!(Errors, "Invalid flags ({:#x})\n", cmdbuf.flags);
+        return Err(EINVAL);
+    }
 
-        let utiles_per_tile_x = tile_width / utile_width;
-        let utiles_per_tile_y = tile_height / utile_height;
-
-        let utiles_per_tile = utiles_per_tile_x * utiles_per_tile_y;
-
-        let tiles_x = (width + tile_width - 1) / tile_width;
-        let tiles_y = (height + tile_height - 1) / tile_height;
-        let tiles = tiles_x * tiles_y;
-
-        let mtiles_x = 4u32;
-        let mtiles_y = 4u32;
-        let mtiles = mtiles_x * mtiles_y;
-
-        let tiles_per_mtile_x = align(div_ceil(tiles_x, mtiles_x), 4);
-        let tiles_per_mtile_y = align(div_ceil(tiles_y, mtiles_y), 4);
-        let tiles_per_mtile = tiles_per_mtile_x * tiles_per_mtile_y;
-
-        let mtile_x1 = tiles_per_mtile_x;
-        let mtile_x2 = 2 * tiles_per_mtile_x;
-        let mtile_x3 = 3 * tiles_per_mtile_x;
-
-        let mtile_y1 = tiles_per_mtile_y;
-        let mtile_y2 = 2 * tiles_per_mtile_y;
-        let mtile_y3 = 3 * tiles_per_mtile_y;
-
-        let rgn_entry_size = 5;
-        // Macrotile stride in 32-bit words
-        let rgn_size = align(rgn_entry_size * tiles_per_mtile * utiles_per_tile, 4) / 4;
-        let tilemap_size = (4 * rgn_size * mtiles * layers) as usize;
-
-        let tpc_entry_size = 8;
-        // TPC stride in 32-bit words
-        let tpc_mtile_stride = tpc_entry_size * utiles_per_tile * tiles_per_mtile / 4;
-        let tpc_size = (num_clusters * (4 * tpc_mtile_stride * mtiles) * layers) as usize;
-
-        // No idea where this comes from, but it fits what macOS does...
-        // GUESS: Number of 32K heap blocks to fit a 5-byte region header/pointer per tile?
-        // That would make a ton of sense...
-        // TODO: Layers? Why the sample count factor here?
-        let meta1_blocks = if num_clusters > 1 {
-            div_ceil(
-                align(tiles_x, 2) * align(tiles_y, 4) * utiles_per_tile,
-                0x1980,
-            )
-        } else {
-            0
-        };
+    if cmdbuf.fb_width == 0
+        || cmdbuf.fb_height == 0
+        || cmdbuf.fb_width > 16384
+        || cmdbuf.fb_height > 16384
+    {
+        cls_pr_debug!(
+            Errors,
+            "Invalid dimensions ({}x{})\n",
+            cmdbuf.fb_width,
+            cmdbuf.fb_height
+        );
+        return Err(EINVAL);
+    }
 
-        let mut min_tvb_blocks = align(div_ceil(tiles_x * tiles_y, 128), 8);
+    let mut unks: uapi::drm_asahi_cmd_render_unknowns = Default::default();
 
-        if num_clusters > 1 {
-            min_tvb_blocks = min_tvb_blocks.max(7 + 2 * layers);
+    ext::walk_in(mem, cmdbuf.extensions, |ext| match ext.ext_type() {
+        uapi::ASAHI_RENDER_EXT_UNKNOWNS => {
+            if !debug_enabled(debug::DebugFlags::AllowUnknownOverrides) {
+                cls_pr_debug!(Errors, "Overrides not enabled\n");
+                return Err(EINVAL);
+            }
+            // SAFETY: drm_asahi_cmd_render_unknowns is plain old data.
+            unks = unsafe { ext.read()? };
+            Ok(())
+        }
+        ext_type => {
+            cls_pr_debug!(Errors, "Unknown extension {}\n", ext_type);
+            Err(EINVAL)
         }
+    })?;
 
-        Ok(buffer::TileInfo {
-            tiles_x,
-            tiles_y,
-            tiles,
-            utile_width,
-            utile_height,
-            //mtiles_x,
-            //mtiles_y,
-            tiles_per_mtile_x,
-            tiles_per_mtile_y,
-            //tiles_per_mtile,
-            utiles_per_mtile_x: tiles_per_mtile_x * utiles_per_tile_x,
-            utiles_per_mtile_y: tiles_per_mtile_y * utiles_per_tile_y,
-            //utiles_per_mtile: tiles_per_mtile * utiles_per_tile,
-            tilemap_size,
-            tpc_size,
-            meta1_blocks,
-            layermeta_size: if layers > 1 { 0x100 } else { 0 },
-            min_tvb_blocks: min_tvb_blocks as usize,
-            params: fw::vertex::raw::TilingParameters {
-                rgn_size,
-                unk_4: 0x88,
-                ppp_ctrl: cmdbuf.ppp_ctrl,
-                x_max: (width - 1) as u16,
-                y_max: (height - 1) as u16,
-                te_screen: ((tiles_y - 1) << 12) | (tiles_x - 1),
-                te_mtile1: mtile_x3 | (mtile_x2 << 9) | (mtile_x1 << 18),
-                te_mtile2: mtile_y3 | (mtile_y2 << 9) | (mtile_y1 << 18),
-                tiles_per_mtile,
-                tpc_stride: tpc_mtile_stride,
-                unk_24: 0x100,
-                unk_28: if layers > 1 {
-                    0xe000 | (layers - 1)
-                } else {
-                    0x8000
-                },
-                __pad: Default::default(),
-            },
-        })
+    if unks.pad != 0 {
+        cls_pr_debug!(Errors, "Nonzero unks.pad: {}\n", unks.pad);
+        return Err(EINVAL);
     }
 
+    Ok((cmdbuf, unks))
+}
+
+#[versions(AGX)]
+impl super::Queue::ver {
     /// Submit work to a render queue.
     pub(super) fn submit_render(
         &self,
         job: &mut Job<super::QueueJob::ver>,
         cmd: &uapi::drm_asahi_command,
         result_writer: Option<super::ResultWriter>,
+        user_timestamps: super::timestamp::CommandTimestamps,
         id: u64,
         flush_stamps: bool,
     ) -> Result {
-        if cmd.cmd_type != uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_RENDER {
-            cls_pr_debug!(Errors, "Not a render command ({})\n", cmd.cmd_type);
-            return Err(EINVAL);
-        }
-
         mod_dev_dbg!(self.dev, "[Submission {}] Render!\n", id);
 
-        let mut cmdbuf_reader = unsafe {
-            UserSlicePtr::new(
-                cmd.cmd_buffer as usize as *mut _,
-                core::mem::size_of::<uapi::drm_asahi_cmd_render>(),
-            )
-            .reader()
-        };
-
-        let mut cmdbuf: MaybeUninit<uapi::drm_asahi_cmd_render> = MaybeUninit::uninit();
-        unsafe {
-            cmdbuf_reader.read_raw(
-                cmdbuf.as_mut_ptr() as *mut u8,
-                core::mem::size_of::<uapi::drm_asahi_cmd_render>(),
-            )?;
-        }
-        let cmdbuf = unsafe { cmdbuf.assume_init() };
-
-        if cmdbuf.flags
-            & !(uapi::ASAHI_RENDER_NO_CLEAR_PIPELINE_TEXTURES
-                | uapi::ASAHI_RENDER_SET_WHEN_RELOADING_Z_OR_S
-                | uapi::ASAHI_RENDER_PROCESS_EMPTY_TILES
-                | uapi::ASAHI_RENDER_NO_VERTEX_CLUSTERING
-                | uapi::ASAHI_RENDER_MSAA_ZS) as u64
-            != 0
-        {
-            cls_pr_debug!(Errors, "Invalid flags ({:#x})\n", cmdbuf.flags);
-            return Err(EINVAL);
-        }
-
-        if cmdbuf.fb_width == 0
-            || cmdbuf.fb_height == 0
-            || cmdbuf.fb_width > 16384
-            || cmdbuf.fb_height > 16384
-        {
-            cls_pr_debug!(
-                Errors,
-                "Invalid dimensions ({}x{})\n",
-                cmdbuf.fb_width,
-                cmdbuf.fb_height
-            );
-            return Err(EINVAL);
-        }
-
-        let mut unks: uapi::drm_asahi_cmd_render_unknowns = Default::default();
-
-        let mut ext_ptr = cmdbuf.extensions;
-        while ext_ptr != 0 {
-            let ext_type = u32::from_ne_bytes(
-                unsafe { UserSlicePtr::new(ext_ptr as usize as *mut _, 4) }
-                    .read_all()?
-                    .try_into()
-                    .or(Err(EINVAL))?,
-            );
-
-            match ext_type {
-                uapi::ASAHI_RENDER_EXT_UNKNOWNS => {
-                    if !debug_enabled(debug::DebugFlags::AllowUnknownOverrides) {
-                        cls_pr_debug!(Errors, "Overrides not enabled\n");
-                        return Err(EINVAL);
-                    }
-                    let mut ext_reader = unsafe {
-                        UserSlicePtr::new(
-                            ext_ptr as usize as *mut _,
-                            core::mem::size_of::<uapi::drm_asahi_cmd_render_unknowns>(),
-                        )
-                        .reader()
-                    };
-                    unsafe {
-                        ext_reader.read_raw(
-                            &mut unks as *mut _ as *mut u8,
-                            core::mem::size_of::<uapi::drm_asahi_cmd_render_unknowns>(),
-                        )?;
-                    }
-
-                    ext_ptr = unks.next;
-                }
-                _ => {
-                    cls_pr_debug!(Errors, "Unknown extension {}\n", ext_type);
-                    return Err(EINVAL);
-                }
-            }
-        }
-
-        if unks.pad != 0 {
-            cls_pr_debug!(Errors, "Nonzero unks.pad: {}\n", unks.pad);
-            return Err(EINVAL);
-        }
+        let (cmdbuf, unks) = parse_render_cmd(&UserSpace, cmd)?;
 
         let dev = self.dev.data();
         let gpu = match dev.gpu.as_any().downcast_ref::<gpu::GpuManager::ver>() {
@@ -345,7 +339,11 @@ impl super::Queue::ver {
         // but it's unclear *which* slot...
         let slot_client_seq: u8 = (self.id & 0xff) as u8;
 
-        let tile_info = Self::get_tiling_params(&cmdbuf, if clustering { nclusters } else { 1 })?;
+        let tile_info = get_tiling_params(
+            gpu.get_cfg(),
+            &cmdbuf,
+            if clustering { nclusters } else { 1 },
+        )?;
 
         let buffer = self.buffer.as_ref().ok_or_else(|| {
             cls_pr_debug!(Errors, "Failed to get buffer\n");
@@ -384,6 +382,9 @@ impl super::Queue::ver {
         let scene = Arc::try_new(buffer.new_scene(kalloc, &tile_info)?)?;
 
         let vm_bind = job.vm_bind.clone();
+        let vtx_trace_tag = job.trace.pipe(trace::PIPE_VERTEX);
+        let frag_trace_tag = job.trace.pipe(trace::PIPE_FRAGMENT);
+        let trace_timestamps = trace::enabled();
 
         mod_dev_dbg!(
             self.dev,
@@ -603,7 +604,8 @@ impl super::Queue::ver {
         let frag = GpuObject::new_init_prealloc(
             kalloc.gpu_ro.alloc_object()?,
             |ptr: GpuWeakPointer<fw::fragment::RunFragment::ver>| {
-                let has_result = frag_result.is_some();
+                let has_timestamps =
+                    frag_result.is_some() || trace_timestamps || user_timestamps.end.is_some();
                 let scene = scene.clone();
                 let notifier = notifier.clone();
                 let vm_bind = vm_bind.clone();
@@ -653,6 +655,7 @@ impl super::Queue::ver {
                             unk_84: unk1.into(),
                             uuid: uuid_3d,
                             attachments: common::build_attachments(
+                                &UserSpace,
                                 cmdbuf.fragment_attachments,
                                 cmdbuf.fragment_attachment_count,
                             )?,
@@ -663,7 +666,7 @@ impl super::Queue::ver {
                             notifier_buf: inner_weak_ptr!(notifier.weak_pointer(), state.unk_buf),
                         })?;
 
-                        if has_result {
+                        if has_timestamps {
                             builder.add(microseq::Timestamp::ver {
                                 header: microseq::op::Timestamp::new(true),
                                 cur_ts: inner_weak_ptr!(ptr, cur_ts),
@@ -687,7 +690,7 @@ impl super::Queue::ver {
                             header: microseq::op::WaitForIdle2::HEADER,
                         })?;
 
-                        if has_result {
+                        if has_timestamps {
                             builder.add(microseq::Timestamp::ver {
                                 header: microseq::op::Timestamp::new(false),
                                 cur_ts: inner_weak_ptr!(ptr, cur_ts),
@@ -1066,10 +1069,10 @@ impl super::Queue::ver {
                         start_addr: Some(inner_ptr!(inner.timestamps.gpu_pointer(), frag.start)),
                         end_addr: Some(inner_ptr!(inner.timestamps.gpu_pointer(), frag.end)),
                     }),
-                    user_timestamp_pointers <- try_init!(fw::job::raw::TimestampPointers {
-                        start_addr: None,
-                        end_addr: None,
-                    }),
+                    user_timestamp_pointers: fw::job::raw::UserTimestampPointers {
+                        start_addr: U64(0),
+                        end_addr: user_timestamps.end_addr(),
+                    },
                     client_sequence: slot_client_seq,
                     pad_925: Default::default(),
                     unk_928: 0,
@@ -1085,7 +1088,19 @@ impl super::Queue::ver {
         mod_dev_dbg!(self.dev, "[Submission {}] Add Frag\n", id);
         fence.add_command();
 
+        let trace_dev = self.dev.clone();
         frag_job.add_cb(frag, vm_bind.slot(), move |cmd, error| {
+            if trace_timestamps {
+                cmd.timestamps.with(|raw, _inner| {
+                    trace_dev.data().gpu.trace().record_fw(
+                        frag_trace_tag,
+                        raw.frag.start.load(Ordering::Relaxed),
+                        raw.frag.end.load(Ordering::Relaxed),
+                    );
+                });
+            }
+            // The firmware is done writing to the user timestamp buffers
+            core::mem::drop(user_timestamps);
             if let Some(err) = error {
                 fence.set_error(err.into());
             }
@@ -1136,7 +1151,8 @@ impl super::Queue::ver {
         let vtx = GpuObject::new_init_prealloc(
             kalloc.gpu_ro.alloc_object()?,
             |ptr: GpuWeakPointer<fw::vertex::RunVertex::ver>| {
-                let has_result = vtx_result.is_some();
+                let has_timestamps =
+                    vtx_result.is_some() || trace_timestamps || user_timestamps.start.is_some();
                 let scene = scene.clone();
                 let vm_bind = vm_bind.clone();
                 let timestamps = timestamps.clone();
@@ -1179,6 +1195,7 @@ impl super::Queue::ver {
                             unk_68: unk1.into(),
                             uuid: uuid_ta,
                             attachments: common::build_attachments(
+                                &UserSpace,
                                 cmdbuf.vertex_attachments,
                                 cmdbuf.vertex_attachment_count,
                             )?,
@@ -1193,7 +1210,7 @@ impl super::Queue::ver {
                             unk_178: (!clustering) as u32,
                         })?;
 
-                        if has_result {
+                        if has_timestamps {
                             builder.add(microseq::Timestamp::ver {
                                 header: microseq::op::Timestamp::new(true),
                                 cur_ts: inner_weak_ptr!(ptr, cur_ts),
@@ -1217,7 +1234,7 @@ impl super::Queue::ver {
                             header: microseq::op::WaitForIdle2::HEADER,
                         })?;
 
-                        if has_result {
+                        if has_timestamps {
                             builder.add(microseq::Timestamp::ver {
                                 header: microseq::op::Timestamp::new(false),
                                 cur_ts: inner_weak_ptr!(ptr, cur_ts),
@@ -1522,10 +1539,10 @@ impl super::Queue::ver {
                         start_addr: Some(inner_ptr!(inner.timestamps.gpu_pointer(), vtx.start)),
                         end_addr: Some(inner_ptr!(inner.timestamps.gpu_pointer(), vtx.end)),
                     }),
-                    user_timestamp_pointers <- try_init!(fw::job::raw::TimestampPointers {
-                        start_addr: None,
-                        end_addr: None,
-                    }),
+                    user_timestamp_pointers: fw::job::raw::UserTimestampPointers {
+                        start_addr: user_timestamps.start_addr(),
+                        end_addr: U64(0),
+                    },
                     client_sequence: slot_client_seq,
                     pad_5d5: Default::default(),
                     unk_5d8: 0,
@@ -1542,7 +1559,17 @@ impl super::Queue::ver {
 
         mod_dev_dbg!(self.dev, "[Submission {}] Add Vertex\n", id);
         fence.add_command();
+        let trace_dev = self.dev.clone();
         vtx_job.add_cb(vtx, vm_bind.slot(), move |cmd, error| {
+            if trace_timestamps {
+                cmd.timestamps.with(|raw, _inner| {
+                    trace_dev.data().gpu.trace().record_fw(
+                        vtx_trace_tag,
+                        raw.vtx.start.load(Ordering::Relaxed),
+                        raw.vtx.end.load(Ordering::Relaxed),
+                    );
+                });
+            }
             if let Some(err) = error {
                 fence.set_error(err.into())
             }
@@ -1574,6 +1601,9 @@ impl super::Queue::ver {
         job.get_vtx()?.next_seq();
         job.get_frag()?.next_seq();
 
+        gpu.preemption_stats()
+            .record_render(cmdbuf.flags & uapi::ASAHI_RENDER_NO_PREEMPTION as u64 != 0);
+
         Ok(())
     }
 }
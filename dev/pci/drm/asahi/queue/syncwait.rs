@@ -0,0 +1,186 @@
+// SPDX-License-Identifier: GPL-2.0-only OR MIT
+
+//! Wait-before-signal support for timeline sync objects.
+//!
+//! Userspace may submit a job that waits on a timeline point before the job that signals that
+//! point has been submitted (Vulkan timeline semaphores do this routinely). Such jobs are held in
+//! the scheduler behind a `PointFence`, which a syncobj wait callback signals once the point gets
+//! a fence. Once a point fence has signaled, `QueueJob::prepare()` swaps it for the real fence of
+//! the point. A `SyncWaitTimeout` bounds how long a job can be held like this.
+//!
+//! Each `PointFence` gets its own fence context. The scheduler only keeps the latest fence of each
+//! context as a dependency, which would otherwise make a job wait for just one of its points.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use kernel::c_str;
+use kernel::dma_fence::{self, Fence, FenceContexts, FenceObject, RawDmaFence, UserFence};
+use kernel::drm::syncobj::{PointWait, PointWaitCallback, SyncObj};
+use kernel::prelude::*;
+use kernel::sync::{Arc, LockClassKey};
+use kernel::workqueue::{DelayedWork, HasDelayedWork, Queue, WorkItem};
+
+/// Default and maximum time a job may be held waiting for timeline points to be submitted. This
+/// matches the DRM core's timeout for `DRM_SYNCOBJ_WAIT_FLAGS_WAIT_FOR_SUBMIT`.
+pub(crate) const MAX_SYNC_WAIT: Duration = Duration::from_secs(5);
+
+/// Fence signaled once a timeline point has been submitted, or with an error on timeout.
+#[derive(Default)]
+pub(crate) struct PointFence {
+    done: AtomicBool,
+    timed_out: AtomicBool,
+}
+
+impl PointFence {
+    /// Signal the fence. Only the first call has any effect.
+    fn complete(self: &FenceObject<Self>, timed_out: bool) {
+        if self.done.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        if timed_out {
+            self.timed_out.store(true, Ordering::Release);
+            self.set_error(ETIMEDOUT);
+        }
+        if self.signal().is_err() {
+            pr_err!("PointFence: Fence signal failed\n");
+        }
+    }
+
+    /// Returns whether the point was submitted or timed out.
+    fn is_done(self: &FenceObject<Self>) -> bool {
+        self.done.load(Ordering::Acquire)
+    }
+
+    /// Returns whether the point was submitted before the timeout.
+    fn available(self: &FenceObject<Self>) -> bool {
+        self.is_done() && !self.timed_out.load(Ordering::Acquire)
+    }
+}
+
+static POINT_FENCE_NAME: &CStr = c_str!("asahi_syncwait");
+static POINT_FENCE_CLASS_KEY: LockClassKey = kernel::static_lock_class!();
+
+#[vtable]
+impl dma_fence::FenceOps for PointFence {
+    const USE_64BIT_SEQNO: bool = true;
+
+    fn get_driver_name<'a>(self: &'a FenceObject<Self>) -> &'a CStr {
+        c_str!("asahi")
+    }
+    fn get_timeline_name<'a>(self: &'a FenceObject<Self>) -> &'a CStr {
+        c_str!("syncwait")
+    }
+}
+
+/// Syncobj wait callback that signals a `PointFence`.
+struct PointWaiter(UserFence<PointFence>);
+
+impl PointWaitCallback for PointWaiter {
+    fn point_available(&self) {
+        self.0.complete(false);
+    }
+}
+
+/// A timeline point that a job waits on, which had not been submitted when the job was.
+pub(crate) struct PendingSync {
+    syncobj: SyncObj,
+    point: u64,
+    fence: UserFence<PointFence>,
+    _wait: Option<Pin<Box<PointWait<PointWaiter>>>>,
+}
+
+impl PendingSync {
+    /// Start waiting for `point` of `syncobj` to be submitted.
+    pub(crate) fn new(syncobj: SyncObj, point: u64) -> Result<PendingSync> {
+        let fence_ctx = FenceContexts::new(1, POINT_FENCE_NAME, POINT_FENCE_CLASS_KEY)?;
+        let fence: UserFence<PointFence> = fence_ctx.new_fence(0, Default::default())?.into();
+        let wait = syncobj.wait_for_point(point, PointWaiter(fence.clone()))?;
+
+        // The point may have been submitted since the in_syncs were parsed.
+        if wait.is_none() {
+            fence.complete(false);
+        }
+
+        Ok(PendingSync {
+            syncobj,
+            point,
+            fence,
+            _wait: wait,
+        })
+    }
+
+    /// Returns the fence the job should depend on until the point is submitted.
+    pub(crate) fn point_fence(&self) -> UserFence<PointFence> {
+        self.fence.clone()
+    }
+
+    /// Returns whether the point has neither been submitted nor timed out yet.
+    pub(crate) fn is_pending(&self) -> bool {
+        !self.fence.is_done()
+    }
+
+    /// Returns the fence for the submitted point, `None` if it has already signaled, or
+    /// `ETIMEDOUT` if it was not submitted in time. Must only be called once the point is no
+    /// longer pending.
+    pub(crate) fn resolve(&self) -> Result<Option<Fence>> {
+        match self
+            .syncobj
+            .fence_get()
+            .map(|fence| fence.chain_find_seqno(self.point))
+        {
+            Some(Ok(fence)) => Ok(Some(fence)),
+            // The point was submitted, but has since signaled and been dropped from the chain.
+            _ if self.fence.available() => Ok(None),
+            _ => Err(ETIMEDOUT),
+        }
+    }
+}
+
+/// Fails the `PointFence`s of a job if they are still pending once its wait bound expires.
+///
+/// The owning job cancels the timeout when it is prepared or dropped, so it never fires after the
+/// job (and the device reference that keeps the driver task queue alive) is gone.
+#[pin_data]
+pub(crate) struct SyncWaitTimeout {
+    fences: Vec<UserFence<PointFence>>,
+    #[pin]
+    work: DelayedWork<SyncWaitTimeout>,
+}
+
+impl SyncWaitTimeout {
+    /// Arm the timeout for the given point fences, to run on `queue`.
+    pub(crate) fn start(
+        queue: &Queue,
+        fences: Vec<UserFence<PointFence>>,
+        timeout: Duration,
+    ) -> Result<Arc<SyncWaitTimeout>> {
+        let this = Arc::pin_init(pin_init!(SyncWaitTimeout {
+            fences,
+            work <- DelayedWork::new(),
+        }))?;
+
+        queue.enqueue_delayed(this.clone(), timeout);
+
+        Ok(this)
+    }
+
+    /// Disarm the timeout once it is no longer needed.
+    pub(crate) fn cancel(this: &Arc<SyncWaitTimeout>) {
+        DelayedWork::cancel(this);
+    }
+}
+
+impl WorkItem for SyncWaitTimeout {
+    fn run(this: Arc<Self>) {
+        for fence in this.fences.iter() {
+            fence.complete(true);
+        }
+    }
+}
+
+impl HasDelayedWork for SyncWaitTimeout {
+    fn delayed_work(&self) -> &DelayedWork<Self> {
+        &self.work
+    }
+}
@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: GPL-2.0-only OR MIT
+
+//! Timestamp queries.
+//!
+//! A timestamp command does not become a firmware command of its own. Instead, it is recorded by
+//! the `Timestamp` microsequence op at the end of the previous render or compute command on the
+//! same subqueue, after that command waits for idle, or at the start of the next one if the
+//! submission starts the subqueue with a timestamp. A timestamp command with barriers is always
+//! recorded at the start of the next command, and its barriers are added to that command's, so
+//! the firmware waits on them before taking the timestamp. For render commands, the start is the
+//! start of the vertex work and the end is the end of the fragment work.
+//!
+//! The firmware writes the value directly into the user's timestamp buffer through the command's
+//! `user_timestamp_pointers`. Only the page holding the timestamp is mapped for the firmware, and
+//! only until the command completes. Each client can have at most
+//! [`file::MAX_TIMESTAMPS_IN_FLIGHT`] such pages mapped at once, so that it cannot exhaust the
+//! firmware VA range reserved for them.
+
+use crate::debug::*;
+use crate::file::{self, DrmFile};
+use crate::fw::types::*;
+use crate::gem;
+use crate::mmu;
+use crate::umem::{UserMemory, UserSpace};
+use core::sync::atomic::{AtomicU32, Ordering};
+use kernel::prelude::*;
+use kernel::sync::Arc;
+use kernel::uapi;
+
+const DEBUG_CLASS: DebugFlags = DebugFlags::Queue;
+
+/// A parsed timestamp command.
+pub(crate) struct TimestampCommand {
+    pub(super) subqueue: usize,
+    obj: gem::ObjectRef,
+    offset: usize,
+    quota: Quota,
+}
+
+impl TimestampCommand {
+    /// Validates a timestamp command, looks up its timestamp buffer and reserves a timestamp
+    /// mapping for it.
+    pub(crate) fn parse(file: &DrmFile, cmd: &uapi::drm_asahi_command) -> Result<TimestampCommand> {
+        if cmd.result_offset != 0 || cmd.result_size != 0 {
+            cls_pr_debug!(Errors, "Timestamp commands cannot have results\n");
+            return Err(EINVAL);
+        }
+
+        // SAFETY: drm_asahi_cmd_timestamp is plain old data.
+        let cmdbuf: uapi::drm_asahi_cmd_timestamp = unsafe { UserSpace.read(cmd.cmd_buffer)? };
+
+        if cmdbuf.flags != 0 {
+            cls_pr_debug!(Errors, "Invalid timestamp command flags\n");
+            return Err(EINVAL);
+        }
+
+        let subqueue = match cmdbuf.subqueue {
+            uapi::drm_asahi_subqueue_DRM_ASAHI_SUBQUEUE_RENDER
+            | uapi::drm_asahi_subqueue_DRM_ASAHI_SUBQUEUE_COMPUTE => cmdbuf.subqueue as usize,
+            sq => {
+                cls_pr_debug!(Errors, "Invalid timestamp subqueue {}\n", sq);
+                return Err(EINVAL);
+            }
+        };
+
+        let obj = gem::lookup_handle(file, cmdbuf.handle)?;
+
+        let size = core::mem::size_of::<u64>() as u64;
+        if cmdbuf.offset % size != 0
+            || cmdbuf
+                .offset
+                .checked_add(size)
+                .map_or(true, |end| end > obj.size() as u64)
+        {
+            cls_pr_debug!(Errors, "Invalid timestamp offset {:#x}\n", cmdbuf.offset);
+            return Err(EINVAL);
+        }
+
+        Ok(TimestampCommand {
+            subqueue,
+            obj,
+            offset: cmdbuf.offset as usize,
+            quota: Quota::reserve(file.inner().timestamps())?,
+        })
+    }
+
+    /// Maps the page of the timestamp buffer holding the timestamp for the firmware with
+    /// `map_page`, which is given the buffer and the offset into it.
+    pub(super) fn map(
+        self,
+        map_page: impl FnOnce(&gem::ObjectRef, usize) -> Result<mmu::Mapping>,
+    ) -> Result<UserTimestamp> {
+        let mapping = map_page(&self.obj, self.offset)?;
+        let addr = (mapping.iova() + (self.offset & mmu::UAT_PGMSK)) as u64;
+
+        Ok(UserTimestamp {
+            _mapping: mapping,
+            _quota: self.quota,
+            _obj: self.obj,
+            addr,
+        })
+    }
+}
+
+/// A reservation of one of a client's timestamp mappings, released when dropped.
+struct Quota(Arc<AtomicU32>);
+
+impl Quota {
+    fn reserve(count: &Arc<AtomicU32>) -> Result<Quota> {
+        count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < file::MAX_TIMESTAMPS_IN_FLIGHT).then_some(n + 1)
+            })
+            .map_err(|_| {
+                cls_pr_debug!(Errors, "Too many timestamps in flight\n");
+                EBUSY
+            })?;
+
+        Ok(Quota(count.clone()))
+    }
+}
+
+impl Drop for Quota {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A timestamp buffer slot that the firmware writes a timestamp to.
+///
+/// Dropping this unmaps the slot from the firmware, so it must be kept until the command
+/// completes.
+pub(super) struct UserTimestamp {
+    // Field order matters: the page must be unmapped before the buffer can go away.
+    _mapping: mmu::Mapping,
+    _quota: Quota,
+    _obj: gem::ObjectRef,
+    addr: u64,
+}
+
+/// The user timestamps recorded at the start and end of a command.
+#[derive(Default)]
+pub(super) struct CommandTimestamps {
+    pub(super) start: Option<UserTimestamp>,
+    pub(super) end: Option<UserTimestamp>,
+}
+
+impl CommandTimestamps {
+    /// Returns the firmware VA of the start timestamp, or zero if there is none.
+    pub(super) fn start_addr(&self) -> U64 {
+        U64(self.start.as_ref().map_or(0, |ts| ts.addr))
+    }
+
+    /// Returns the firmware VA of the end timestamp, or zero if there is none.
+    pub(super) fn end_addr(&self) -> U64 {
+        U64(self.end.as_ref().map_or(0, |ts| ts.addr))
+    }
+}
@@ -8,7 +8,7 @@
 //! information, and starting the GPU firmware coprocessor.
 
 use crate::hw;
-use kernel::{device, io_mem::IoMem, platform, prelude::*};
+use kernel::{c_str, device, device::RawDevice, io_mem::IoMem, platform, prelude::*};
 
 /// Size of the ASC control MMIO region.
 pub(crate) const ASC_CTL_SIZE: usize = 0x4000;
@@ -150,13 +150,45 @@ pub(crate) struct Resources {
 impl Resources {
     /// Map the required resources given our platform device.
     pub(crate) fn new(pdev: &mut platform::Device) -> Result<Resources> {
-        // TODO: add device abstraction to ioremap by name
-        let asc_res = unsafe { pdev.ioremap_resource(0)? };
-        let sgx_res = unsafe { pdev.ioremap_resource(1)? };
+        let node = pdev.of_node().ok_or(EINVAL)?;
+        let dev = device::Device::from_dev(pdev);
+
+        // Older device trees have no `reg-names` and list the regions in a fixed order.
+        let has_names = node.find_property(c_str!("reg-names")).is_some();
+
+        let region = |name: &CStr, fallback: usize, min_size: usize| -> Result<usize> {
+            let found = if has_names {
+                node.reg_by_name(name)
+            } else {
+                node.reg()
+                    .and_then(|regs| Ok((fallback, *regs.get(fallback).ok_or(ENOENT)?)))
+            };
+            let (index, reg) = found.map_err(|e| {
+                dev_err!(dev, "Missing {} MMIO region: {:?}\n", name, e);
+                e
+            })?;
+            if reg.size < min_size as u64 {
+                dev_err!(
+                    dev,
+                    "{} MMIO region too small ({:#x} < {:#x})\n",
+                    name,
+                    reg.size,
+                    min_size
+                );
+                return Err(EINVAL);
+            }
+            Ok(index)
+        };
+
+        let asc_index = region(c_str!("asc"), 0, ASC_CTL_SIZE)?;
+        let sgx_index = region(c_str!("sgx"), 1, SGX_SIZE)?;
+
+        let asc_res = unsafe { pdev.ioremap_resource(asc_index)? };
+        let sgx_res = unsafe { pdev.ioremap_resource(sgx_index)? };
 
         Ok(Resources {
             // SAFETY: This device does DMA via the UAT IOMMU.
-            dev: device::Device::from_dev(pdev),
+            dev,
             asc: asc_res,
             sgx: sgx_res,
         })
@@ -344,15 +376,26 @@ impl Resources {
         })
     }
 
+    fn read_fault_info(&self, cfg: &'static hw::HwConfig) -> u64 {
+        if cfg.gpu_core as u32 >= hw::GpuCore::G14S as u32 {
+            self.sgx_read64(FAULT_INFO_G14X)
+        } else {
+            self.sgx_read64(FAULT_INFO)
+        }
+    }
+
+    /// Check whether an MMU fault is latched, without decoding it.
+    ///
+    /// This only reads one register and is safe to call from interrupt context.
+    pub(crate) fn fault_pending(&self, cfg: &'static hw::HwConfig) -> bool {
+        self.read_fault_info(cfg) & 1 != 0
+    }
+
     /// Get the fault information from the MMU status register, if one occurred.
     pub(crate) fn get_fault_info(&self, cfg: &'static hw::HwConfig) -> Option<FaultInfo> {
         let g14x = cfg.gpu_core as u32 >= hw::GpuCore::G14S as u32;
 
-        let fault_info = if g14x {
-            self.sgx_read64(FAULT_INFO_G14X)
-        } else {
-            self.sgx_read64(FAULT_INFO)
-        };
+        let fault_info = self.read_fault_info(cfg);
 
         if fault_info & 1 == 0 {
             return None;
@@ -0,0 +1,263 @@
+// SPDX-License-Identifier: GPL-2.0-only OR MIT
+
+//! Submission tracing
+//!
+//! When `DebugFlags::TraceSubmissions` is set, the driver records a structured event at each
+//! point a job passes through on its way to the GPU and back: the submit ioctl, dependency
+//! resolution in the scheduler, the run callback, the firmware start/end timestamps of each
+//! command and completion processing. Events are kept in a fixed-size device-wide ring, with the
+//! oldest events being overwritten, and clients read back their own events with the
+//! `TRACE_READ` ioctl, either as raw events or as Chrome trace event JSON. All timestamps are in
+//! the GPU timer domain, so CPU and firmware events line up on one timeline.
+
+use crate::debug::*;
+use crate::mem;
+use core::fmt::{self, Write};
+use kernel::prelude::*;
+use kernel::sync::Mutex;
+use kernel::{c_str, uapi};
+
+/// Number of events kept in the ring.
+const RING_SIZE: usize = 4096;
+
+/// Pipe value for events that apply to a whole job.
+pub(crate) const PIPE_JOB: u32 = uapi::drm_asahi_trace_pipe_DRM_ASAHI_TRACE_PIPE_JOB;
+/// Pipe value for events of the vertex half of render commands.
+pub(crate) const PIPE_VERTEX: u32 = uapi::drm_asahi_trace_pipe_DRM_ASAHI_TRACE_PIPE_VERTEX;
+/// Pipe value for events of the fragment half of render commands.
+pub(crate) const PIPE_FRAGMENT: u32 = uapi::drm_asahi_trace_pipe_DRM_ASAHI_TRACE_PIPE_FRAGMENT;
+/// Pipe value for events of compute commands.
+pub(crate) const PIPE_COMPUTE: u32 = uapi::drm_asahi_trace_pipe_DRM_ASAHI_TRACE_PIPE_COMPUTE;
+
+/// Returns whether submission tracing is enabled.
+#[inline(always)]
+pub(crate) fn enabled() -> bool {
+    debug_enabled(DebugFlags::TraceSubmissions)
+}
+
+/// Identifies the job (and optionally firmware pipe) an event belongs to.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Tag {
+    pub(crate) file_id: u64,
+    pub(crate) queue_id: u64,
+    pub(crate) submission_id: u64,
+    pub(crate) pipe: u32,
+}
+
+impl Tag {
+    /// Returns a copy of this tag for a specific firmware pipe.
+    pub(crate) fn pipe(self, pipe: u32) -> Tag {
+        Tag { pipe, ..self }
+    }
+}
+
+#[derive(Default)]
+struct RingInner {
+    events: Vec<uapi::drm_asahi_trace_event>,
+    next_seq: u64,
+}
+
+/// Device-wide ring buffer of trace events.
+#[pin_data]
+pub(crate) struct TraceRing {
+    #[pin]
+    inner: Mutex<RingInner>,
+}
+
+impl TraceRing {
+    /// Create a new, empty trace ring. Storage is only allocated once tracing is first used.
+    pub(crate) fn new() -> impl PinInit<TraceRing> {
+        pin_init!(TraceRing {
+            inner <- Mutex::new_named(Default::default(), c_str!("trace_ring")),
+        })
+    }
+
+    /// Record an event at the current time.
+    pub(crate) fn record(&self, tag: Tag, event_type: u32) {
+        if enabled() {
+            self.record_at(tag, event_type, mem::read_counter());
+        }
+    }
+
+    /// Record an event with an explicit GPU timestamp.
+    pub(crate) fn record_at(&self, tag: Tag, event_type: u32, timestamp: u64) {
+        if !enabled() {
+            return;
+        }
+
+        let mut inner = self.inner.lock();
+
+        // Drop the event if the ring cannot be allocated, tracing is best-effort.
+        if inner.events.capacity() < RING_SIZE && inner.events.try_reserve(RING_SIZE).is_err() {
+            return;
+        }
+
+        let seq = inner.next_seq;
+        let event = uapi::drm_asahi_trace_event {
+            seq,
+            timestamp,
+            file_id: tag.file_id,
+            queue_id: tag.queue_id,
+            submission_id: tag.submission_id,
+            type_: event_type,
+            pipe: tag.pipe,
+        };
+
+        if inner.events.len() < RING_SIZE {
+            inner.events.push(event);
+        } else {
+            inner.events[seq as usize % RING_SIZE] = event;
+        }
+        inner.next_seq += 1;
+    }
+
+    /// Record the firmware start and end timestamps of a command, if it has any.
+    pub(crate) fn record_fw(&self, tag: Tag, start: u64, end: u64) {
+        if start != 0 {
+            self.record_at(
+                tag,
+                uapi::drm_asahi_trace_event_type_DRM_ASAHI_TRACE_FW_START,
+                start,
+            );
+        }
+        if end != 0 {
+            self.record_at(
+                tag,
+                uapi::drm_asahi_trace_event_type_DRM_ASAHI_TRACE_FW_END,
+                end,
+            );
+        }
+    }
+
+    /// Collect up to `max` events of `file_id` starting at sequence number `seq` into `out`.
+    ///
+    /// Returns the sequence number to continue from and the number of events that were
+    /// overwritten before they could be read.
+    pub(crate) fn read(
+        &self,
+        file_id: u64,
+        seq: u64,
+        max: usize,
+        out: &mut Vec<uapi::drm_asahi_trace_event>,
+    ) -> (u64, u64) {
+        let inner = self.inner.lock();
+        let oldest = inner.next_seq - inner.events.len() as u64;
+        let dropped = oldest.saturating_sub(seq);
+        let mut next = seq.max(oldest);
+
+        while next < inner.next_seq && out.len() < max {
+            let event = &inner.events[next as usize % RING_SIZE];
+            if event.file_id == file_id {
+                out.push(*event);
+            }
+            next += 1;
+        }
+
+        (next, dropped)
+    }
+}
+
+/// Formatter into a byte buffer of fixed capacity, which fails instead of growing.
+pub(crate) struct JsonWriter {
+    buf: Vec<u8>,
+    cap: usize,
+}
+
+impl JsonWriter {
+    /// Create a writer that holds at most `cap` bytes.
+    pub(crate) fn new(cap: usize) -> Result<JsonWriter> {
+        let mut buf = Vec::new();
+        buf.try_reserve(cap)?;
+        Ok(JsonWriter { buf, cap })
+    }
+
+    /// Return the bytes written so far.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Write `event` as Chrome trace event JSON objects, each followed by `",\n"`.
+    ///
+    /// Either the whole event is written or, if it does not fit, nothing is.
+    /// `timer_hz` is the GPU timer frequency, used to convert timestamps to microseconds.
+    pub(crate) fn write_event(
+        &mut self,
+        event: &uapi::drm_asahi_trace_event,
+        timer_hz: u64,
+    ) -> fmt::Result {
+        let len = self.buf.len();
+        let ret = Self::format_event(self, event, timer_hz);
+        if ret.is_err() {
+            self.buf.truncate(len);
+        }
+        ret
+    }
+
+    fn format_event(
+        out: &mut impl Write,
+        event: &uapi::drm_asahi_trace_event,
+        timer_hz: u64,
+    ) -> fmt::Result {
+        let pipe = match event.pipe {
+            PIPE_VERTEX => "vertex",
+            PIPE_FRAGMENT => "fragment",
+            PIPE_COMPUTE => "compute",
+            _ => "job",
+        };
+        // Firmware start/end pairs are durations on their own thread per queue and pipe, so they
+        // never overlap. Everything else is an instant event.
+        let (name, phase) = match event.type_ {
+            uapi::drm_asahi_trace_event_type_DRM_ASAHI_TRACE_SUBMIT => ("submit", "i"),
+            uapi::drm_asahi_trace_event_type_DRM_ASAHI_TRACE_DEPS_RESOLVED => {
+                ("deps_resolved", "i")
+            }
+            uapi::drm_asahi_trace_event_type_DRM_ASAHI_TRACE_RUN => ("run", "i"),
+            uapi::drm_asahi_trace_event_type_DRM_ASAHI_TRACE_FW_START => (pipe, "B"),
+            uapi::drm_asahi_trace_event_type_DRM_ASAHI_TRACE_FW_END => (pipe, "E"),
+            _ => ("complete", "i"),
+        };
+        let tid = event.queue_id * 4 + event.pipe as u64;
+
+        // Name the thread of each lane on the first event that can appear on it.
+        if phase == "B" || event.type_ == uapi::drm_asahi_trace_event_type_DRM_ASAHI_TRACE_SUBMIT {
+            write!(
+                out,
+                "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\
+                 \"args\":{{\"name\":\"queue {} {}\"}}}},\n",
+                event.file_id, tid, event.queue_id, pipe
+            )?;
+        }
+
+        let ns = (event.timestamp / timer_hz) * 1_000_000_000
+            + (event.timestamp % timer_hz) * 1_000_000_000 / timer_hz;
+        write!(
+            out,
+            "{{\"name\":\"{}\",\"cat\":\"asahi\",\"ph\":\"{}\",\"ts\":{}.{:03},\
+             \"pid\":{},\"tid\":{},",
+            name,
+            phase,
+            ns / 1000,
+            ns % 1000,
+            event.file_id,
+            tid
+        )?;
+        if phase == "i" {
+            out.write_str("\"s\":\"t\",")?;
+        }
+        write!(
+            out,
+            "\"args\":{{\"submission\":{}}}}},\n",
+            event.submission_id
+        )
+    }
+}
+
+impl Write for JsonWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.buf.len() + s.len() > self.cap {
+            return Err(fmt::Error);
+        }
+        self.buf.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
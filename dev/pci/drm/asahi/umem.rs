@@ -0,0 +1,189 @@
+// SPDX-License-Identifier: GPL-2.0-only OR MIT
+
+//! User memory access
+//!
+//! Submission parsing reads its untrusted arguments out of the calling process through a
+//! [`UserMemory`] source instead of constructing `UserSlicePtr`s directly. In the kernel that
+//! source is always [`UserSpace`], but it lets the parsing and validation steps of the submit
+//! path run in host unit tests against a fake address space (`host::HostMemory`).
+
+use core::mem::{self, MaybeUninit};
+use kernel::io_buffer::IoBufferReader;
+use kernel::prelude::*;
+use kernel::user_ptr::{UserSlicePtr, UserSlicePtrReader};
+
+/// A source of user memory to parse ioctl arguments from.
+pub(crate) trait UserMemory {
+    /// Reader type returned by [`UserMemory::reader`].
+    type Reader: IoBufferReader;
+
+    /// Returns a reader for `len` bytes of user memory starting at `ptr`.
+    ///
+    /// Faults are only reported once the memory is actually read.
+    fn reader(&self, ptr: u64, len: usize) -> Self::Reader;
+
+    /// Reads a `T` from user memory at `ptr`.
+    ///
+    /// # Safety
+    /// `T` must be a plain old data type for which any bit pattern is valid.
+    unsafe fn read<T>(&self, ptr: u64) -> Result<T> {
+        let mut reader = self.reader(ptr, mem::size_of::<T>());
+        // SAFETY: Forwarded from our caller.
+        unsafe { read_one(&mut reader) }
+    }
+
+    /// Reads an array of `count` `T`s from user memory at `ptr`.
+    ///
+    /// # Safety
+    /// `T` must be a plain old data type for which any bit pattern is valid.
+    unsafe fn read_array<T>(&self, ptr: u64, count: usize) -> Result<Vec<T>> {
+        let size = mem::size_of::<T>().checked_mul(count).ok_or(EINVAL)?;
+
+        // Each element is read exactly once, so there are no TOCTOU issues.
+        let mut reader = self.reader(ptr, size);
+
+        let mut vec = Vec::new();
+        vec.try_reserve(count)?;
+        for _i in 0..count {
+            // SAFETY: Forwarded from our caller.
+            vec.push(unsafe { read_one(&mut reader)? });
+        }
+
+        Ok(vec)
+    }
+}
+
+/// Reads a single `T` from `reader`.
+///
+/// # Safety
+/// `T` must be a plain old data type for which any bit pattern is valid.
+unsafe fn read_one<T>(reader: &mut impl IoBufferReader) -> Result<T> {
+    let mut out = MaybeUninit::<T>::uninit();
+    // SAFETY: `out` is valid for writes of `size_of::<T>()` bytes, and any bit pattern is valid
+    // for T.
+    unsafe {
+        reader.read_raw(out.as_mut_ptr() as *mut u8, mem::size_of::<T>())?;
+        Ok(out.assume_init())
+    }
+}
+
+/// The address space of the calling process.
+pub(crate) struct UserSpace;
+
+impl UserMemory for UserSpace {
+    type Reader = UserSlicePtrReader;
+
+    fn reader(&self, ptr: u64, len: usize) -> UserSlicePtrReader {
+        // SAFETY: The user pointer is only accessed through copyin, which validates it.
+        unsafe { UserSlicePtr::new(ptr as usize as *mut _, len).reader() }
+    }
+}
+
+/// Fake user address space for host tests.
+#[cfg(test)]
+pub(crate) mod host {
+    use super::*;
+
+    /// A sparse address space made of byte regions. Reads that are not entirely within a single
+    /// region fault with `EFAULT`, like `copyin` does for unmapped memory.
+    #[derive(Default)]
+    pub(crate) struct HostMemory {
+        regions: Vec<(u64, Vec<u8>)>,
+    }
+
+    impl HostMemory {
+        /// Maps `data` at address `addr`.
+        pub(crate) fn map(&mut self, addr: u64, data: &[u8]) {
+            self.regions.push((addr, data.to_vec()));
+        }
+
+        /// Maps the raw bytes of `items` at address `addr`.
+        pub(crate) fn map_items<T>(&mut self, addr: u64, items: &[T]) {
+            let len = mem::size_of_val(items);
+            // SAFETY: Only used with uapi structs, which have no padding bytes.
+            let bytes = unsafe { core::slice::from_raw_parts(items.as_ptr() as *const u8, len) };
+            self.map(addr, bytes);
+        }
+    }
+
+    /// Reader over a [`HostMemory`] range. `data` holds the mapped prefix of the range.
+    pub(crate) struct HostReader {
+        data: Vec<u8>,
+        pos: usize,
+        len: usize,
+    }
+
+    impl IoBufferReader for HostReader {
+        fn len(&self) -> usize {
+            self.len
+        }
+
+        unsafe fn read_raw(&mut self, out: *mut u8, len: usize) -> Result {
+            if len > self.len || self.pos + len > self.data.len() {
+                return Err(EFAULT);
+            }
+            // SAFETY: `out` is valid for `len` bytes per our contract, and the source range was
+            // checked above.
+            unsafe { core::ptr::copy_nonoverlapping(self.data[self.pos..].as_ptr(), out, len) };
+            self.pos += len;
+            self.len -= len;
+            Ok(())
+        }
+    }
+
+    impl UserMemory for HostMemory {
+        type Reader = HostReader;
+
+        fn reader(&self, ptr: u64, len: usize) -> HostReader {
+            let data = self
+                .regions
+                .iter()
+                .find(|(addr, data)| ptr >= *addr && ptr - *addr < data.len() as u64)
+                .map(|(addr, data)| {
+                    let start = (ptr - addr) as usize;
+                    let end = data.len().min(start.saturating_add(len));
+                    data[start..end].to_vec()
+                })
+                .unwrap_or_default();
+
+            HostReader { data, pos: 0, len }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::host::HostMemory;
+    use super::*;
+
+    #[test]
+    fn read_array() {
+        let mut mem = HostMemory::default();
+        mem.map_items(0x1000, &[1u32, 2, 3, 4]);
+
+        // SAFETY: u32 is plain old data.
+        unsafe {
+            assert_eq!(mem.read_array::<u32>(0x1000, 4).unwrap(), [1, 2, 3, 4]);
+            assert_eq!(mem.read_array::<u32>(0x1008, 2).unwrap(), [3, 4]);
+            assert!(mem.read_array::<u32>(0x1000, 0).unwrap().is_empty());
+            assert_eq!(mem.read::<u64>(0x1004).unwrap(), 2 | (3 << 32));
+        }
+    }
+
+    #[test]
+    fn faults() {
+        let mut mem = HostMemory::default();
+        mem.map_items(0x1000, &[1u32, 2]);
+
+        // SAFETY: u32 is plain old data.
+        unsafe {
+            // Runs off the end of the mapping
+            assert_eq!(mem.read_array::<u32>(0x1004, 2), Err(EFAULT));
+            // Unmapped
+            assert_eq!(mem.read::<u32>(0x2000), Err(EFAULT));
+            assert_eq!(mem.read::<u32>(0), Err(EFAULT));
+            // Size overflow
+            assert_eq!(mem.read_array::<u32>(0x1000, usize::MAX), Err(EINVAL));
+        }
+    }
+}
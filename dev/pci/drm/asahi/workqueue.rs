@@ -20,9 +20,10 @@ use crate::fw::workqueue::*;
 use crate::no_debug;
 use crate::object::OpaqueGpuObject;
 use crate::regs::FaultReason;
-use crate::{channel, driver, event, fw, gpu, object, regs};
+use crate::{channel, driver, event, fw, gpu, object, regs, trace};
+use core::any::Any;
 use core::num::NonZeroU64;
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicU32, Ordering};
 use kernel::{
     c_str, dma_fence,
     error::code::*,
@@ -38,6 +39,9 @@ const DEBUG_CLASS: DebugFlags = DebugFlags::WorkQueue;
 
 const MAX_JOB_SLOTS: u32 = 127;
 
+/// Number of GPU faults a context may cause before it is banned from submitting more work.
+const MAX_CONTEXT_FAULTS: u32 = 3;
+
 /// An enum of possible errors that might cause a piece of work to fail execution.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub(crate) enum WorkError {
@@ -104,6 +108,7 @@ impl From<WorkError> for kernel::error::Error {
 pub(crate) struct GpuContext {
     dev: driver::AsahiDevRef,
     data: Option<Box<GpuObject<fw::workqueue::GpuContextData>>>,
+    faults: AtomicU32,
 }
 no_debug!(GpuContext);
 
@@ -120,9 +125,20 @@ impl GpuContext {
                 fw::workqueue::GpuContextData { _buffer: buffer },
                 |_inner| Default::default(),
             )?)),
+            faults: AtomicU32::new(0),
         })
     }
 
+    /// Record a GPU fault caused by this context. Returns true if this fault got it banned.
+    pub(crate) fn add_fault(&self) -> bool {
+        self.faults.fetch_add(1, Ordering::Relaxed) + 1 == MAX_CONTEXT_FAULTS
+    }
+
+    /// Returns true if this context has caused too many faults to be allowed to submit work.
+    pub(crate) fn is_banned(&self) -> bool {
+        self.faults.load(Ordering::Relaxed) >= MAX_CONTEXT_FAULTS
+    }
+
     /// Returns the GPU pointer to the inner GPU context data structure.
     pub(crate) fn gpu_pointer(&self) -> GpuPointer<'_, fw::workqueue::GpuContextData> {
         self.data.as_ref().unwrap().gpu_pointer()
@@ -150,6 +166,7 @@ where
     vm_slot: u32,
     callback: Option<C>,
     fence: dma_fence::Fence,
+    trace: trace::Tag,
 }
 
 pub(crate) trait GenSubmittedWork: Send + Sync {
@@ -157,9 +174,11 @@ pub(crate) trait GenSubmittedWork: Send + Sync {
     fn value(&self) -> event::EventValue;
     fn wptr(&self) -> u32;
     fn set_wptr(&mut self, wptr: u32);
+    fn vm_slot(&self) -> u32;
     fn mark_error(&mut self, error: WorkError);
     fn complete(&mut self);
     fn get_fence(&self) -> dma_fence::Fence;
+    fn trace_tag(&self) -> trace::Tag;
 }
 
 impl<O: OpaqueGpuObject, C: FnOnce(&mut O, Option<WorkError>) + Send + Sync> GenSubmittedWork
@@ -181,6 +200,10 @@ impl<O: OpaqueGpuObject, C: FnOnce(&mut O, Option<WorkError>) + Send + Sync> Gen
         self.wptr = wptr;
     }
 
+    fn vm_slot(&self) -> u32 {
+        self.vm_slot
+    }
+
     fn complete(&mut self) {
         if let Some(cb) = self.callback.take() {
             cb(&mut self.object, self.error);
@@ -198,6 +221,10 @@ impl<O: OpaqueGpuObject, C: FnOnce(&mut O, Option<WorkError>) + Send + Sync> Gen
     fn get_fence(&self) -> dma_fence::Fence {
         self.fence.clone()
     }
+
+    fn trace_tag(&self) -> trace::Tag {
+        self.trace
+    }
 }
 
 /// Inner data for managing a single work queue.
@@ -264,6 +291,7 @@ pub(crate) struct Job {
     submitted: bool,
     event_count: usize,
     fence: dma_fence::Fence,
+    trace: trace::Tag,
 }
 
 #[versions(AGX)]
@@ -316,6 +344,7 @@ impl Job::ver {
             wptr: 0,
             vm_slot,
             fence: self.fence.clone(),
+            trace: self.trace,
         }));
 
         Ok(())
@@ -671,7 +700,11 @@ impl WorkQueue::ver {
         })
     }
 
-    pub(crate) fn new_job(self: &Arc<Self>, fence: dma_fence::Fence) -> Result<Job::ver> {
+    pub(crate) fn new_job(
+        self: &Arc<Self>,
+        fence: dma_fence::Fence,
+        trace: trace::Tag,
+    ) -> Result<Job::ver> {
         let mut inner = self.inner.lock();
 
         if inner.event.is_none() {
@@ -712,12 +745,50 @@ impl WorkQueue::ver {
             committed: false,
             submitted: false,
             fence,
+            trace,
         })
     }
 
     pub(crate) fn pipe_type(&self) -> PipeType {
         self.inner.lock().pipe_type
     }
+
+    /// Ask the firmware to run this queue up to its current write pointer again.
+    ///
+    /// This is used after recovering from a fault caused by another context, so that work that
+    /// was in flight when the firmware halted gets picked up again. `run` is called with the
+    /// queue's pipe type and priority and must send the message on the matching pipe channel.
+    /// Returns false if the queue has no pending work.
+    pub(crate) fn resubmit(
+        &self,
+        run: impl FnOnce(PipeType, u32, &fw::channels::RunWorkQueueMsg::ver) -> Result,
+    ) -> Result<bool> {
+        let inner = self.inner.lock();
+
+        let event_slot = match inner.event.as_ref() {
+            Some(event) if !inner.pending.is_empty() => event.0.slot(),
+            _ => return Ok(false),
+        };
+
+        mod_pr_debug!(
+            "WorkQueue({:?}): Resubmitting up to wptr {}\n",
+            inner.pipe_type,
+            inner.wptr
+        );
+
+        let msg = fw::channels::RunWorkQueueMsg::ver {
+            pipe_type: inner.pipe_type,
+            work_queue: Some(inner.info.weak_pointer()),
+            wptr: inner.wptr,
+            event_slot,
+            is_new: false,
+            __pad: Default::default(),
+        };
+
+        // Keep the queue locked while sending, so this cannot race a newer submission.
+        run(inner.pipe_type, inner.priority, &msg)?;
+        Ok(true)
+    }
 }
 
 /// Trait used to erase the version-specific type of WorkQueues, to avoid leaking
@@ -726,6 +797,9 @@ pub(crate) trait WorkQueue {
     fn signal(&self) -> bool;
     fn mark_error(&self, value: event::EventValue, error: WorkError);
     fn fail_all(&self, error: WorkError);
+    fn owns_vm_slot(&self, value: event::EventValue, vm_slot: u32) -> bool;
+    fn gpu_context(&self) -> Arc<GpuContext>;
+    fn as_any(&self) -> &dyn Any;
 }
 
 #[versions(AGX)]
@@ -811,11 +885,16 @@ impl WorkQueue for WorkQueue::ver {
         let dev = inner.dev.clone();
         core::mem::drop(inner);
 
+        let gpu = &dev.data().gpu;
+
         for cmd in completed.iter_mut() {
+            gpu.trace().record(
+                cmd.trace_tag(),
+                uapi::drm_asahi_trace_event_type_DRM_ASAHI_TRACE_COMPLETE,
+            );
             cmd.complete();
         }
 
-        let gpu = &dev.data().gpu;
         gpu.add_completed_work(completed);
 
         empty
@@ -877,11 +956,37 @@ impl WorkQueue for WorkQueue::ver {
             inner.event = None;
         }
 
+        let dev = inner.dev.clone();
         core::mem::drop(inner);
 
         for mut cmd in cmds {
             cmd.mark_error(error);
+            dev.data().gpu.trace().record(
+                cmd.trace_tag(),
+                uapi::drm_asahi_trace_event_type_DRM_ASAHI_TRACE_COMPLETE,
+            );
             cmd.complete();
         }
     }
+
+    /// Returns true if any of this queue's work up to a certain stamp value runs in the VM bound
+    /// to the given slot.
+    fn owns_vm_slot(&self, value: event::EventValue, vm_slot: u32) -> bool {
+        let inner = self.inner.lock();
+
+        inner
+            .pending
+            .iter()
+            .take_while(|cmd| cmd.value() <= value)
+            .any(|cmd| cmd.vm_slot() == vm_slot)
+    }
+
+    /// Returns the GPU context this queue belongs to.
+    fn gpu_context(&self) -> Arc<GpuContext> {
+        self.inner.lock().info.gpu_context.clone()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
@@ -1140,6 +1140,39 @@ xa_get_next(struct xarray *xa, unsigned long *index)
 	return NULL;
 }
 
+/*
+ * Like xa_get_next(), but skips reserved (NULL) entries and only
+ * considers indices up to max.  Caller must hold xa_lock.
+ *
+ * Splaying on *index leaves its predecessor or successor at the root,
+ * so the first candidate is found without walking the whole tree.
+ */
+void *
+__xa_find(struct xarray *xa, unsigned long *index, unsigned long max)
+{
+	struct xarray_entry find, *res;
+
+	if (SPLAY_EMPTY(&xa->xa_tree) || *index > INT_MAX)
+		return NULL;
+
+	find.id = *index;
+	SPLAY_FIND(xarray_tree, &xa->xa_tree, &find);
+	res = SPLAY_ROOT(&xa->xa_tree);
+	if (res->id < *index)
+		res = SPLAY_NEXT(xarray_tree, &xa->xa_tree, res);
+
+	for (; res != NULL; res = SPLAY_NEXT(xarray_tree, &xa->xa_tree, res)) {
+		if (res->id > max)
+			break;
+		if (res->ptr != NULL) {
+			*index = res->id;
+			return res->ptr;
+		}
+	}
+
+	return NULL;
+}
+
 int
 sg_alloc_table(struct sg_table *table, unsigned int nents, gfp_t gfp_mask)
 {
@@ -235,6 +235,9 @@ static void
 syncobj_eventfd_entry_func(struct drm_syncobj *syncobj,
 			   struct syncobj_eventfd_entry *entry);
 
+static void syncobj_wait_cb_func(struct drm_syncobj *syncobj,
+				 struct drm_syncobj_wait_cb *cb);
+
 /**
  * drm_syncobj_find - lookup and reference a sync object.
  * @file_private: drm file private pointer
@@ -337,6 +340,7 @@ void drm_syncobj_add_point(struct drm_syncobj *syncobj,
 {
 	struct syncobj_wait_entry *wait_cur, *wait_tmp;
 	struct syncobj_eventfd_entry *ev_fd_cur, *ev_fd_tmp;
+	struct drm_syncobj_wait_cb *cb_cur, *cb_tmp;
 	struct dma_fence *prev;
 
 	dma_fence_get(fence);
@@ -354,6 +358,8 @@ void drm_syncobj_add_point(struct drm_syncobj *syncobj,
 		syncobj_wait_syncobj_func(syncobj, wait_cur);
 	list_for_each_entry_safe(ev_fd_cur, ev_fd_tmp, &syncobj->ev_fd_list, node)
 		syncobj_eventfd_entry_func(syncobj, ev_fd_cur);
+	list_for_each_entry_safe(cb_cur, cb_tmp, &syncobj->wait_cb_list, node)
+		syncobj_wait_cb_func(syncobj, cb_cur);
 	spin_unlock(&syncobj->lock);
 
 	/* Walk the chain once to trigger garbage collection */
@@ -375,6 +381,7 @@ void drm_syncobj_replace_fence(struct drm_syncobj *syncobj,
 	struct dma_fence *old_fence;
 	struct syncobj_wait_entry *wait_cur, *wait_tmp;
 	struct syncobj_eventfd_entry *ev_fd_cur, *ev_fd_tmp;
+	struct drm_syncobj_wait_cb *cb_cur, *cb_tmp;
 
 	if (fence)
 		dma_fence_get(fence);
@@ -390,6 +397,8 @@ void drm_syncobj_replace_fence(struct drm_syncobj *syncobj,
 			syncobj_wait_syncobj_func(syncobj, wait_cur);
 		list_for_each_entry_safe(ev_fd_cur, ev_fd_tmp, &syncobj->ev_fd_list, node)
 			syncobj_eventfd_entry_func(syncobj, ev_fd_cur);
+		list_for_each_entry_safe(cb_cur, cb_tmp, &syncobj->wait_cb_list, node)
+			syncobj_wait_cb_func(syncobj, cb_cur);
 	}
 
 	spin_unlock(&syncobj->lock);
@@ -518,6 +527,85 @@ out:
 }
 EXPORT_SYMBOL(drm_syncobj_find_fence);
 
+static void syncobj_wait_cb_func(struct drm_syncobj *syncobj,
+				 struct drm_syncobj_wait_cb *cb)
+{
+	struct dma_fence *fence;
+
+	/* This happens inside the syncobj lock */
+	fence = dma_fence_get(rcu_dereference_protected(syncobj->fence,
+							lockdep_is_held(&syncobj->lock)));
+	if (!fence || dma_fence_chain_find_seqno(&fence, cb->point)) {
+		dma_fence_put(fence);
+		return;
+	}
+	dma_fence_put(fence);
+
+	list_del_init(&cb->node);
+	cb->func(cb);
+}
+
+/**
+ * drm_syncobj_add_wait_cb - get called back once a point has a fence
+ * @syncobj: sync object to watch
+ * @cb: callback entry, which must stay valid until it fires or is removed
+ * @point: timeline point to wait for
+ * @func: function to call, with the syncobj lock held
+ *
+ * This is the non-blocking counterpart of drm_syncobj_find_fence() with
+ * DRM_SYNCOBJ_WAIT_FLAGS_WAIT_FOR_SUBMIT, for drivers that want to defer work
+ * until userspace submits the work that signals @point.
+ *
+ * Returns true if the callback was armed, or false if @point already has a
+ * fence, in which case @func is not called.
+ */
+bool drm_syncobj_add_wait_cb(struct drm_syncobj *syncobj,
+			     struct drm_syncobj_wait_cb *cb, u64 point,
+			     drm_syncobj_wait_func_t func)
+{
+	struct dma_fence *fence;
+	bool armed = false;
+
+	INIT_LIST_HEAD(&cb->node);
+	cb->point = point;
+	cb->func = func;
+
+	spin_lock(&syncobj->lock);
+	fence = dma_fence_get(rcu_dereference_protected(syncobj->fence, 1));
+	if (!fence || dma_fence_chain_find_seqno(&fence, point)) {
+		list_add_tail(&cb->node, &syncobj->wait_cb_list);
+		armed = true;
+	}
+	spin_unlock(&syncobj->lock);
+
+	dma_fence_put(fence);
+
+	return armed;
+}
+EXPORT_SYMBOL(drm_syncobj_add_wait_cb);
+
+/**
+ * drm_syncobj_remove_wait_cb - disarm a callback added by drm_syncobj_add_wait_cb()
+ * @syncobj: sync object the callback was added to
+ * @cb: callback entry
+ *
+ * Once this returns, @cb is no longer referenced and its function is not
+ * running. Returns true if the callback was still armed.
+ */
+bool drm_syncobj_remove_wait_cb(struct drm_syncobj *syncobj,
+				struct drm_syncobj_wait_cb *cb)
+{
+	bool armed;
+
+	spin_lock(&syncobj->lock);
+	armed = !list_empty(&cb->node);
+	list_del_init(&cb->node);
+	spin_unlock(&syncobj->lock);
+
+	return armed;
+}
+EXPORT_SYMBOL(drm_syncobj_remove_wait_cb);
+
 /**
  * drm_syncobj_free - free a sync object.
  * @kref: kref to free.
@@ -565,6 +653,7 @@ int drm_syncobj_create(struct drm_syncobj **out_syncobj, uint32_t flags,
 	kref_init(&syncobj->refcount);
 	INIT_LIST_HEAD(&syncobj->cb_list);
 	INIT_LIST_HEAD(&syncobj->ev_fd_list);
+	INIT_LIST_HEAD(&syncobj->wait_cb_list);
 	mtx_init(&syncobj->lock, IPL_NONE);
 
 	if (flags & DRM_SYNCOBJ_CREATE_SIGNALED) {
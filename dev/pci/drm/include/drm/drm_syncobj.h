@@ -58,7 +58,12 @@ struct drm_syncobj {
 	 */
 	struct list_head ev_fd_list;
 	/**
-	 * @lock: Protects &cb_list and &ev_fd_list, and write-locks &fence.
+	 * @wait_cb_list: List of &drm_syncobj_wait_cb waiting for a point.
+	 */
+	struct list_head wait_cb_list;
+	/**
+	 * @lock: Protects &cb_list, &ev_fd_list and &wait_cb_list, and
+	 * write-locks &fence.
 	 */
 	spinlock_t lock;
 	/**
@@ -69,6 +74,25 @@ struct drm_syncobj {
 
 void drm_syncobj_free(struct kref *kref);
 
+struct drm_syncobj_wait_cb;
+typedef void (*drm_syncobj_wait_func_t)(struct drm_syncobj_wait_cb *cb);
+
+/**
+ * struct drm_syncobj_wait_cb - callback for a point being attached
+ *
+ * Drivers embed this in their own structure and register it with
+ * drm_syncobj_add_wait_cb() to be told, without blocking, when a fence for a
+ * timeline point (or, for point 0, any fence) has been attached to a syncobj.
+ */
+struct drm_syncobj_wait_cb {
+	/** @node: Entry in &drm_syncobj.wait_cb_list, managed by the core. */
+	struct list_head node;
+	/** @point: Timeline point being waited for. */
+	u64 point;
+	/** @func: Called with &drm_syncobj.lock held, must not sleep. */
+	drm_syncobj_wait_func_t func;
+};
+
 /**
  * drm_syncobj_get - acquire a syncobj reference
  * @obj: sync object
@@ -126,6 +150,11 @@ void drm_syncobj_replace_fence(struct drm_syncobj *syncobj,
 int drm_syncobj_find_fence(struct drm_file *file_private,
 			   u32 handle, u64 point, u64 flags,
 			   struct dma_fence **fence);
+bool drm_syncobj_add_wait_cb(struct drm_syncobj *syncobj,
+			     struct drm_syncobj_wait_cb *cb, u64 point,
+			     drm_syncobj_wait_func_t func);
+bool drm_syncobj_remove_wait_cb(struct drm_syncobj *syncobj,
+				struct drm_syncobj_wait_cb *cb);
 void drm_syncobj_free(struct kref *kref);
 int drm_syncobj_create(struct drm_syncobj **out_syncobj, uint32_t flags,
 		       struct dma_fence *fence);
@@ -37,6 +37,7 @@ void *__xa_load(struct xarray *, unsigned long);
 void *__xa_store(struct xarray *, unsigned long, void *, gfp_t);
 void *__xa_erase(struct xarray *, unsigned long);
 void *xa_get_next(struct xarray *, unsigned long *);
+void *__xa_find(struct xarray *, unsigned long *, unsigned long);
 
 #define xa_for_each(xa, index, entry) \
 	for (index = 0; ((entry) = xa_get_next(xa, &(index))) != NULL; index++)
@@ -28,6 +28,8 @@ extern "C" {
 #define DRM_ASAHI_QUEUE_DESTROY			0x07
 #define DRM_ASAHI_SUBMIT			0x08
 #define DRM_ASAHI_GET_TIME			0x09
+#define DRM_ASAHI_GEM_SYNC			0x0a
+#define DRM_ASAHI_TRACE_READ			0x0b
 
 #define DRM_ASAHI_MAX_CLUSTERS	32
 
@@ -74,20 +76,139 @@ struct drm_asahi_params_global {
 	__u32 firmware_version[4];
 };
 
-/*
 enum drm_asahi_feat_compat {
+	/* ASAHI_BIND_OP_BIND_SPARSE and ASAHI_BIND_OP_UNBIND_SPARSE are supported */
+	DRM_ASAHI_FEAT_SPARSE = (1UL) << 0,
 };
-*/
 
 enum drm_asahi_feat_incompat {
 	DRM_ASAHI_FEAT_MANDATORY_ZS_COMPRESSION = (1UL) << 0,
 };
 
+enum drm_asahi_param_group {
+	/* struct drm_asahi_params_global */
+	DRM_ASAHI_PARAM_GROUP_GLOBAL = 0,
+	/* struct drm_asahi_params_perf_states */
+	DRM_ASAHI_PARAM_GROUP_PERF_STATES = 1,
+	/* struct drm_asahi_params_render */
+	DRM_ASAHI_PARAM_GROUP_RENDER = 2,
+	/* struct drm_asahi_params_tvb */
+	DRM_ASAHI_PARAM_GROUP_TVB = 3,
+	/* struct drm_asahi_params_firmware */
+	DRM_ASAHI_PARAM_GROUP_FIRMWARE = 4,
+	/* struct drm_asahi_params_preemption */
+	DRM_ASAHI_PARAM_GROUP_PREEMPTION = 5,
+};
+
+/*
+ * All parameter groups other than DRM_ASAHI_PARAM_GROUP_GLOBAL start with a
+ * size and version header. @size is the size of the struct as known to the
+ * kernel, and @version is bumped whenever fields are appended to it, so
+ * userspace built against an older header can tell which fields were filled in.
+ */
+
+#define DRM_ASAHI_MAX_PERF_STATES	16
+#define DRM_ASAHI_MAX_POWER_ZONES	8
+
+struct drm_asahi_perf_state {
+	__u32 freq_hz;
+	/* Highest voltage across all clusters at this state */
+	__u32 max_volt_mv;
+	__u32 pwr_mw;
+	__u32 pad;
+};
+
+struct drm_asahi_power_zone {
+	__u32 target;
+	__u32 target_offset;
+	__u32 filter_tc;
+	__u32 pad;
+};
+
+#define DRM_ASAHI_PARAMS_PERF_STATES_VERSION	1
+
+struct drm_asahi_params_perf_states {
+	__u32 size;
+	__u32 version;
+
+	__u32 num_perf_states;
+	__u32 perf_base_pstate;
+	__u32 perf_max_pstate;
+	__u32 max_power_mw;
+	__u32 num_power_zones;
+	__u32 pad;
+
+	struct drm_asahi_perf_state perf_states[DRM_ASAHI_MAX_PERF_STATES];
+	struct drm_asahi_power_zone power_zones[DRM_ASAHI_MAX_POWER_ZONES];
+};
+
+#define DRM_ASAHI_PARAMS_RENDER_VERSION		1
+
+struct drm_asahi_params_render {
+	__u32 size;
+	__u32 version;
+
+	/* Default tiling control register value */
+	__u32 tiling_control;
+	/* Nonzero if the clustering fields below are valid */
+	__u32 has_clustering;
+
+	__u32 clustering_meta1_blocksize;
+	__u32 clustering_meta2_size;
+	__u32 clustering_meta3_size;
+	__u32 clustering_meta4_size;
+	__u32 clustering_max_splits;
+	__u32 pad;
+};
+
+#define DRM_ASAHI_PARAMS_TVB_VERSION		1
+
+struct drm_asahi_params_tvb {
+	__u32 size;
+	__u32 version;
+
+	__u32 page_size;
+	__u32 pages_per_block;
+	__u32 block_size;
+	/* Initial number of blocks allocated per render queue */
+	__u32 initial_blocks;
+	__u64 max_size;
+	__u64 max_size_nomemless;
+};
+
+#define DRM_ASAHI_PARAMS_FIRMWARE_VERSION	1
+
+struct drm_asahi_params_firmware {
+	__u32 size;
+	__u32 version;
+
+	/* Active firmware version as major, minor, patch */
+	__u32 firmware_version[3];
+	__u32 pad;
+};
+
+#define DRM_ASAHI_PARAMS_PREEMPTION_VERSION	1
+
+/*
+ * Device-wide counts of submitted render and compute commands, and of those
+ * that set ASAHI_RENDER_NO_PREEMPTION or ASAHI_COMPUTE_NO_PREEMPTION. These
+ * count requests; the firmware does not report actual preemptions.
+ */
+struct drm_asahi_params_preemption {
+	__u32 size;
+	__u32 version;
+
+	__u64 render_commands;
+	__u64 render_no_preemption;
+	__u64 compute_commands;
+	__u64 compute_no_preemption;
+};
+
 struct drm_asahi_get_params {
 	/** @extensions: Pointer to the first extension struct, if any */
 	__u64 extensions;
 
-	/** @param: Parameter group to fetch (MBZ) */
+	/** @param: Parameter group to fetch (enum drm_asahi_param_group) */
 	__u32 param_group;
 
 	/** @pad: MBZ */
@@ -156,10 +277,46 @@ struct drm_asahi_gem_mmap_offset {
 	__u64 offset;
 };
 
+enum drm_asahi_gem_sync_dir {
+	/* Clean CPU caches so the GPU sees CPU writes */
+	ASAHI_GEM_SYNC_TO_DEVICE = 0,
+	/* Invalidate CPU caches so the CPU sees GPU writes */
+	ASAHI_GEM_SYNC_FROM_DEVICE = 1,
+	/* Clean and invalidate CPU caches */
+	ASAHI_GEM_SYNC_BIDIRECTIONAL = 2,
+};
+
+struct drm_asahi_gem_sync {
+	/** @extensions: Pointer to the first extension struct, if any */
+	__u64 extensions;
+
+	/** @handle: Handle for the object being synced. */
+	__u32 handle;
+
+	/** @direction: One of drm_asahi_gem_sync_dir */
+	__u32 direction;
+
+	/** @offset: Offset into the object */
+	__u64 offset;
+
+	/** @size: Number of bytes from offset to sync */
+	__u64 size;
+};
+
 enum drm_asahi_bind_op {
 	ASAHI_BIND_OP_BIND = 0,
 	ASAHI_BIND_OP_UNBIND = 1,
 	ASAHI_BIND_OP_UNBIND_ALL = 2,
+	/*
+	 * Reserve a sparse range backed by a writable scratch page. Objects
+	 * bound inside it replace the scratch page, and go back to it when
+	 * unbound. Accesses to unbound pages do not fault: writes to them are
+	 * discarded, and reads return undefined values. Objects bound inside a
+	 * sparse range have no guard page.
+	 */
+	ASAHI_BIND_OP_BIND_SPARSE = 3,
+	/* Release a sparse range. Objects bound inside it must be unbound first. */
+	ASAHI_BIND_OP_UNBIND_SPARSE = 4,
 };
 
 #define ASAHI_BIND_READ		(1L << 0)
@@ -195,6 +352,7 @@ enum drm_asahi_cmd_type {
 	DRM_ASAHI_CMD_RENDER = 0,
 	DRM_ASAHI_CMD_BLIT = 1,
 	DRM_ASAHI_CMD_COMPUTE = 2,
+	DRM_ASAHI_CMD_TIMESTAMP = 3,
 };
 
 /* Note: this is an enum so that it can be resolved by Rust bindgen. */
@@ -289,7 +447,11 @@ struct drm_asahi_submit {
 	/** @extensions: Pointer to the first extension struct, if any */
 	__u64 extensions;
 
-	/** @in_syncs: An optional array of drm_asahi_sync to wait on before starting this job. */
+	/**
+	 * @in_syncs: An optional array of drm_asahi_sync to wait on before starting this job.
+	 * Timeline points that have not been submitted yet are waited for, see
+	 * struct drm_asahi_submit_sync_wait.
+	 */
 	__u64 in_syncs;
 
 	/** @in_syncs: An optional array of drm_asahi_sync objects to signal upon completion. */
@@ -317,6 +479,58 @@ struct drm_asahi_submit {
 	__u32 command_count;
 };
 
+#define ASAHI_SUBMIT_EXT_SYNC_WAIT	0x0001
+
+struct drm_asahi_submit_sync_wait {
+	/** @type: Type ID of this extension */
+	__u32 type;
+	__u32 pad;
+	/** @next: Pointer to the next extension struct, if any */
+	__u64 next;
+
+	/**
+	 * @timeout_ns: Maximum time the job may be held waiting for in_sync
+	 * timeline points to be submitted, after which it fails with
+	 * ETIMEDOUT. Clamped to the default of 5 seconds.
+	 */
+	__u64 timeout_ns;
+};
+
+#define ASAHI_SUBMIT_EXT_IMPLICIT_SYNC	0x0002
+
+/* The job reads from the BO */
+#define ASAHI_BO_USAGE_READ	(1UL << 0)
+/* The job writes to the BO */
+#define ASAHI_BO_USAGE_WRITE	(1UL << 1)
+
+struct drm_asahi_bo_usage {
+	/** @handle: GEM handle of the BO */
+	__u32 handle;
+	/** @flags: Combination of ASAHI_BO_USAGE_* flags */
+	__u32 flags;
+};
+
+/*
+ * Opt-in implicit synchronization: the job waits for the existing fences of
+ * the listed BOs (only writers if the job just reads), and its completion fence
+ * is added to each of them, for BOs shared with the display or other processes.
+ * A BO listed more than once is treated as written if any of its entries is.
+ */
+struct drm_asahi_submit_implicit_sync {
+	/** @type: Type ID of this extension */
+	__u32 type;
+	__u32 pad;
+	/** @next: Pointer to the next extension struct, if any */
+	__u64 next;
+
+	/** @bos: Pointer to an array of struct drm_asahi_bo_usage */
+	__u64 bos;
+	/** @bo_count: Number of entries in @bos */
+	__u32 bo_count;
+	/** @pad1: MBZ */
+	__u32 pad1;
+};
+
 struct drm_asahi_attachment {
 	/** @pointer: Base address of the attachment */
 	__u64 pointer;
@@ -608,6 +822,38 @@ struct drm_asahi_result_render {
 	__u32 num_tvb_overflows;
 };
 
+/*
+ * A timestamp query on the render or compute subqueue. The firmware writes a
+ * 64-bit GPU timestamp to @offset in the BO @handle. It does not run on its
+ * own: it is recorded at the end of the previous command on the same subqueue
+ * in the submission, after that command has gone idle, or, if there is none, at
+ * the start of the next one. The start and the end of a command can each only
+ * carry one timestamp. For render commands, the start is the start of the
+ * vertex work and the end is the end of the fragment work.
+ *
+ * Timestamp commands do not count as command indices for barriers and may not
+ * have a result of their own. A timestamp command with barriers is always
+ * recorded at the start of the next command on its subqueue, which then also
+ * waits on those barriers, so there must be one.
+ *
+ * Only the page holding the timestamp is mapped for the firmware, until the
+ * command it is recorded in completes. A client can only have a limited number
+ * of timestamps in flight; submissions beyond that fail with EBUSY.
+ */
+struct drm_asahi_cmd_timestamp {
+	/** @flags: MBZ */
+	__u64 flags;
+
+	/** @subqueue: One of drm_asahi_subqueue */
+	__u32 subqueue;
+
+	/** @handle: GEM handle of the BO to write the timestamp to */
+	__u32 handle;
+
+	/** @offset: Offset of the timestamp in the BO, 8-byte aligned */
+	__u64 offset;
+};
+
 struct drm_asahi_result_compute {
 	/** @address: Common result information */
 	struct drm_asahi_result_info info;
@@ -639,6 +885,109 @@ struct drm_asahi_get_time {
 	__u64 gpu_timestamp;
 };
 
+enum drm_asahi_trace_event_type {
+	/* The job was accepted by the submit ioctl */
+	DRM_ASAHI_TRACE_SUBMIT = 0,
+	/* All of the job's dependencies have signaled */
+	DRM_ASAHI_TRACE_DEPS_RESOLVED = 1,
+	/* The job was handed to the firmware */
+	DRM_ASAHI_TRACE_RUN = 2,
+	/* Firmware timestamp at the start of a command */
+	DRM_ASAHI_TRACE_FW_START = 3,
+	/* Firmware timestamp at the end of a command */
+	DRM_ASAHI_TRACE_FW_END = 4,
+	/* The driver processed the completion of a command */
+	DRM_ASAHI_TRACE_COMPLETE = 5,
+};
+
+/* Note: this is an enum so that it can be resolved by Rust bindgen. */
+enum drm_asahi_trace_pipe {
+	/* Events that apply to the whole job */
+	DRM_ASAHI_TRACE_PIPE_JOB = 0,
+	/* The vertex half of a render command */
+	DRM_ASAHI_TRACE_PIPE_VERTEX = 1,
+	/* The fragment half of a render command */
+	DRM_ASAHI_TRACE_PIPE_FRAGMENT = 2,
+	/* A compute command */
+	DRM_ASAHI_TRACE_PIPE_COMPUTE = 3,
+};
+
+/*
+ * A submission trace event. Events are only recorded while submission tracing
+ * is enabled in the driver debug flags.
+ *
+ * The vertex and fragment halves of render commands run on separate firmware
+ * pipes and may overlap, so FW_START/FW_END pairs only nest per @queue_id and
+ * @pipe.
+ */
+struct drm_asahi_trace_event {
+	/** @seq: Sequence number of this event, increasing across the device */
+	__u64 seq;
+
+	/** @timestamp: GPU timestamp of the event, see drm_asahi_get_time */
+	__u64 timestamp;
+
+	/** @file_id: Driver-wide ID of the DRM file that submitted the job */
+	__u64 file_id;
+
+	/** @queue_id: Driver-wide ID of the queue, as used in kernel logs */
+	__u64 queue_id;
+
+	/** @submission_id: Driver-wide submission ID */
+	__u64 submission_id;
+
+	/** @type: One of drm_asahi_trace_event_type */
+	__u32 type;
+
+	/** @pipe: One of drm_asahi_trace_pipe */
+	__u32 pipe;
+};
+
+/*
+ * Return Chrome trace event JSON instead of struct drm_asahi_trace_event.
+ * @events then points to a buffer of @count bytes, and @count returns the
+ * number of bytes written. Each event is written as a complete JSON object
+ * followed by ",\n", and the ioctl fails with ENOSPC if the buffer cannot hold
+ * the first one. Prepending "[" to the concatenated output of all reads
+ * gives a trace in the JSON Array Format, whose closing bracket is optional,
+ * which chrome://tracing and Perfetto load directly.
+ *
+ * Events go to a thread per queue and pipe in a process per DRM file. Firmware
+ * command start/end are "B"/"E" duration events named after the pipe, the
+ * other event types are instant events. Timestamps are converted to
+ * microseconds with the GPU timer frequency.
+ */
+#define DRM_ASAHI_TRACE_READ_JSON	(1UL << 0)
+
+struct drm_asahi_trace_read {
+	/** @extensions: Pointer to the first extension struct, if any */
+	__u64 extensions;
+
+	/** @flags: Combination of DRM_ASAHI_TRACE_READ_* flags */
+	__u64 flags;
+
+	/**
+	 * @events: Pointer to an array of struct drm_asahi_trace_event, or to a
+	 * byte buffer with DRM_ASAHI_TRACE_READ_JSON
+	 */
+	__u64 events;
+
+	/** @count: Size of @events on input, number of events or bytes returned on output */
+	__u32 count;
+
+	/** @pad: MBZ */
+	__u32 pad;
+
+	/**
+	 * @seq: First sequence number to return on input, sequence number to
+	 * pass to the next call on output
+	 */
+	__u64 seq;
+
+	/** @dropped: On return, number of events lost to ring overwrites */
+	__u64 dropped;
+};
+
 /* Note: this is an enum so that it can be resolved by Rust bindgen. */
 enum {
    DRM_IOCTL_ASAHI_GET_PARAMS       = DRM_IOWR(DRM_COMMAND_BASE + DRM_ASAHI_GET_PARAMS, struct drm_asahi_get_params),
@@ -651,6 +1000,8 @@ enum {
    DRM_IOCTL_ASAHI_QUEUE_DESTROY    = DRM_IOW(DRM_COMMAND_BASE + DRM_ASAHI_QUEUE_DESTROY, struct drm_asahi_queue_destroy),
    DRM_IOCTL_ASAHI_SUBMIT           = DRM_IOW(DRM_COMMAND_BASE + DRM_ASAHI_SUBMIT, struct drm_asahi_submit),
    DRM_IOCTL_ASAHI_GET_TIME         = DRM_IOWR(DRM_COMMAND_BASE + DRM_ASAHI_GET_TIME, struct drm_asahi_get_time),
+   DRM_IOCTL_ASAHI_GEM_SYNC         = DRM_IOW(DRM_COMMAND_BASE + DRM_ASAHI_GEM_SYNC, struct drm_asahi_gem_sync),
+   DRM_IOCTL_ASAHI_TRACE_READ       = DRM_IOWR(DRM_COMMAND_BASE + DRM_ASAHI_TRACE_READ, struct drm_asahi_trace_read),
 };
 
 #if defined(__cplusplus)
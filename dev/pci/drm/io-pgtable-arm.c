@@ -839,10 +839,9 @@ static size_t arm_lpae_split_blk_unmap(struct arm_lpae_io_pgtable *data,
 		return 0;
 
 	tablep = __arm_lpae_alloc_pages(tablesz, GFP_ATOMIC, cfg, data->iop.cookie);
-	nptep = tablep->cpu_addr;
-	data->pgd = tablep;
 	if (!tablep)
 		return 0; /* Bytes unmapped */
+	nptep = tablep->cpu_addr;
 
 	if (size == split_sz) {
 		unmap_idx_start = ARM_LPAE_LVL_IDX(iova, lvl, data);
@@ -14,11 +14,14 @@
 #include <sys/device.h>
 #include <sys/mutex.h>
 #include <sys/rwlock.h>
+#include <sys/task.h>
+#include <sys/timeout.h>
 #include <sys/time.h>
 #include <sys/param.h>
 #include <sys/ioccom.h>
 #include <machine/bus.h>
 #include <machine/fdt.h>
+#include <machine/intr.h>
 #include <drm/asahi/asahidrm.h>
 #include <drm/drm_device.h>
 #include <drm/drm_drv.h>
@@ -8,9 +8,11 @@
 pub mod shmem;
 
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 use crate::{
     bindings,
+    dma_fence::RawDmaFence,
     drm::{device, drv, file},
     error::{to_result, Result},
     prelude::*,
@@ -208,6 +210,98 @@ pub trait BaseObject: IntoGEMObject {
 
 impl<T: IntoGEMObject> BaseObject for T {}
 
+/// The reservations of a set of GEM objects, locked together.
+///
+/// The locks are taken with a ww acquire context, so that submissions locking overlapping sets of
+/// objects in different orders back off instead of deadlocking. All of them stay locked until
+/// this is dropped.
+pub struct ReservationGuard<'a, T: IntoGEMObject> {
+    objs: Vec<&'a T>,
+    raw: Vec<*mut bindings::drm_gem_object>,
+    // Fence slots reserved and not yet used, per object.
+    slots: Vec<u32>,
+    // Boxed since the locks point to it while they are held.
+    ctx: Box<bindings::ww_acquire_ctx>,
+}
+
+impl<'a, T: IntoGEMObject> ReservationGuard<'a, T> {
+    /// Locks the reservations of `objs`, which must all be distinct.
+    pub fn lock(objs: Vec<&'a T>) -> Result<Self> {
+        let mut raw = Vec::new();
+        let mut slots = Vec::new();
+        raw.try_reserve(objs.len())?;
+        slots.try_reserve(objs.len())?;
+        for obj in objs.iter() {
+            raw.push(obj.gem_obj() as *const _ as *mut _);
+            slots.push(0);
+        }
+        let count = raw.len().try_into()?;
+
+        // SAFETY: A zeroed ww_acquire_ctx is valid, it is initialized by the lock call below.
+        let mut ctx: Box<bindings::ww_acquire_ctx> = Box::try_new(unsafe { mem::zeroed() })?;
+        // SAFETY: The objects are valid for 'a, and `ctx` does not move until it is released
+        // in `drop`.
+        to_result(unsafe {
+            bindings::drm_gem_lock_reservations(raw.as_mut_ptr(), count, &mut *ctx)
+        })?;
+
+        Ok(ReservationGuard {
+            objs,
+            raw,
+            slots,
+            ctx,
+        })
+    }
+
+    /// Returns the locked object at `index`.
+    pub fn obj(&self, index: usize) -> &'a T {
+        self.objs[index]
+    }
+
+    /// Reserves `num` more fence slots on every locked reservation, to be used by
+    /// [`ReservationGuard::add_fence`].
+    pub fn reserve_fences(&mut self, num: u32) -> Result {
+        for (obj, slots) in self.raw.iter().zip(self.slots.iter_mut()) {
+            // SAFETY: The reservation is locked and valid for the lifetime of the object.
+            to_result(unsafe { bindings::dma_resv_reserve_fences((**obj).resv, *slots + num) })?;
+            *slots += num;
+        }
+        Ok(())
+    }
+
+    /// Adds a fence to the reservation of the object at `index` for implicit synchronization, as
+    /// a write to the object if `write` is true or as a read otherwise. Consumes a fence slot
+    /// reserved with [`ReservationGuard::reserve_fences`].
+    pub fn add_fence(&mut self, index: usize, fence: &dyn RawDmaFence, write: bool) -> Result {
+        if self.slots[index] == 0 {
+            return Err(EINVAL);
+        }
+        self.slots[index] -= 1;
+
+        let usage = if write {
+            bindings::dma_resv_usage_DMA_RESV_USAGE_WRITE
+        } else {
+            bindings::dma_resv_usage_DMA_RESV_USAGE_READ
+        };
+        // SAFETY: The reservation is locked, and has a fence slot reserved.
+        unsafe { bindings::dma_resv_add_fence((*self.raw[index]).resv, fence.raw(), usage) };
+        Ok(())
+    }
+}
+
+impl<T: IntoGEMObject> Drop for ReservationGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: The reservations were locked with `ctx` in `lock`.
+        unsafe {
+            bindings::drm_gem_unlock_reservations(
+                self.raw.as_mut_ptr(),
+                self.raw.len() as _,
+                &mut *self.ctx,
+            )
+        };
+    }
+}
+
 /// A base GEM object.
 #[repr(C)]
 #[pin_data]
@@ -176,7 +176,7 @@ impl<T: DriverObject> Object<T> {
 
         Ok(SGTable {
             sgt,
-            _owner: self.reference(),
+            owner: self.reference(),
         })
     }
 
@@ -337,13 +337,18 @@ impl SGEntry {
 ///
 /// # Invariants
 /// `sgt` must be a valid pointer to the `sg_table`, which must correspond to the owned
-/// object in `_owner` (which ensures it remains valid).
+/// object in `owner` (which ensures it remains valid).
 pub struct SGTable<T: DriverObject> {
     sgt: *const bindings::sg_table,
-    _owner: gem::ObjectRef<Object<T>>,
+    owner: gem::ObjectRef<Object<T>>,
 }
 
 impl<T: DriverObject> SGTable<T> {
+    /// Returns the GEM object backing this SGTable.
+    pub fn owner(&self) -> &gem::ObjectRef<Object<T>> {
+        &self.owner
+    }
+
     /// Returns an iterator through the SGTable's entries
     pub fn iter(&'_ self) -> SGTableIter<'_> {
         SGTableIter {
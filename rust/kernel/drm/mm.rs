@@ -283,6 +283,32 @@ impl<A: AllocInner<T>, T> Allocator<A, T> {
         let mut guard = self.mm.lock();
         cb(&mut guard.1)
     }
+
+    /// Call `cb` on each allocated node in order of increasing start address, until it returns
+    /// `false`.
+    ///
+    /// The allocator lock is held during the walk, so the callback must not drop nodes belonging to
+    /// this allocator.
+    pub fn for_each_node(&self, mut cb: impl FnMut(&NodeData<A, T>) -> bool) {
+        let guard = self.mm.lock();
+
+        // SAFETY: We hold the lock, so the node list cannot change under us. Every node on the
+        // list other than the head node was inserted by this allocator and is embedded in a
+        // `NodeData`, which stays alive at least until it removes itself from the list (which
+        // takes the lock).
+        unsafe {
+            let head = core::ptr::addr_of_mut!((*guard.0.get()).head_node.node_list);
+            let mut pos = (*head).next;
+            while pos != head {
+                let node = crate::container_of!(pos, bindings::drm_mm_node, node_list);
+                let data = crate::container_of!(node, NodeData<A, T>, node);
+                if !cb(&*data) {
+                    break;
+                }
+                pos = (*pos).next;
+            }
+        }
+    }
 }
 
 impl<A: AllocInner<T>, T> Drop for MmInner<A, T> {
@@ -7,6 +7,7 @@
 use crate::{
     bindings, device,
     dma_fence::*,
+    drm::gem::{IntoGEMObject, ReservationGuard},
     error::{to_result, Result},
     prelude::*,
     sync::{Arc, UniqueArc},
@@ -141,6 +142,26 @@ impl<'a, T: JobImpl> PendingJob<'a, T> {
         })
     }
 
+    /// Add the implicit synchronization fences of the locked GEM object at `index` as dependencies
+    /// of the job. A job that writes to the object waits for all of them, otherwise only for
+    /// previous writers.
+    pub fn add_implicit_dependencies<O: IntoGEMObject>(
+        &mut self,
+        resvs: &ReservationGuard<'_, O>,
+        index: usize,
+        write: bool,
+    ) -> Result {
+        // SAFETY: The job and the GEM object are valid per their type invariants, and the
+        // object's reservation is locked.
+        to_result(unsafe {
+            bindings::drm_sched_job_add_implicit_dependencies(
+                &mut self.0.job,
+                resvs.obj(index).gem_obj() as *const _ as *mut _,
+                write,
+            )
+        })
+    }
+
     /// Arm the job to make it ready for execution
     pub fn arm(mut self) -> ArmedJob<'a, T> {
         unsafe { bindings::drm_sched_job_arm(&mut self.0.job) };
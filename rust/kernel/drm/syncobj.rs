@@ -4,7 +4,8 @@
 //!
 //! C header: [`include/linux/drm/drm_syncobj.h`](../../../../include/linux/drm/drm_syncobj.h)
 
-use crate::{bindings, dma_fence::*, drm, error::Result, prelude::*};
+use crate::{bindings, dma_fence::*, drm, error::Result, prelude::*, types::Opaque};
+use core::marker::PhantomPinned;
 
 /// A DRM Sync Object
 ///
@@ -55,6 +56,88 @@ impl SyncObj {
         // This takes over the FenceChain ownership.
         unsafe { bindings::drm_syncobj_add_point(self.ptr, chain.into_raw(), fence.raw(), point) };
     }
+
+    /// Arranges for `callback` to be notified once a fence has been attached for the timeline
+    /// `point` (or, for point 0, once any fence is attached).
+    ///
+    /// Returns `None` if the point already has a fence, in which case the callback is dropped
+    /// without being notified. Otherwise, the callback stays armed until it fires or the returned
+    /// `PointWait` is dropped.
+    pub fn wait_for_point<T: PointWaitCallback>(
+        &self,
+        point: u64,
+        callback: T,
+    ) -> Result<Option<Pin<Box<PointWait<T>>>>> {
+        let wait = Pin::from(Box::try_new(PointWait {
+            cb: Opaque::uninit(),
+            syncobj: self.clone(),
+            callback,
+            _pin: PhantomPinned,
+        })?);
+
+        // SAFETY: `cb` lives in a pinned allocation that disarms it on drop, and `ptr` is valid
+        // per the type invariant.
+        let armed = unsafe {
+            bindings::drm_syncobj_add_wait_cb(
+                self.ptr,
+                wait.cb.get(),
+                point,
+                Some(point_wait_cb::<T>),
+            )
+        };
+
+        Ok(if armed { Some(wait) } else { None })
+    }
+}
+
+/// Trait for callbacks registered with `SyncObj::wait_for_point()`.
+pub trait PointWaitCallback: Send + Sync + Sized {
+    /// Called once the awaited point has a fence.
+    ///
+    /// This runs with the syncobj lock held, so it must not sleep or touch the syncobj.
+    fn point_available(&self);
+}
+
+/// A pending wait for a timeline point of a `SyncObj` to be attached.
+///
+/// # Invariants
+/// `cb` has been initialized by `drm_syncobj_add_wait_cb()` on `syncobj`.
+pub struct PointWait<T: PointWaitCallback> {
+    cb: Opaque<bindings::drm_syncobj_wait_cb>,
+    syncobj: SyncObj,
+    callback: T,
+    _pin: PhantomPinned,
+}
+
+impl<T: PointWaitCallback> PointWait<T> {
+    /// Returns the callback object.
+    pub fn callback(&self) -> &T {
+        &self.callback
+    }
+}
+
+impl<T: PointWaitCallback> Drop for PointWait<T> {
+    fn drop(&mut self) {
+        // SAFETY: `cb` was initialized per the type invariant, and this waits out a concurrent
+        // invocation of the callback, so it is safe to free afterwards.
+        unsafe { bindings::drm_syncobj_remove_wait_cb(self.syncobj.ptr, self.cb.get()) };
+    }
+}
+
+// SAFETY: The C side only touches `cb` under the syncobj lock, and T is Send + Sync.
+unsafe impl<T: PointWaitCallback> Send for PointWait<T> {}
+unsafe impl<T: PointWaitCallback> Sync for PointWait<T> {}
+
+unsafe extern "C" fn point_wait_cb<T: PointWaitCallback>(cb: *mut bindings::drm_syncobj_wait_cb) {
+    // SAFETY: All of our wait callbacks are embedded in a PointWait<T>, which is kept alive
+    // while armed.
+    let p = crate::container_of!(
+        cb as *const Opaque<bindings::drm_syncobj_wait_cb>,
+        PointWait<T>,
+        cb
+    );
+    // SAFETY: As above.
+    unsafe { (*p).callback.point_available() };
 }
 
 impl Drop for SyncObj {
@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! Interrupts.
+//!
+//! Interrupts are established from a device tree node and handled by a [`Handler`]. Handlers run
+//! in interrupt context and may defer work to a threaded bottom half, which runs in process context
+//! from the system task queue. While the bottom half is pending, the interrupt stays masked, so
+//! level-triggered sources do not fire again before they have been serviced.
+//!
+//! C headers: [`machine/intr.h`](../../../../arch/arm64/include/intr.h),
+//! [`sys/task.h`](../../../../sys/task.h)
+
+use crate::{
+    bindings,
+    error::{code::*, Result},
+    of,
+    str::CStr,
+    types::{ForeignOwnable, Opaque},
+};
+use alloc::boxed::Box;
+use core::{
+    ffi::c_void,
+    marker::PhantomData,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+/// The return value of an interrupt handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Return {
+    /// The interrupt was not from this device.
+    None,
+    /// The interrupt was handled.
+    Handled,
+    /// The interrupt was handled and the threaded handler should run. The interrupt is masked
+    /// until it returns.
+    WakeThread,
+    /// The interrupt was handled, but must stay masked, for example because the device is wedged.
+    Disable,
+}
+
+/// An interrupt handler.
+pub trait Handler {
+    /// The context data passed to the handler.
+    type Data: ForeignOwnable + Send + Sync;
+
+    /// Called in interrupt context. Must not sleep.
+    fn handle_irq(data: <Self::Data as ForeignOwnable>::Borrowed<'_>) -> Return;
+
+    /// Called in process context after [`Handler::handle_irq`] returned [`Return::WakeThread`].
+    ///
+    /// The interrupt is unmasked again afterwards unless this returns [`Return::Disable`].
+    fn handle_threaded_irq(_data: <Self::Data as ForeignOwnable>::Borrowed<'_>) -> Return {
+        Return::Handled
+    }
+}
+
+struct Inner {
+    /// Null until `arm_intr_establish_fdt_idx` returns, which may be after the first interrupt.
+    cookie: AtomicPtr<c_void>,
+    data: *const c_void,
+    task: Opaque<bindings::task>,
+    dying: AtomicBool,
+}
+
+/// An established interrupt.
+///
+/// The interrupt is disestablished when this is dropped, after any running handler and pending
+/// bottom half have completed. Only then is the handler data released, so anything it references
+/// (typically the device data) outlives the interrupt.
+pub struct Registration<T: Handler> {
+    inner: Box<Inner>,
+    _p: PhantomData<T>,
+}
+
+// SAFETY: The handler data is `Send` and `Sync`, and the C interrupt and task objects may be
+// disestablished from any thread.
+unsafe impl<T: Handler> Send for Registration<T> {}
+// SAFETY: `Registration` has no methods that take `&self`.
+unsafe impl<T: Handler> Sync for Registration<T> {}
+
+impl<T: Handler> Registration<T> {
+    /// Establishes the `index`-th interrupt of `node`, as listed in its `interrupts` property.
+    ///
+    /// `name` is shown in interrupt statistics.
+    pub fn try_new(
+        node: &of::Node,
+        index: usize,
+        name: &'static CStr,
+        data: T::Data,
+    ) -> Result<Self> {
+        let mut inner = Box::new(Inner {
+            cookie: AtomicPtr::new(core::ptr::null_mut()),
+            data: data.into_foreign(),
+            task: Opaque::uninit(),
+            dying: AtomicBool::new(false),
+        });
+        let arg = &mut *inner as *mut Inner as *mut c_void;
+
+        // SAFETY: `inner.task` is valid for writes, and `arg` stays valid until the task is
+        // removed in `drop`.
+        unsafe { bindings::task_set(inner.task.get(), Some(thread_callback::<T>), arg) };
+
+        // SAFETY: `arg` stays valid until the interrupt is disestablished in `drop`, and `name`
+        // is static.
+        let cookie = unsafe {
+            bindings::arm_intr_establish_fdt_idx(
+                node.handle(),
+                index as _,
+                (bindings::IPL_BIO | bindings::IPL_MPSAFE) as _,
+                Some(irq_callback::<T>),
+                arg,
+                name.as_char_ptr() as *mut _,
+            )
+        };
+        if cookie.is_null() {
+            // SAFETY: The interrupt was not established, so we still own the data.
+            unsafe { T::Data::from_foreign(inner.data) };
+            return Err(ENXIO);
+        }
+        inner.cookie.store(cookie, Ordering::Release);
+
+        Ok(Self {
+            inner,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<T: Handler> Drop for Registration<T> {
+    fn drop(&mut self) {
+        let inner = &*self.inner;
+        let cookie = inner.cookie.load(Ordering::Relaxed);
+
+        // Stop the bottom half from unmasking the interrupt again, wait for any running instance,
+        // then mask it and wait for the top half and any bottom half it queued in the meantime.
+        inner.dying.store(true, Ordering::Release);
+        // SAFETY: The task was initialized in `try_new`, and `cookie` is a valid established
+        // interrupt.
+        unsafe {
+            bindings::taskq_del_barrier(bindings::systq, inner.task.get());
+            bindings::arm_intr_disable(cookie);
+            bindings::intr_barrier(cookie);
+            bindings::taskq_del_barrier(bindings::systq, inner.task.get());
+        }
+
+        // SAFETY: The interrupt is masked, no handler is running or queued, and none can re-enable
+        // it, so it is safe to tear it down.
+        unsafe { bindings::arm_intr_disestablish_fdt(cookie) };
+
+        // SAFETY: `data` came from `into_foreign` in `try_new`, and no handler can use it anymore.
+        unsafe { T::Data::from_foreign(self.inner.data) };
+    }
+}
+
+unsafe extern "C" fn irq_callback<T: Handler>(arg: *mut c_void) -> core::ffi::c_int {
+    // SAFETY: `arg` is the `Inner` passed to `arm_intr_establish_fdt_idx`, which outlives the
+    // interrupt.
+    let inner = unsafe { &*(arg as *const Inner) };
+    // SAFETY: `data` came from `into_foreign` and is only released after the interrupt is gone.
+    let data = unsafe { T::Data::borrow(inner.data) };
+
+    let ret = T::handle_irq(data);
+    let cookie = inner.cookie.load(Ordering::Acquire);
+
+    if matches!(ret, Return::WakeThread | Return::Disable) && !cookie.is_null() {
+        // SAFETY: `cookie` is valid while the interrupt is established.
+        unsafe { bindings::arm_intr_disable(cookie) };
+    }
+    if ret == Return::WakeThread {
+        // SAFETY: `task` was initialized before the interrupt was established.
+        unsafe { bindings::task_add(bindings::systq, inner.task.get()) };
+    }
+
+    (ret != Return::None) as _
+}
+
+unsafe extern "C" fn thread_callback<T: Handler>(arg: *mut c_void) {
+    // SAFETY: `arg` is the `Inner` passed to `task_set`, which outlives the task.
+    let inner = unsafe { &*(arg as *const Inner) };
+    // SAFETY: `data` came from `into_foreign` and is only released after the task is gone.
+    let data = unsafe { T::Data::borrow(inner.data) };
+
+    let ret = T::handle_threaded_irq(data);
+    let cookie = inner.cookie.load(Ordering::Acquire);
+
+    if ret != Return::Disable && !cookie.is_null() && !inner.dying.load(Ordering::Acquire) {
+        // SAFETY: `cookie` is valid while the interrupt is established, and `drop` waits for
+        // this task before disestablishing it.
+        unsafe { bindings::arm_intr_enable(cookie) };
+    }
+}
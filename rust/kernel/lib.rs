@@ -27,6 +27,7 @@ pub mod io_buffer;
 pub mod io_mem;
 pub mod io_pgtable;
 pub mod ioctl;
+pub mod irq;
 pub mod of;
 pub mod platform;
 pub mod prelude;
@@ -40,6 +41,7 @@ pub mod time;
 pub mod tools;
 pub mod types;
 pub mod user_ptr;
+pub mod workqueue;
 pub mod xarray;
 
 pub use alloc;
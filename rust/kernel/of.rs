@@ -1,8 +1,13 @@
 // SPDX-License-Identifier: ISC
 
-use crate::{bindings, prelude::*};
+use crate::{bindings, c_str, fmt, prelude::*, str::CString};
 use alloc::{vec, vec::Vec};
 
+/// Default `#address-cells` value when a node does not specify one.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+/// Default `#size-cells` value when a node does not specify one.
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
 pub struct NodeIter {
     curr: *mut bindings::device_node,
     is_halt: bool,
@@ -36,6 +41,71 @@ impl Iterator for NodeIter {
     }
 }
 
+/// Iterator over the properties of a [`Node`], yielding each property name and value.
+pub struct PropertyIter {
+    node: Node,
+    name: [u8; bindings::OFMAXPARAM as usize],
+    is_halt: bool,
+}
+
+impl Iterator for PropertyIter {
+    type Item = (CString, Property);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.is_halt {
+            return None;
+        }
+
+        let mut next = [0u8; bindings::OFMAXPARAM as usize];
+        let len = unsafe {
+            bindings::OF_nextprop(
+                self.node.handle(),
+                self.name.as_mut_ptr() as *mut _,
+                next.as_mut_ptr() as *mut core::ffi::c_void,
+            )
+        };
+        if len < 0 || len as usize >= next.len() {
+            self.is_halt = true;
+            return None;
+        }
+        self.name = next;
+
+        let name = CStr::from_bytes_with_nul(&self.name[..=len as usize]).ok()?;
+        let prop = self.node.find_property(name)?;
+        Some((CString::try_from(name).ok()?, prop))
+    }
+}
+
+/// A decoded `reg` entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reg {
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// A decoded `ranges` entry, mapping a child bus range to the parent bus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Range {
+    pub child_addr: u64,
+    pub parent_addr: u64,
+    pub size: u64,
+}
+
+/// Decodes one big-endian value of `cells` 32-bit cells, advancing `data` past it.
+fn read_cells(data: &mut &[u8], cells: u32) -> Result<u64> {
+    let len = cells as usize * 4;
+    if cells > 2 || data.len() < len {
+        return Err(EINVAL);
+    }
+
+    let mut val = 0u64;
+    for cell in data[..len].chunks_exact(4) {
+        val = (val << 32) | u32::from_be_bytes(cell.try_into().or(Err(EINVAL))?) as u64;
+    }
+    *data = &data[len..];
+    Ok(val)
+}
+
 #[derive(Clone, Copy)]
 pub struct Node {
     pub raw_node: *mut bindings::device_node,
@@ -77,9 +147,50 @@ impl Node {
         unsafe { self.raw_node as usize as i32 }
     }
 
-    // pub fn full_name(&self) -> &CStr {
-    // unsafe { CStr::from_char_ptr(self.node().full_name) }
-    // }
+    /// Returns the node name, without the unit address.
+    pub fn name(&self) -> Result<CString> {
+        let prop = self.find_property(c_str!("name")).ok_or(ENOENT)?;
+        let name = CStr::from_bytes_with_nul(prop.value()).or(Err(EINVAL))?;
+        Ok(CString::try_from(name)?)
+    }
+
+    /// Returns the full path of the node, such as `/soc/gpu@206400000`.
+    ///
+    /// Unit addresses are rebuilt from the first `reg` entry of each node on the path.
+    pub fn full_name(&self) -> Result<CString> {
+        let mut parts = Vec::new();
+        let mut node = *self;
+        while let Some(parent) = node.parent() {
+            let name = node.name()?;
+            let part = match node.reg().ok().and_then(|r| r.first().copied()) {
+                Some(reg) => CString::try_from_fmt(fmt!("{}@{:x}", &*name, reg.addr))?,
+                None => name,
+            };
+            parts.push(part);
+            node = parent;
+        }
+
+        if parts.is_empty() {
+            return Ok(CString::try_from(c_str!("/"))?);
+        }
+
+        let mut path = Vec::new();
+        for part in parts.iter().rev() {
+            path.push(b'/');
+            path.extend_from_slice(part.as_bytes());
+        }
+        path.push(0);
+        Ok(CString::try_from(CStr::from_bytes_with_nul(&path).or(Err(EINVAL))?)?)
+    }
+
+    /// Returns an iterator over all properties of this node.
+    pub fn properties(&self) -> PropertyIter {
+        PropertyIter {
+            node: *self,
+            name: [0u8; bindings::OFMAXPARAM as usize],
+            is_halt: false,
+        }
+    }
 
     pub fn child(&self) -> NodeIter {
         let handle = self.handle();
@@ -101,6 +212,21 @@ impl Node {
         }
     }
 
+    /// Matches the node's `compatible` list against `table`, returning the data of the first
+    /// match.
+    ///
+    /// Unlike [`compatible_info!`], entries are tried in the node's order, so the most specific
+    /// compatible string wins and more generic ones act as fallbacks.
+    pub fn match_compatible<'a, T>(&self, table: &'a [(&CStr, Option<T>)]) -> Option<&'a T> {
+        let compat = self.read_string_array(c_str!("compatible")).ok()?;
+        compat.iter().find_map(|c| {
+            table
+                .iter()
+                .find(|(name, _)| name.as_bytes() == c.as_bytes())
+                .and_then(|(_, data)| data.as_ref())
+        })
+    }
+
     pub fn is_compatible(&self, name: &CStr) -> i32 {
         unsafe {
             let handle = self.handle();
@@ -143,6 +269,105 @@ impl Node {
             .map_or(Ok(None), |prop| Ok(Some(prop.try_into()?)))
     }
 
+    /// Reads a string list property, such as `compatible` or `reg-names`.
+    pub fn read_string_array(&self, name: &CStr) -> Result<Vec<CString>> {
+        let prop = self.find_property(name).ok_or(ENOENT)?;
+        let value = prop.value();
+        if value.last() != Some(&0) {
+            return Err(EINVAL);
+        }
+
+        let mut ret = Vec::new();
+        for s in value[..value.len() - 1].split(|&c| c == 0) {
+            let mut buf = Vec::new();
+            buf.extend_from_slice(s);
+            buf.push(0);
+            ret.push(CString::try_from(CStr::from_bytes_with_nul(&buf).or(Err(EINVAL))?)?);
+        }
+        Ok(ret)
+    }
+
+    /// Reads a property as an array of 32-bit cells.
+    pub fn read_u32_array(&self, name: &CStr) -> Result<Vec<u32>> {
+        self.get_property(name)
+    }
+
+    /// Reads a property as an array of 64-bit values.
+    pub fn read_u64_array(&self, name: &CStr) -> Result<Vec<u64>> {
+        self.get_property(name)
+    }
+
+    /// Returns the `#address-cells` value this node specifies for its children.
+    pub fn address_cells(&self) -> u32 {
+        self.get_property(c_str!("#address-cells"))
+            .unwrap_or(DEFAULT_ADDRESS_CELLS)
+    }
+
+    /// Returns the `#size-cells` value this node specifies for its children.
+    pub fn size_cells(&self) -> u32 {
+        self.get_property(c_str!("#size-cells"))
+            .unwrap_or(DEFAULT_SIZE_CELLS)
+    }
+
+    /// Decodes the `reg` property, using the cell sizes of the parent node.
+    pub fn reg(&self) -> Result<Vec<Reg>> {
+        let parent = self.parent().ok_or(EINVAL)?;
+        let (ac, sc) = (parent.address_cells(), parent.size_cells());
+        let prop = self.find_property(c_str!("reg")).ok_or(ENOENT)?;
+
+        let entry = (ac + sc) as usize * 4;
+        if entry == 0 || prop.len() % entry != 0 {
+            return Err(EINVAL);
+        }
+
+        let mut data = prop.value();
+        let mut ret = Vec::new();
+        while !data.is_empty() {
+            let addr = read_cells(&mut data, ac)?;
+            let size = read_cells(&mut data, sc)?;
+            ret.push(Reg { addr, size });
+        }
+        Ok(ret)
+    }
+
+    /// Returns the `reg` entry named `name` in `reg-names`, along with its index.
+    pub fn reg_by_name(&self, name: &CStr) -> Result<(usize, Reg)> {
+        let names = self.read_string_array(c_str!("reg-names"))?;
+        let index = names
+            .iter()
+            .position(|n| n.as_bytes() == name.as_bytes())
+            .ok_or(ENOENT)?;
+        let reg = *self.reg()?.get(index).ok_or(EINVAL)?;
+        Ok((index, reg))
+    }
+
+    /// Decodes the `ranges` property. An empty `ranges` (identity mapping) yields no entries.
+    pub fn ranges(&self) -> Result<Vec<Range>> {
+        let parent = self.parent().ok_or(EINVAL)?;
+        let (cac, csc) = (self.address_cells(), self.size_cells());
+        let pac = parent.address_cells();
+        let prop = self.find_property(c_str!("ranges")).ok_or(ENOENT)?;
+
+        let entry = (cac + pac + csc) as usize * 4;
+        if prop.len() != 0 && (entry == 0 || prop.len() % entry != 0) {
+            return Err(EINVAL);
+        }
+
+        let mut data = prop.value();
+        let mut ret = Vec::new();
+        while !data.is_empty() {
+            let child_addr = read_cells(&mut data, cac)?;
+            let parent_addr = read_cells(&mut data, pac)?;
+            let size = read_cells(&mut data, csc)?;
+            ret.push(Range {
+                child_addr,
+                parent_addr,
+                size,
+            });
+        }
+        Ok(ret)
+    }
+
     pub fn property_match_string(&self, propname: &CStr, name: &CStr) -> i32 {
         unsafe {
             let idx = bindings::OF_getindex(self.handle(), name.as_char_ptr() as *mut _, propname.as_char_ptr() as *mut _);
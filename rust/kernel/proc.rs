@@ -4,7 +4,7 @@
 //!
 //! C header: [`include/linux/sched.h`](../../../../include/linux/sched.h).
 
-use crate::{bindings, types::Opaque};
+use crate::{bindings, str::CStr, types::Opaque};
 use core::{marker::PhantomData, ops::Deref, ptr};
 
 /// Returns the currently running proc.
@@ -132,6 +132,13 @@ impl Proc {
         unsafe { *ptr::addr_of!((*(*self.0.get()).p_p).ps_pid) }
     }
 
+    /// Returns the command name of the process the given proc belongs to.
+    pub fn comm(&self) -> &CStr {
+        // SAFETY: By the type invariant, we know that `self.0` is a valid proc. The command name
+        // of a valid process is always NUL-terminated.
+        unsafe { CStr::from_char_ptr(ptr::addr_of!((*(*self.0.get()).p_p).ps_comm).cast()) }
+    }
+
     /// Determines whether the given proc has pending signals.
     pub fn signal_pending(&self) -> bool {
         // SAFETY: By the type invariant, we know that `self.0` is valid.
@@ -19,7 +19,7 @@ use no_lockdep as lockdep;
 
 pub use arc::{Arc, ArcBorrow, UniqueArc};
 pub use condvar::CondVar;
-pub use lock::{mutex::Mutex, spinlock::SpinLock};
+pub use lock::{mutex::Mutex, rwlock::RwLock, spinlock::SpinLock};
 pub use lockdep::{LockClassKey, StaticLockClassKey};
 pub use locked_by::LockedBy;
 
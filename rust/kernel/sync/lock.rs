@@ -13,6 +13,7 @@ use core::{cell::UnsafeCell, marker::PhantomData, marker::PhantomPinned};
 use macros::pin_data;
 
 pub mod mutex;
+pub mod rwlock;
 pub mod spinlock;
 
 /// The "backend" of a lock.
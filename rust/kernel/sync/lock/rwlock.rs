@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! A kernel reader-writer lock.
+//!
+//! This module allows Rust code to use the kernel's `struct rwlock` with both shared (read) and
+//! exclusive (write) ownership.
+
+use super::{Guard, Lock};
+use crate::bindings;
+use core::marker::PhantomData;
+
+/// Creates a [`RwLock`] initialiser with the given name and a newly-created lock class.
+///
+/// It uses the name if one is given, otherwise it generates one based on the file name and line
+/// number.
+#[macro_export]
+macro_rules! new_rwlock {
+    ($inner:expr $(, $name:literal)? $(,)?) => {
+        $crate::sync::RwLock::new_with_key(
+            $inner, $crate::optional_name!($($name)?), $crate::static_lock_class!())
+    };
+}
+
+/// Creates a [`RwLock`] initialiser with the given name and a newly-created lock class,
+/// given an initialiser for the inner type.
+///
+/// It uses the name if one is given, otherwise it generates one based on the file name and line
+/// number.
+#[macro_export]
+macro_rules! new_rwlock_pinned {
+    ($inner:expr $(, $name:literal)? $(,)?) => {
+        $crate::sync::RwLock::pin_init_with_key(
+            $inner, $crate::optional_name!($($name)?), $crate::static_lock_class!())
+    };
+}
+
+/// A reader-writer lock.
+///
+/// Exposes the kernel's [`struct rwlock`]. Any number of threads may hold the lock for reading at
+/// the same time, but a writer excludes all readers and other writers. Both readers and writers
+/// may block (sleep), so [`RwLock`] needs to be used with care in atomic contexts.
+///
+/// Exclusive access is obtained with [`RwLock::write`] (or the generic [`Lock::lock`]) and yields
+/// the same [`Guard`] type as a [`Mutex`](super::mutex::Mutex), so [`LockedBy`] and
+/// [`CondVar`] work unchanged. Shared access is obtained with [`RwLock::read`].
+///
+/// Instances of [`RwLock`] need a lock class and to be pinned. The recommended way to create such
+/// instances is with the [`pin_init`](crate::pin_init) and [`new_rwlock`] macros.
+///
+/// # Examples
+///
+/// ```
+/// use kernel::sync::RwLock;
+///
+/// struct Example {
+///     a: u32,
+///     b: u32,
+/// }
+///
+/// fn sum(m: &RwLock<Example>) -> u32 {
+///     let guard = m.read();
+///     guard.a + guard.b
+/// }
+///
+/// fn bump(m: &RwLock<Example>) {
+///     let mut guard = m.write();
+///     guard.a += 10;
+///     guard.b += 20;
+/// }
+/// ```
+///
+/// [`struct rwlock`]: ../../../../sys/rwlock.h
+/// [`LockedBy`]: crate::sync::LockedBy
+/// [`CondVar`]: crate::sync::CondVar
+pub type RwLock<T> = super::Lock<T, RwLockBackend>;
+
+/// A kernel `struct rwlock` lock backend.
+///
+/// The [`super::Backend`] operations take the lock for writing.
+pub struct RwLockBackend;
+
+// SAFETY: The underlying kernel `struct rwlock` object ensures mutual exclusion when taken for
+// writing. `relock` uses the default implementation that always calls the same locking method.
+unsafe impl super::Backend for RwLockBackend {
+    type State = bindings::rwlock;
+    type GuardState = ();
+
+    unsafe fn init(
+        ptr: *mut Self::State,
+        name: *const core::ffi::c_char,
+        _key: *mut bindings::lock_class_key,
+    ) {
+        // SAFETY: The safety requirements ensure that `ptr` is valid for writes, and `name` is
+        // valid for read indefinitely.
+        unsafe { bindings::BINDING_rw_init(ptr, name) }
+    }
+
+    unsafe fn lock(ptr: *mut Self::State) -> Self::GuardState {
+        // SAFETY: The safety requirements of this function ensure that `ptr` points to valid
+        // memory, and that it has been initialised before.
+        unsafe { bindings::rw_enter_write(ptr) };
+    }
+
+    unsafe fn unlock(ptr: *mut Self::State, _guard_state: &Self::GuardState) {
+        // SAFETY: The safety requirements of this function ensure that `ptr` is valid and that the
+        // caller is the owner of the lock.
+        unsafe { bindings::rw_exit_write(ptr) };
+    }
+}
+
+impl<T: ?Sized> Lock<T, RwLockBackend> {
+    /// Acquires the lock for writing and gives the caller exclusive access to the data.
+    pub fn write(&self) -> Guard<'_, T, RwLockBackend> {
+        self.lock()
+    }
+}
+
+impl<T: ?Sized + Sync> Lock<T, RwLockBackend> {
+    /// Acquires the lock for reading and gives the caller shared access to the data.
+    ///
+    /// Other readers may hold the lock at the same time, so this requires `T: Sync`.
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        // SAFETY: The constructor of the type calls `init`, so the existence of the object proves
+        // that `init` was called.
+        unsafe { bindings::rw_enter_read(self.state.get()) };
+        ReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+/// A shared (read) guard for a [`RwLock`].
+///
+/// Releases the read lock when it goes out of scope and only provides shared access to the
+/// protected data.
+#[must_use = "the lock unlocks immediately when the guard is unused"]
+pub struct ReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*mut ()>,
+}
+
+// SAFETY: `ReadGuard` only hands out shared references, so it is sync when the data is.
+unsafe impl<T: Sync + ?Sized> Sync for ReadGuard<'_, T> {}
+
+impl<T: ?Sized> core::ops::Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The caller holds the lock for reading, so no writer can exist.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: The guard holds the lock for reading, so it is safe to release it.
+        unsafe { bindings::rw_exit_read(self.lock.state.get()) };
+    }
+}
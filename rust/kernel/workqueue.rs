@@ -0,0 +1,384 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! Deferred work.
+//!
+//! Work items are embedded in reference-counted objects and queued onto kernel task queues, where
+//! they run later in process context, optionally after a delay. While an item is queued, the queue
+//! holds a reference to the [`Arc`] that owns it, so the object cannot go away under the task.
+//!
+//! C headers: [`sys/task.h`](../../../../sys/task.h),
+//! [`sys/timeout.h`](../../../../sys/timeout.h)
+
+use crate::{
+    bindings,
+    error::{code::*, Result},
+    init::{self, PinInit},
+    str::CStr,
+    sync::Arc,
+    types::{ForeignOwnable, Opaque},
+};
+use core::{
+    marker::PhantomData,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicPtr, Ordering},
+    time::Duration,
+};
+
+/// An object that can be run from a task queue.
+pub trait WorkItem: Send + Sync + Sized + 'static {
+    /// Runs the work item. Called in process context with the reference that was queued.
+    fn run(this: Arc<Self>);
+}
+
+/// An object that embeds a [`Work`] item.
+pub trait HasWork: WorkItem {
+    /// Returns the embedded work item.
+    fn work(&self) -> &Work<Self>;
+}
+
+/// An object that embeds a [`DelayedWork`] item.
+pub trait HasDelayedWork: WorkItem {
+    /// Returns the embedded delayed work item.
+    fn delayed_work(&self) -> &DelayedWork<Self>;
+}
+
+/// A kernel task queue.
+///
+/// Wraps the C `struct taskq`.
+#[repr(transparent)]
+pub struct Queue(Opaque<bindings::taskq>);
+
+// SAFETY: Task queues are internally locked and may be used from any thread.
+unsafe impl Send for Queue {}
+// SAFETY: Task queues are internally locked and may be used from any thread.
+unsafe impl Sync for Queue {}
+
+impl Queue {
+    /// Uses the provided `struct taskq` with Rust.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that `ptr` points at a valid task queue that remains valid for the
+    /// lifetime `'a`.
+    pub unsafe fn from_raw<'a>(ptr: *const bindings::taskq) -> &'a Queue {
+        // SAFETY: `Queue` is a transparent wrapper, and the caller guarantees validity.
+        unsafe { &*ptr.cast::<Queue>() }
+    }
+
+    fn as_ptr(&self) -> *mut bindings::taskq {
+        self.0.get()
+    }
+
+    /// Queues a work item to run as soon as possible.
+    ///
+    /// Returns `false` if the item was already queued, in which case `item` is dropped and the
+    /// previously queued reference will run it.
+    pub fn enqueue<T: HasWork>(&self, item: Arc<T>) -> bool {
+        let work: *const Work<T> = item.work();
+        let ptr = item.into_foreign();
+        // SAFETY: `ptr` holds a reference to the object that contains `work`, keeping it alive.
+        unsafe { (*work).enqueue_raw(self, ptr) }
+    }
+
+    /// Queues a delayed work item to run after `delay` has elapsed.
+    ///
+    /// Returns `false` if the item was already queued (or waiting for its delay), in which case
+    /// `item` is dropped and the delay is not changed.
+    pub fn enqueue_delayed<T: HasDelayedWork>(&self, item: Arc<T>, delay: Duration) -> bool {
+        let dwork: *const DelayedWork<T> = item.delayed_work();
+        let ptr = item.into_foreign();
+        // SAFETY: `ptr` holds a reference to the object that contains `dwork`, keeping it alive.
+        unsafe { (*dwork).enqueue_raw(self, ptr, delay) }
+    }
+}
+
+/// Returns the shared system task queue.
+///
+/// Work run from here should not sleep for long, since it delays unrelated work.
+pub fn system() -> &'static Queue {
+    // SAFETY: `systq` is created at boot and never destroyed.
+    unsafe { Queue::from_raw(bindings::systq) }
+}
+
+/// An owned task queue with its own worker threads, destroyed when dropped.
+pub struct BoxedQueue(NonNull<bindings::taskq>);
+
+// SAFETY: Task queues are internally locked and may be used from any thread.
+unsafe impl Send for BoxedQueue {}
+// SAFETY: Task queues are internally locked and may be used from any thread.
+unsafe impl Sync for BoxedQueue {}
+
+impl BoxedQueue {
+    /// Creates a new task queue with the given name and number of worker threads.
+    pub fn try_new(name: &'static CStr, nthreads: u32) -> Result<BoxedQueue> {
+        // SAFETY: `name` is a valid C string with static lifetime.
+        let tq = unsafe {
+            bindings::taskq_create(
+                name.as_char_ptr(),
+                nthreads,
+                bindings::IPL_NONE as _,
+                bindings::TASKQ_MPSAFE,
+            )
+        };
+        Ok(BoxedQueue(NonNull::new(tq).ok_or(ENOMEM)?))
+    }
+}
+
+impl core::ops::Deref for BoxedQueue {
+    type Target = Queue;
+
+    fn deref(&self) -> &Queue {
+        // SAFETY: The task queue is valid until we destroy it in `drop`.
+        unsafe { Queue::from_raw(self.0.as_ptr()) }
+    }
+}
+
+impl Drop for BoxedQueue {
+    fn drop(&mut self) {
+        // SAFETY: We own the task queue. Queued items hold their own references, and
+        // `taskq_destroy` waits for all of them to run.
+        unsafe { bindings::taskq_destroy(self.0.as_ptr()) };
+    }
+}
+
+/// A work item, embedded in an object of type `T`.
+///
+/// # Invariants
+///
+/// `task` is initialised with [`run_callback`] and a pointer to this `Work` as its argument.
+/// `pending` is either null or holds a reference from `Arc::<T>::into_foreign`, which is owned by
+/// the queue while the task is queued or waiting for its timeout.
+#[repr(C)]
+pub struct Work<T: WorkItem> {
+    task: Opaque<bindings::task>,
+    queue: AtomicPtr<bindings::taskq>,
+    pending: AtomicPtr<core::ffi::c_void>,
+    _p: PhantomData<T>,
+}
+
+// SAFETY: All mutable state is either atomic or owned by the task queue.
+unsafe impl<T: WorkItem> Send for Work<T> {}
+// SAFETY: All mutable state is either atomic or owned by the task queue.
+unsafe impl<T: WorkItem> Sync for Work<T> {}
+
+unsafe extern "C" fn run_callback<T: WorkItem>(arg: *mut core::ffi::c_void) {
+    // SAFETY: `arg` points to the `Work` that was queued, which is kept alive by the pending
+    // reference until we take it below.
+    let work = unsafe { &*(arg as *const Work<T>) };
+    let ptr = work.pending.swap(ptr::null_mut(), Ordering::AcqRel);
+    if ptr.is_null() {
+        return;
+    }
+    // SAFETY: Non-null pending pointers come from `Arc::<T>::into_foreign`.
+    T::run(unsafe { Arc::<T>::from_foreign(ptr) });
+}
+
+impl<T: WorkItem> Work<T> {
+    /// Constructs a new work item initialiser.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> impl PinInit<Self> {
+        // SAFETY: `init_raw` fully initialises the slot and never fails.
+        unsafe {
+            init::pin_init_from_closure(move |slot: *mut Self| {
+                Self::init_raw(slot);
+                Ok(())
+            })
+        }
+    }
+
+    /// Initialises a work item in place.
+    ///
+    /// # Safety
+    ///
+    /// `slot` must be valid for writes and pinned.
+    unsafe fn init_raw(slot: *mut Self) {
+        // SAFETY: The caller guarantees `slot` is valid for writes.
+        unsafe {
+            ptr::addr_of_mut!((*slot).queue).write(AtomicPtr::new(ptr::null_mut()));
+            ptr::addr_of_mut!((*slot).pending).write(AtomicPtr::new(ptr::null_mut()));
+            bindings::task_set(
+                Opaque::raw_get(ptr::addr_of!((*slot).task)),
+                Some(run_callback::<T>),
+                slot as *mut _,
+            );
+        }
+    }
+
+    /// Claims the pending slot for `ptr`, returning `false` (and dropping `ptr`) if the item is
+    /// already queued.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `Arc::<T>::into_foreign` for the object containing this `Work`.
+    unsafe fn claim(&self, queue: &Queue, ptr: *const core::ffi::c_void) -> bool {
+        if self
+            .pending
+            .compare_exchange(
+                ptr::null_mut(),
+                ptr as *mut _,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_err()
+        {
+            // SAFETY: We still own `ptr` since it was not stored.
+            unsafe { Arc::<T>::from_foreign(ptr) };
+            return false;
+        }
+        self.queue.store(queue.as_ptr(), Ordering::Release);
+        true
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `Arc::<T>::into_foreign` for the object containing this `Work`.
+    unsafe fn enqueue_raw(&self, queue: &Queue, ptr: *const core::ffi::c_void) -> bool {
+        // SAFETY: Forwarded from our caller.
+        if !unsafe { self.claim(queue, ptr) } {
+            return false;
+        }
+        // SAFETY: The task was initialised by `new`, and the pending reference keeps it alive
+        // until it runs.
+        unsafe { bindings::task_add(queue.as_ptr(), self.task.get()) };
+        true
+    }
+
+    /// Drops the pending reference, if any.
+    fn release(&self) -> bool {
+        let ptr = self.pending.swap(ptr::null_mut(), Ordering::AcqRel);
+        if ptr.is_null() {
+            false
+        } else {
+            // SAFETY: Non-null pending pointers come from `Arc::<T>::into_foreign`.
+            unsafe { Arc::<T>::from_foreign(ptr) };
+            true
+        }
+    }
+
+    /// Cancels the work item if it is queued but not yet running.
+    ///
+    /// Returns `true` if it was cancelled. Taking an `Arc` guarantees that dropping the queued
+    /// reference cannot free the object.
+    pub fn cancel(this: &Arc<T>) -> bool
+    where
+        T: HasWork,
+    {
+        this.work().cancel_raw(false)
+    }
+
+    /// Cancels the work item and waits for a running instance to finish.
+    ///
+    /// Must not be called from the work item itself.
+    pub fn cancel_sync(this: &Arc<T>) -> bool
+    where
+        T: HasWork,
+    {
+        this.work().cancel_raw(true)
+    }
+
+    fn cancel_raw(&self, sync: bool) -> bool {
+        let queue = self.queue.load(Ordering::Acquire);
+        if queue.is_null() {
+            return false;
+        }
+        // SAFETY: `queue` is the queue this task was last added to, which outlives its tasks.
+        unsafe {
+            if sync {
+                bindings::taskq_del_barrier(queue, self.task.get());
+            } else if bindings::task_del(queue, self.task.get()) == 0 {
+                return false;
+            }
+        }
+        self.release()
+    }
+}
+
+/// A work item that is queued after a delay, embedded in an object of type `T`.
+///
+/// # Invariants
+///
+/// `timeout` is initialised with [`timeout_callback`] and a pointer to `work` as its argument.
+#[repr(C)]
+pub struct DelayedWork<T: WorkItem> {
+    work: Work<T>,
+    timeout: Opaque<bindings::timeout>,
+}
+
+unsafe extern "C" fn timeout_callback<T: WorkItem>(arg: *mut core::ffi::c_void) {
+    // SAFETY: `arg` points to the `Work` that was armed, which is kept alive by the pending
+    // reference.
+    let work = unsafe { &*(arg as *const Work<T>) };
+    let queue = work.queue.load(Ordering::Acquire);
+    // SAFETY: `queue` was stored before the timeout was armed.
+    unsafe { bindings::task_add(queue, work.task.get()) };
+}
+
+impl<T: WorkItem> DelayedWork<T> {
+    /// Constructs a new delayed work item initialiser.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> impl PinInit<Self> {
+        // SAFETY: The closure fully initialises the slot and never fails.
+        unsafe {
+            init::pin_init_from_closure(move |slot: *mut Self| {
+                let work = ptr::addr_of_mut!((*slot).work);
+                Work::init_raw(work);
+                bindings::timeout_set(
+                    Opaque::raw_get(ptr::addr_of!((*slot).timeout)),
+                    Some(timeout_callback::<T>),
+                    work as *mut _,
+                );
+                Ok(())
+            })
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `Arc::<T>::into_foreign` for the object containing this item.
+    unsafe fn enqueue_raw(
+        &self,
+        queue: &Queue,
+        ptr: *const core::ffi::c_void,
+        delay: Duration,
+    ) -> bool {
+        // SAFETY: Forwarded from our caller.
+        if !unsafe { self.work.claim(queue, ptr) } {
+            return false;
+        }
+        let msecs = delay.as_millis().try_into().unwrap_or(u64::MAX);
+        // SAFETY: The timeout was initialised by `new`, and the pending reference keeps it alive
+        // until it fires and the task runs.
+        unsafe { bindings::timeout_add_msec(self.timeout.get(), msecs) };
+        true
+    }
+
+    /// Cancels the delayed work item if its delay has not elapsed or it has not started running.
+    ///
+    /// Returns `true` if it was cancelled.
+    pub fn cancel(this: &Arc<T>) -> bool
+    where
+        T: HasDelayedWork,
+    {
+        let dwork = this.delayed_work();
+        // SAFETY: The timeout was initialised by `new`.
+        if unsafe { bindings::timeout_del(dwork.timeout.get()) } != 0 {
+            return dwork.work.release();
+        }
+        dwork.work.cancel_raw(false)
+    }
+
+    /// Cancels the delayed work item and waits for a running instance to finish.
+    ///
+    /// Must not be called from the work item itself.
+    pub fn cancel_sync(this: &Arc<T>) -> bool
+    where
+        T: HasDelayedWork,
+    {
+        let dwork = this.delayed_work();
+        // SAFETY: The timeout was initialised by `new`.
+        if unsafe { bindings::timeout_del_barrier(dwork.timeout.get()) } != 0 {
+            return dwork.work.release();
+        }
+        dwork.work.cancel_raw(true)
+    }
+}
@@ -11,6 +11,8 @@ use crate::{
 };
 use core::{
     marker::{PhantomData, PhantomPinned},
+    mem::ManuallyDrop,
+    ops::{Bound, RangeBounds},
     pin::Pin,
     ptr::NonNull,
 };
@@ -50,6 +52,156 @@ impl<'a, T: ForeignOwnable> Drop for Guard<'a, T> {
     }
 }
 
+/// A locked view of an `XArray`, which holds the `XArray` lock until dropped.
+///
+/// This allows iterating and searching the array without dropping the lock between entries. The
+/// lock is a spinning mutex, so callers must not sleep while holding it.
+pub struct Locked<'a, T: ForeignOwnable>(Pin<&'a XArray<T>>);
+
+impl<'a, T: ForeignOwnable> Locked<'a, T> {
+    /// Returns the first entry with an index in `range`, along with its index.
+    pub fn find(&self, range: impl RangeBounds<usize>) -> Option<(usize, T::Borrowed<'_>)> {
+        let (start, end) = bounds(range)?;
+        // SAFETY: We hold the lock, so the entry remains in the `XArray` while borrowed.
+        let (index, p) = unsafe { self.0.next_locked(start, end) }?;
+        // SAFETY: Entries are always valid `ForeignOwnable` pointers, and we hold the lock for
+        // the duration of the borrow.
+        Some((index, unsafe { T::borrow(p) }))
+    }
+
+    /// Returns an iterator over all entries in index order, along with their indices.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            xa: self.0,
+            next: 0,
+            end: usize::MAX,
+            _lock: PhantomData,
+        }
+    }
+
+    /// Returns an iterator over the entries with an index in `range`.
+    pub fn range(&self, range: impl RangeBounds<usize>) -> Iter<'_, T> {
+        let (next, end) = bounds(range).unwrap_or((1, 0));
+        Iter {
+            xa: self.0,
+            next,
+            end,
+            _lock: PhantomData,
+        }
+    }
+
+    /// Returns the number of entries in the array.
+    ///
+    /// Reserved slots without a value are not counted.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Returns `true` if the array has no entries.
+    ///
+    /// Reserved slots without a value are not counted.
+    pub fn is_empty(&self) -> bool {
+        self.find(..).is_none()
+    }
+
+    /// Calls `f` with a mutable reference to every entry, in index order.
+    ///
+    /// `f` runs with the lock held and must not sleep. To replace an entry, `f` returns the new
+    /// value, and the previous one is dropped with the lock briefly released before iteration
+    /// resumes at the next index. Assigning through the reference instead also works, but drops
+    /// the previous value with the lock held.
+    pub fn for_each_mut(&mut self, mut f: impl FnMut(usize, &mut T) -> Option<T>) {
+        let mut next = 0;
+
+        // SAFETY: We hold the lock.
+        while let Some((index, p)) = unsafe { self.0.next_locked(next, usize::MAX) } {
+            // SAFETY: Entries are always valid `ForeignOwnable` pointers. We hold the lock and
+            // `&mut self`, so nobody else can access this entry while we own it temporarily. The
+            // `ManuallyDrop` keeps the entry alive in the array if `f` panics.
+            let mut value = ManuallyDrop::new(unsafe { T::from_foreign(p) });
+            let old = f(index, &mut value).map(|new| core::mem::replace(&mut *value, new));
+
+            // `p` was converted back exactly once above. Whatever now occupies the slot, be it the
+            // original entry, a returned replacement or a value assigned by `f`, goes back into
+            // the array, and any previous value is either in `old` or was already dropped by `f`.
+            let cur = ManuallyDrop::into_inner(value).into_foreign();
+            if cur != p {
+                // SAFETY: `index` is present, so this replaces the entry in place without
+                // allocating.
+                unsafe { bindings::__xa_store(self.0.xa.get(), index as _, cur as *mut _, 0) };
+            }
+
+            if let Some(old) = old {
+                // Drop the old value unlocked, in case its destructor sleeps.
+                // SAFETY: We hold the lock, and take it again before continuing.
+                unsafe { bindings::BINDINGS_xa_unlock(self.0.xa.get()) };
+                drop(old);
+                // SAFETY: `self.0.xa` is always valid by the type invariant.
+                unsafe { bindings::BINDINGS_xa_lock(self.0.xa.get()) };
+            }
+
+            next = match index.checked_add(1) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+    }
+}
+
+impl<'a, T: ForeignOwnable> Drop for Locked<'a, T> {
+    fn drop(&mut self) {
+        // SAFETY: The XArray we have a reference to owns the C xarray object.
+        unsafe { bindings::BINDINGS_xa_unlock(self.0.xa.get()) };
+    }
+}
+
+/// An iterator over the entries of a [`Locked`] `XArray`.
+pub struct Iter<'a, T: ForeignOwnable> {
+    xa: Pin<&'a XArray<T>>,
+    next: usize,
+    end: usize,
+    _lock: PhantomData<&'a Locked<'a, T>>,
+}
+
+impl<'a, T: ForeignOwnable> Iterator for Iter<'a, T> {
+    type Item = (usize, T::Borrowed<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next > self.end {
+            return None;
+        }
+        // SAFETY: The `Locked` this iterator borrows from holds the lock.
+        let (index, p) = unsafe { self.xa.next_locked(self.next, self.end) }?;
+        // Stop after `usize::MAX` instead of wrapping around.
+        match index.checked_add(1) {
+            Some(next) => self.next = next,
+            None => self.end = 0,
+        }
+        // SAFETY: Entries are always valid `ForeignOwnable` pointers, and the lock is held for
+        // `'a`.
+        Some((index, unsafe { T::borrow(p) }))
+    }
+}
+
+/// Converts a range of indices into inclusive bounds, returning `None` if it is empty.
+fn bounds(range: impl RangeBounds<usize>) -> Option<(usize, usize)> {
+    let start = match range.start_bound() {
+        Bound::Included(&s) => s,
+        Bound::Excluded(&s) => s.checked_add(1)?,
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&e) => e,
+        Bound::Excluded(&e) => e.checked_sub(1)?,
+        Bound::Unbounded => usize::MAX,
+    };
+    if start > end {
+        None
+    } else {
+        Some((start, end))
+    }
+}
+
 /// Represents a reserved slot in an `XArray`, which does not yet have a value but has an assigned
 /// index and may not be allocated by any other user. If the Reservation is dropped without
 /// being filled, the entry is marked as available again.
@@ -177,6 +329,96 @@ impl<T: ForeignOwnable> XArray<T> {
         })
     }
 
+    /// Takes the `XArray` lock and returns a [`Locked`] view for iteration and searching.
+    ///
+    /// Like a `Guard`, this blocks all other actions on the `XArray` until dropped.
+    pub fn lock(self: Pin<&Self>) -> Locked<'_, T> {
+        // SAFETY: `self.xa` is always valid by the type invariant.
+        unsafe { bindings::BINDINGS_xa_lock(self.xa.get()) };
+        Locked(self)
+    }
+
+    /// Looks up the first entry with an index in `range`, returning its index and a `Guard`.
+    ///
+    /// As with [`XArray::get`], the guard blocks all other actions on the `XArray`.
+    pub fn find(self: Pin<&Self>, range: impl RangeBounds<usize>) -> Option<(usize, Guard<'_, T>)> {
+        let (start, end) = bounds(range)?;
+
+        // SAFETY: `self.xa` is always valid by the type invariant.
+        unsafe { bindings::BINDINGS_xa_lock(self.xa.get()) };
+
+        // SAFETY: `self.xa` is always valid by the type invariant.
+        let guard = ScopeGuard::new(|| unsafe { bindings::BINDINGS_xa_unlock(self.xa.get()) });
+
+        // SAFETY: We hold the lock.
+        let (index, p) = unsafe { self.next_locked(start, end) }?;
+
+        let p = NonNull::new(p as *mut T)?;
+        guard.dismiss();
+        Some((index, Guard(p, self)))
+    }
+
+    /// Returns the number of entries in the array.
+    pub fn len(self: Pin<&Self>) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns `true` if the array has no entries.
+    pub fn is_empty(self: Pin<&Self>) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Removes and drops all entries, returning how many there were.
+    ///
+    /// Entries are removed one at a time and dropped with the lock released, so their destructors
+    /// may sleep.
+    pub fn clear(self: Pin<&Self>) -> usize {
+        let mut count = 0;
+
+        loop {
+            // SAFETY: `self.xa` is always valid by the type invariant.
+            unsafe { bindings::BINDINGS_xa_lock(self.xa.get()) };
+            // SAFETY: We hold the lock, and we erase the entry before releasing it.
+            let p = unsafe {
+                self.next_locked(0, usize::MAX)
+                    .map(|(index, _)| bindings::__xa_erase(self.xa.get(), index as _))
+            };
+            // SAFETY: We took the lock above.
+            unsafe { bindings::BINDINGS_xa_unlock(self.xa.get()) };
+
+            match p {
+                // SAFETY: Entries are always valid `ForeignOwnable` pointers, and we just took
+                // ownership by removing it.
+                Some(p) if !p.is_null() => unsafe { T::from_foreign(p) },
+                _ => break,
+            };
+            count += 1;
+        }
+
+        count
+    }
+
+    /// Returns the first entry with an index in `start..=end`, along with its index.
+    ///
+    /// # Safety
+    ///
+    /// The caller must hold the `XArray` lock.
+    unsafe fn next_locked(
+        self: Pin<&Self>,
+        start: usize,
+        end: usize,
+    ) -> Option<(usize, *const core::ffi::c_void)> {
+        let mut index: core::ffi::c_ulong = start.try_into().ok()?;
+        let max: core::ffi::c_ulong = end.try_into().unwrap_or(core::ffi::c_ulong::MAX);
+        // SAFETY: `self.xa` is always valid by the type invariant, and the caller holds the lock.
+        let p = unsafe { bindings::__xa_find(self.xa.get(), &mut index, max) };
+        if p.is_null() {
+            None
+        } else {
+            Some((index as usize, p as *const _))
+        }
+    }
+
     /// Removes and returns an entry, returning it if it existed.
     pub fn remove(self: Pin<&Self>, index: usize) -> Option<T> {
         let p = unsafe { bindings::BINDINGS_xa_erase(self.xa.get(), index.try_into().ok()?) };
@@ -260,27 +502,12 @@ impl<T: ForeignOwnable> XArray<T> {
 
 impl<T: ForeignOwnable> Drop for XArray<T> {
     fn drop(&mut self) {
-        // SAFETY: `self.xa` is valid by the type invariant, and as we have the only reference to
-        // the `XArray` we can safely iterate its contents and drop everything.
+        // SAFETY: We are being dropped, so nothing can move the `XArray` anymore and the pinning
+        // requirement of `clear` is upheld.
+        unsafe { Pin::new_unchecked(&*self) }.clear();
+
+        // SAFETY: `self.xa` is valid by the type invariant, and we have the only reference to it.
         unsafe {
-            /*let mut index: core::ffi::c_ulong = 0;
-            let mut entry = bindings::xa_find(
-                self.xa.get(),
-                &mut index,
-                core::ffi::c_ulong::MAX,
-                bindings::BINDINGS_XA_PRESENT,
-            );
-            while !entry.is_null() {
-                T::from_foreign(entry);
-                entry = bindings::xa_find_after(
-                    self.xa.get(),
-                    &mut index,
-                    core::ffi::c_ulong::MAX,
-                    bindings::BINDINGS_XA_PRESENT,
-                );
-            }*/
-
-            bindings::BINDINGS_xa_empty(self.xa.get());
             // Locked locks are not safe to drop. Normally we would want to try_lock()/unlock() here
             // for safety or something similar, but in this case xa_destroy() is guaranteed to
             // acquire the lock anyway. This will deadlock if a lock guard was improperly dropped,